@@ -0,0 +1,228 @@
+use anyhow::{anyhow, Result};
+use clap::Args;
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use crate::pe::{read_imports, Import};
+use crate::sys::*;
+
+/// An API-set contract and the OS whose API-set schema first resolves it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApiSet {
+    /// Contract name or prefix, such as `api-ms-win-core-synch-l1-1-0`.
+    pub name: &'static str,
+    pub min_os: OS,
+    /// The classic DLL exporting the same functions.
+    pub host: Option<&'static str>,
+}
+
+const fn api_set(name: &'static str, min_os: OS, host: Option<&'static str>) -> ApiSet {
+    ApiSet { name, min_os, host }
+}
+
+const KERNEL32: Option<&str> = Some("kernel32.dll");
+const ADVAPI32: Option<&str> = Some("advapi32.dll");
+const NTDLL: Option<&str> = Some("ntdll.dll");
+
+/// Known contracts, the first matching prefix wins.
+const API_SETS: &[ApiSet] = &[
+    // Windows 7, schema 2
+    api_set("api-ms-win-core-console-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-datetime-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-debug-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-delayload-l1-1-0", OS::Windows7, KERNEL32),
+    api_set(
+        "api-ms-win-core-errorhandling-l1-1-0",
+        OS::Windows7,
+        KERNEL32,
+    ),
+    api_set("api-ms-win-core-fibers-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-file-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-handle-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-heap-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-interlocked-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-io-l1-1-0", OS::Windows7, KERNEL32),
+    api_set(
+        "api-ms-win-core-libraryloader-l1-1-0",
+        OS::Windows7,
+        KERNEL32,
+    ),
+    api_set(
+        "api-ms-win-core-localization-l1-1-0",
+        OS::Windows7,
+        KERNEL32,
+    ),
+    api_set(
+        "api-ms-win-core-localregistry-l1-1-0",
+        OS::Windows7,
+        ADVAPI32,
+    ),
+    api_set("api-ms-win-core-memory-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-misc-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-namedpipe-l1-1-0", OS::Windows7, KERNEL32),
+    api_set(
+        "api-ms-win-core-processenvironment-l1-1-0",
+        OS::Windows7,
+        KERNEL32,
+    ),
+    api_set(
+        "api-ms-win-core-processthreads-l1-1-0",
+        OS::Windows7,
+        KERNEL32,
+    ),
+    api_set("api-ms-win-core-profile-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-rtlsupport-l1-1-0", OS::Windows7, NTDLL),
+    api_set("api-ms-win-core-string-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-synch-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-sysinfo-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-threadpool-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-util-l1-1-0", OS::Windows7, KERNEL32),
+    api_set("api-ms-win-core-xstate-l1-1-0", OS::Windows7, NTDLL),
+    api_set("api-ms-win-security-base-l1-1-0", OS::Windows7, ADVAPI32),
+    api_set(
+        "api-ms-win-security-lsalookup-l1-1-0",
+        OS::Windows7,
+        ADVAPI32,
+    ),
+    api_set("api-ms-win-security-sddl-l1-1-0", OS::Windows7, ADVAPI32),
+    api_set("api-ms-win-service-core-l1-1-0", OS::Windows7, ADVAPI32),
+    api_set(
+        "api-ms-win-service-management-l1-1-0",
+        OS::Windows7,
+        ADVAPI32,
+    ),
+    api_set("api-ms-win-service-winsvc-l1-1-0", OS::Windows7, ADVAPI32),
+    // Windows 8, schema 4
+    api_set("api-ms-win-core-", OS::Windows8, KERNEL32),
+    api_set("api-ms-win-security-", OS::Windows8, ADVAPI32),
+    api_set("api-ms-win-service-", OS::Windows8, ADVAPI32),
+    api_set("ext-ms-win-", OS::Windows8, None),
+    // Windows 10, schema 6, older systems need the Universal CRT update (KB2999226)
+    api_set("api-ms-win-crt-", OS::Windows10, Some("ucrtbase.dll")),
+    api_set("api-ms-win-", OS::Windows10, None),
+];
+
+/// Find the API-set contract of a DLL name, `None` if it is a real DLL.
+pub fn find_api_set(dll: &str) -> Option<&'static ApiSet> {
+    let dll = dll.to_ascii_lowercase();
+    API_SETS
+        .iter()
+        .find(|api_set| dll.starts_with(api_set.name))
+}
+
+/// An API-set DLL imported by a PE image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiSetImport {
+    pub dll: String,
+    pub api_set: &'static ApiSet,
+    /// Functions imported from it.
+    pub functions: usize,
+}
+
+/// Find the API-set DLLs among the imports.
+pub fn read_api_set_imports(imports: &[Import]) -> Vec<ApiSetImport> {
+    let mut dlls: BTreeMap<String, ApiSetImport> = BTreeMap::new();
+    for import in imports {
+        if let Some(api_set) = find_api_set(&import.dll) {
+            dlls.entry(import.dll.to_ascii_lowercase())
+                .or_insert_with(|| ApiSetImport {
+                    dll: import.dll.clone(),
+                    api_set,
+                    functions: 0,
+                })
+                .functions += 1;
+        }
+    }
+    dlls.into_values().collect()
+}
+
+/// Describe an API-set import with the OS and host DLL it needs.
+pub fn describe_api_set_import(import: &ApiSetImport) -> String {
+    let mut text = format!("{} needs Windows {}", import.dll, import.api_set.min_os);
+    if let Some(host) = import.api_set.host {
+        text += &format!(" (host {})", host);
+    }
+    text
+}
+
+/// Report the API-set (api-ms-win-*) imports the target OS can not resolve
+#[derive(Debug, Args)]
+pub struct ApiSetArgs {
+    /// The exe or dll to check
+    file: PathBuf,
+    /// Operating system: xp, vista, win7, win10, 20h1 (dafault: win7)
+    #[arg(short, long, value_name = "OS")]
+    os: Option<OS>,
+}
+
+impl ApiSetArgs {
+    pub fn run(self) -> Result<()> {
+        let os = self.os.unwrap_or(OS::Windows7);
+        let data = fs::read(&self.file)
+            .map_err(|e| anyhow!("Failed to read {}: {}", self.file.to_string_lossy(), e))?;
+        let imports = read_api_set_imports(&read_imports(&data)?);
+
+        println!(
+            "Check API sets of {} for Windows {}:",
+            self.file.to_string_lossy(),
+            os
+        );
+        for import in &imports {
+            println!(" * {}", describe_api_set_import(import));
+        }
+
+        let unresolved = imports.iter().filter(|x| x.api_set.min_os > os).count();
+        if imports.is_empty() {
+            println!("No API set imports.");
+            Ok(())
+        } else if unresolved == 0 {
+            println!("All API sets resolve on Windows {}.", os);
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "{} API sets fail to resolve on Windows {}",
+                unresolved,
+                os
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pe::ImportName;
+
+    #[test]
+    fn test_find_api_set() {
+        let synch = find_api_set("API-MS-WIN-CORE-SYNCH-L1-1-0.dll").unwrap();
+        assert_eq!(synch.min_os, OS::Windows7);
+        assert_eq!(synch.host, Some("kernel32.dll"));
+        let synch2 = find_api_set("api-ms-win-core-synch-l1-2-0.dll").unwrap();
+        assert_eq!(synch2.min_os, OS::Windows8);
+        let crt = find_api_set("api-ms-win-crt-runtime-l1-1-0.dll").unwrap();
+        assert_eq!(crt.min_os, OS::Windows10);
+        assert_eq!(crt.host, Some("ucrtbase.dll"));
+        assert!(find_api_set("kernel32.dll").is_none());
+    }
+
+    #[test]
+    fn test_read_api_set_imports() {
+        let import = |dll: &str, name: &str| Import {
+            dll: dll.to_owned(),
+            name: ImportName::Name(name.to_owned()),
+            delayed: false,
+        };
+        let imports = vec![
+            import("api-ms-win-crt-heap-l1-1-0.dll", "malloc"),
+            import("api-ms-win-crt-heap-l1-1-0.dll", "free"),
+            import("kernel32.dll", "ExitProcess"),
+        ];
+        let api_sets = read_api_set_imports(&imports);
+        assert_eq!(api_sets.len(), 1);
+        assert_eq!(api_sets[0].functions, 2);
+        assert_eq!(
+            describe_api_set_import(&api_sets[0]),
+            "api-ms-win-crt-heap-l1-1-0.dll needs Windows 10 (host ucrtbase.dll)"
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use serde_json::Value;
+use std::path::PathBuf;
+
+/// An exe or dll produced by cargo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artifact {
+    pub path: PathBuf,
+    /// A dll of a `cdylib` target.
+    pub is_lib: bool,
+}
+
+/// Read the PE files from the `compiler-artifact` messages of `cargo build --message-format=json`.
+///
+/// Only `bin` and `cdylib` targets, and `example` ones with `examples`, are built for the target.
+/// Build scripts and proc-macros run on the host and are left alone.
+pub fn read_artifacts(messages: &str, examples: bool) -> Vec<Artifact> {
+    let mut artifacts = Vec::new();
+
+    for line in messages.lines() {
+        let message: Value = match serde_json::from_str(line) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        if message["reason"] != "compiler-artifact" {
+            continue;
+        }
+        let kinds = message["target"]["kind"].as_array().into_iter().flatten();
+        let is_target = kinds.filter_map(|x| x.as_str()).any(|kind| match kind {
+            "bin" | "cdylib" => true,
+            "example" => examples,
+            _ => false,
+        });
+        if !is_target {
+            continue;
+        }
+        let filenames = message["filenames"].as_array().into_iter().flatten();
+        for filename in filenames.filter_map(|x| x.as_str()) {
+            let path = PathBuf::from(filename);
+            let extension = path
+                .extension()
+                .map(|x| x.to_string_lossy().to_ascii_lowercase());
+            let is_lib = match extension.as_deref() {
+                Some("exe") => false,
+                Some("dll") => true,
+                _ => continue,
+            };
+            artifacts.push(Artifact { path, is_lib });
+        }
+    }
+
+    artifacts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_artifacts() {
+        let messages = r#"{"reason":"compiler-artifact","target":{"kind":["custom-build"]},"filenames":["C:\\t\\release\\build\\app-1\\build_script_build-1.exe"],"executable":null}
+{"reason":"compiler-artifact","target":{"kind":["proc-macro"]},"filenames":["C:\\t\\release\\deps\\derive-1.dll"]}
+{"reason":"compiler-artifact","target":{"kind":["bin"]},"filenames":["C:\\t\\release\\app.exe","C:\\t\\release\\app.pdb"],"executable":"C:\\t\\release\\app.exe"}
+{"reason":"compiler-artifact","target":{"kind":["lib"]},"filenames":["C:\\t\\release\\liba.rlib"]}
+{"reason":"compiler-artifact","target":{"kind":["cdylib"]},"filenames":["C:\\t\\release\\plugin.dll","C:\\t\\release\\plugin.dll.lib"]}
+{"reason":"compiler-artifact","target":{"kind":["example"]},"filenames":["C:\\t\\release\\examples\\demo.exe"],"executable":"C:\\t\\release\\examples\\demo.exe"}
+{"reason":"build-finished","success":true}"#;
+        assert_eq!(
+            read_artifacts(messages, false),
+            vec![
+                Artifact {
+                    path: PathBuf::from("C:\\t\\release\\app.exe"),
+                    is_lib: false
+                },
+                Artifact {
+                    path: PathBuf::from("C:\\t\\release\\plugin.dll"),
+                    is_lib: true
+                },
+            ]
+        );
+        assert_eq!(
+            read_artifacts(messages, true).last(),
+            Some(&Artifact {
+                path: PathBuf::from("C:\\t\\release\\examples\\demo.exe"),
+                is_lib: false
+            })
+        );
+    }
+}
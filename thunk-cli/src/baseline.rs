@@ -0,0 +1,313 @@
+use anyhow::{anyhow, Result};
+use clap::{Args, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::pe::{read_arch, read_imports, Import, ImportName};
+use crate::sys::*;
+use crate::thunks::{find_thunked_apis, ThunkedApis};
+
+const DEFAULT_BASELINE: &str = "thunk-baseline.toml";
+
+/// The imports of a build for one OS and arch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BaselineArtifact {
+    /// File name of the exe or dll.
+    pub name: String,
+    pub os: String,
+    pub arch: String,
+    /// Imports such as `kernel32.dll!GetTickCount64`.
+    pub imports: BTreeSet<String>,
+}
+
+/// A checked-in list of the imports every build is allowed to have.
+///
+/// ```toml
+/// [[artifact]]
+/// name = "app.exe"
+/// os = "XP"
+/// arch = "Win32"
+/// imports = ["kernel32.dll!ExitProcess"]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Baseline {
+    #[serde(default, rename = "artifact")]
+    pub artifacts: Vec<BaselineArtifact>,
+}
+
+/// Imports of a build compared with the baseline.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BaselineDiff {
+    /// The baseline has no record of the build, all its imports are added.
+    pub new: bool,
+    /// Imports neither in the baseline nor provided by YY-Thunks.
+    pub added: BTreeSet<String>,
+    /// Imports in the baseline the build does not have any more.
+    pub removed: BTreeSet<String>,
+}
+
+impl Baseline {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read {}: {}", path.to_string_lossy(), e))?;
+        toml::from_str(&text).map_err(|e| anyhow!("{}: {}", path.to_string_lossy(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str, os: OS, arch: Arch) -> Option<&BaselineArtifact> {
+        self.artifacts.iter().find(|artifact| {
+            artifact.name.eq_ignore_ascii_case(name)
+                && artifact.os == os.to_string()
+                && artifact.arch == arch.to_string()
+        })
+    }
+
+    /// Record the imports of a build, replacing the former record.
+    pub fn record(&mut self, name: &str, os: OS, arch: Arch, imports: &[Import]) {
+        self.artifacts.retain(|artifact| {
+            !(artifact.name.eq_ignore_ascii_case(name)
+                && artifact.os == os.to_string()
+                && artifact.arch == arch.to_string())
+        });
+        self.artifacts.push(BaselineArtifact {
+            name: name.to_owned(),
+            os: os.to_string(),
+            arch: arch.to_string(),
+            imports: imports.iter().map(baseline_import).collect(),
+        });
+        self.artifacts
+            .sort_by(|a, b| (&a.name, &a.os, &a.arch).cmp(&(&b.name, &b.os, &b.arch)));
+    }
+
+    /// Compare the imports of a build with the baseline, APIs provided by YY-Thunks are allowed.
+    ///
+    /// A build the baseline has no record of is new, with all its imports added.
+    pub fn diff(
+        &self,
+        name: &str,
+        os: OS,
+        arch: Arch,
+        imports: &[Import],
+        thunked: &ThunkedApis,
+    ) -> BaselineDiff {
+        let recorded = self.get(name, os, arch);
+        let empty = BTreeSet::new();
+        let recorded_imports = recorded.map_or(&empty, |recorded| &recorded.imports);
+
+        let current: BTreeSet<String> = imports.iter().map(baseline_import).collect();
+        let added = imports
+            .iter()
+            .filter(|import| match &import.name {
+                ImportName::Name(name) => !thunked.contains(&import.dll, name),
+                ImportName::Ordinal(_) => true,
+            })
+            .map(baseline_import)
+            .filter(|import| !recorded_imports.contains(import))
+            .collect();
+        let removed = recorded_imports.difference(&current).cloned().collect();
+
+        BaselineDiff {
+            new: recorded.is_none(),
+            added,
+            removed,
+        }
+    }
+}
+
+/// Get the baseline form of an import, such as `kernel32.dll!GetTickCount64`.
+fn baseline_import(import: &Import) -> String {
+    format!("{}!{}", import.dll.to_ascii_lowercase(), import.name)
+}
+
+/// Verify a build against the baseline, printing the diff.
+///
+/// `yy_thunks` is the directory the build used, it is found from the configuration without one.
+pub fn verify_file(
+    baseline: &Baseline,
+    file: &Path,
+    yy_thunks: Option<&Path>,
+    os: OS,
+) -> Result<BaselineDiff> {
+    let data =
+        fs::read(file).map_err(|e| anyhow!("Failed to read {}: {}", file.to_string_lossy(), e))?;
+    let arch = read_arch(&data)?;
+    let imports = read_imports(&data)?;
+    let thunked = find_thunked_apis(yy_thunks, os, arch)?;
+
+    let name = file.file_name().unwrap().to_string_lossy();
+    let diff = baseline.diff(&name, os, arch, &imports, &thunked);
+    if diff.new {
+        println!(
+            " * {} for Windows {}({}) is not in the baseline",
+            name, os, arch
+        );
+    }
+    for import in &diff.added {
+        println!(" + {}", import);
+    }
+    for import in &diff.removed {
+        println!(" - {}", import);
+    }
+    Ok(diff)
+}
+
+/// Record or verify the imports of builds against a checked-in baseline
+#[derive(Debug, Args)]
+pub struct BaselineArgs {
+    #[command(subcommand)]
+    command: BaselineCommands,
+}
+
+#[derive(Debug, Subcommand)]
+enum BaselineCommands {
+    /// Record the imports of builds into the baseline
+    Record {
+        /// The exe or dll files to record
+        #[arg(required = true, value_name = "FILES")]
+        files: Vec<PathBuf>,
+        /// Operating system: xp, vista, win7, win10, 20h1 (dafault: win7)
+        #[arg(short, long, value_name = "OS")]
+        os: Option<OS>,
+        /// Baseline file, updated if it exists
+        #[arg(long, value_name = "FILE", default_value = DEFAULT_BASELINE)]
+        baseline: PathBuf,
+    },
+    /// Fail if builds import APIs that are neither in the baseline nor provided by YY-Thunks
+    Verify {
+        /// The exe or dll files to verify
+        #[arg(required = true, value_name = "FILES")]
+        files: Vec<PathBuf>,
+        /// Operating system: xp, vista, win7, win10, 20h1 (dafault: win7)
+        #[arg(short, long, value_name = "OS")]
+        os: Option<OS>,
+        /// Baseline file
+        #[arg(long, value_name = "FILE", default_value = DEFAULT_BASELINE)]
+        baseline: PathBuf,
+    },
+}
+
+impl BaselineArgs {
+    pub fn run(self) -> Result<()> {
+        match self.command {
+            BaselineCommands::Record {
+                files,
+                os,
+                baseline: path,
+            } => {
+                let os = os.unwrap_or(OS::Windows7);
+                let mut baseline = if path.exists() {
+                    Baseline::load(&path)?
+                } else {
+                    Baseline::default()
+                };
+
+                for file in &files {
+                    let data = fs::read(file)
+                        .map_err(|e| anyhow!("Failed to read {}: {}", file.to_string_lossy(), e))?;
+                    let arch = read_arch(&data)?;
+                    let imports = read_imports(&data)?;
+                    let name = file.file_name().unwrap().to_string_lossy();
+                    baseline.record(&name, os, arch, &imports);
+                    println!(
+                        " * {} for Windows {}({}): {} imports",
+                        name,
+                        os,
+                        arch,
+                        imports.len()
+                    );
+                }
+
+                baseline.save(&path)?;
+                println!("Baseline is saved to {}", path.to_string_lossy());
+            }
+            BaselineCommands::Verify {
+                files,
+                os,
+                baseline: path,
+            } => {
+                let os = os.unwrap_or(OS::Windows7);
+                let baseline = Baseline::load(&path)?;
+
+                let mut added = 0;
+                for file in &files {
+                    println!("Verify {} for Windows {}:", file.to_string_lossy(), os);
+                    added += verify_file(&baseline, file, None, os)?.added.len();
+                }
+
+                if added > 0 {
+                    return Err(anyhow!(
+                        "{} new imports are not in the baseline {}",
+                        added,
+                        path.to_string_lossy()
+                    ));
+                }
+                println!("No new imports.");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(dll: &str, name: &str) -> Import {
+        Import {
+            dll: dll.to_owned(),
+            name: ImportName::Name(name.to_owned()),
+            delayed: false,
+        }
+    }
+
+    #[test]
+    fn test_baseline_diff() {
+        let mut baseline = Baseline::default();
+        baseline.record(
+            "app.exe",
+            OS::WindowsXP,
+            Arch::Win32,
+            &[
+                import("KERNEL32.dll", "ExitProcess"),
+                import("KERNEL32.dll", "GetTickCount"),
+            ],
+        );
+        let baseline: Baseline = toml::from_str(&toml::to_string(&baseline).unwrap()).unwrap();
+
+        let imports = [
+            import("KERNEL32.dll", "ExitProcess"),
+            import("KERNEL32.dll", "GetTickCount64"),
+            import("bcrypt.dll", "BCryptGenRandom"),
+        ];
+        let thunked = ThunkedApis::from([("kernel32", "GetTickCount64")]);
+        let diff = baseline.diff("APP.exe", OS::WindowsXP, Arch::Win32, &imports, &thunked);
+        assert!(!diff.new);
+        assert_eq!(
+            diff.added,
+            BTreeSet::from(["bcrypt.dll!BCryptGenRandom".to_owned()])
+        );
+        assert_eq!(
+            diff.removed,
+            BTreeSet::from(["kernel32.dll!GetTickCount".to_owned()])
+        );
+
+        let diff = baseline.diff("app.exe", OS::Windows7, Arch::Win32, &imports, &thunked);
+        assert!(diff.new);
+        assert_eq!(
+            diff.added,
+            BTreeSet::from([
+                "bcrypt.dll!BCryptGenRandom".to_owned(),
+                "kernel32.dll!ExitProcess".to_owned(),
+            ])
+        );
+        assert!(diff.removed.is_empty());
+    }
+}
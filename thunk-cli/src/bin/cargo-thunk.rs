@@ -0,0 +1,5 @@
+use clap::Parser;
+
+fn main() -> anyhow::Result<()> {
+    thunk_cli::cargo::CargoCli::parse().run()
+}
@@ -0,0 +1,10 @@
+fn main() {
+    let args: Vec<_> = std::env::args_os().skip(1).collect();
+    match thunk_cli::link::run_linker(&args) {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use anyhow::{anyhow, Result};
+use clap::Args;
+use object::{read::archive::ArchiveFile, Architecture, FileKind, Object, ObjectSymbol};
+use serde::Serialize;
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::map::{find_map_file, LinkMap};
+use crate::pe::{read_arch, read_imports, Import, ImportName};
+use crate::sys::*;
+use crate::thunks::undecorate;
+
+/// An import and the object that references it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct ImportBlame {
+    pub import: String,
+    #[serde(rename = "crate")]
+    pub crate_name: String,
+    pub object: String,
+}
+
+/// The blamed imports of a build.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlameReport {
+    pub artifact: String,
+    pub imports: Vec<ImportBlame>,
+}
+
+/// An object file that may be linked, with the symbols it references.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexedObject {
+    /// Library file stem as the link map prints it, `None` for a loose object.
+    pub lib: Option<String>,
+    pub name: String,
+    /// Undecorated undefined symbols, `__imp_` stripped.
+    pub references: BTreeSet<String>,
+}
+
+/// Index the objects of the `.rlib`, `.lib`, `.o` and `.obj` files in the directories.
+pub fn index_objects(dirs: &[PathBuf]) -> Vec<IndexedObject> {
+    let mut objects = Vec::new();
+
+    for dir in dirs {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for path in entries.flatten().map(|entry| entry.path()) {
+            let extension = path
+                .extension()
+                .map(|x| x.to_string_lossy().to_ascii_lowercase());
+            let is_archive = matches!(extension.as_deref(), Some("rlib" | "lib"));
+            let is_object = matches!(extension.as_deref(), Some("o" | "obj"));
+            if !is_archive && !is_object {
+                continue;
+            }
+            let data = match fs::read(&path) {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+            let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+
+            if is_object {
+                if let Some(references) = read_references(&data) {
+                    objects.push(IndexedObject {
+                        lib: None,
+                        name: file_name,
+                        references,
+                    });
+                }
+                continue;
+            }
+
+            let archive = match ArchiveFile::parse(data.as_slice()) {
+                Ok(archive) => archive,
+                Err(_) => continue,
+            };
+            let lib = path.file_stem().unwrap().to_string_lossy().to_string();
+            for member in archive.members().flatten() {
+                let name = String::from_utf8_lossy(member.name()).to_string();
+                let name = name.rsplit(['/', '\\']).next().unwrap_or(&name).to_owned();
+                let references = member.data(data.as_slice()).ok().and_then(read_references);
+                if let Some(references) = references {
+                    objects.push(IndexedObject {
+                        lib: Some(lib.clone()),
+                        name,
+                        references,
+                    });
+                }
+            }
+        }
+    }
+
+    objects
+}
+
+/// Read the undefined symbols of a COFF object.
+fn read_references(data: &[u8]) -> Option<BTreeSet<String>> {
+    if !matches!(
+        FileKind::parse(data),
+        Ok(FileKind::Coff | FileKind::CoffBig)
+    ) {
+        return None;
+    }
+    let file = object::File::parse(data).ok()?;
+    let is_x86 = file.architecture() == Architecture::I386;
+    let references = file
+        .symbols()
+        .filter(|symbol| symbol.is_undefined())
+        .filter_map(|symbol| symbol.name().ok())
+        .map(|name| undecorate(name.strip_prefix("__imp_").unwrap_or(name), is_x86))
+        .collect();
+    Some(references)
+}
+
+/// Guess the crate of an object from the `lib<crate>-<hash>` or `<crate>-<hash>.<cgu>.rcgu.o` naming.
+pub fn crate_name(lib: Option<&str>, object: &str) -> String {
+    let strip_hash = |name: &str| match name.rsplit_once('-') {
+        Some((name, hash)) if hash.chars().all(|x| x.is_ascii_hexdigit()) => Some(name.to_owned()),
+        _ => None,
+    };
+    match lib {
+        Some(lib) => lib
+            .strip_prefix("lib")
+            .and_then(strip_hash)
+            .unwrap_or_else(|| lib.to_owned()),
+        None => {
+            let stem = object.split('.').next().unwrap_or(object);
+            strip_hash(stem).unwrap_or_else(|| stem.to_owned())
+        }
+    }
+}
+
+/// Attribute every import to the linked objects that reference it.
+pub fn blame_imports(
+    imports: &[Import],
+    map: &LinkMap,
+    objects: &[IndexedObject],
+) -> Vec<ImportBlame> {
+    let basename = |name: &str| name.rsplit(['/', '\\']).next().unwrap_or(name).to_owned();
+    let linked: BTreeSet<(Option<String>, String)> = map
+        .symbols
+        .iter()
+        .map(|symbol| (symbol.lib.clone(), basename(&symbol.object)))
+        .collect();
+    let linked_objects: Vec<&IndexedObject> = objects
+        .iter()
+        .filter(|object| linked.contains(&(object.lib.clone(), object.name.clone())))
+        .collect();
+
+    let mut blames = BTreeSet::new();
+    for import in imports {
+        let name = match &import.name {
+            ImportName::Name(name) => Some(name),
+            ImportName::Ordinal(_) => None,
+        };
+        let mut found = false;
+        for object in &linked_objects {
+            if name.is_some_and(|name| object.references.contains(name)) {
+                blames.insert(ImportBlame {
+                    import: import.to_string(),
+                    crate_name: crate_name(object.lib.as_deref(), &object.name),
+                    object: object.name.clone(),
+                });
+                found = true;
+            }
+        }
+        if !found {
+            blames.insert(ImportBlame {
+                import: import.to_string(),
+                crate_name: "-".to_owned(),
+                object: "-".to_owned(),
+            });
+        }
+    }
+
+    blames.into_iter().collect()
+}
+
+/// Get the directory of the Rust standard library for the arch.
+fn get_rust_target_libdir(arch: Arch) -> Option<PathBuf> {
+    let output = Command::new("rustc")
+        .args(["--print", "target-libdir", "--target"])
+        .arg(arch.to_rust_target()?)
+        .output()
+        .ok()?;
+    let path = String::from_utf8(output.stdout).ok()?;
+    output.status.success().then(|| PathBuf::from(path.trim()))
+}
+
+/// Blame the imports of a build, searching objects in `deps`, the Rust sysroot and `lib_dirs`.
+pub fn blame_file(
+    file: &Path,
+    map_file: Option<&Path>,
+    lib_dirs: &[PathBuf],
+) -> Result<BlameReport> {
+    let data =
+        fs::read(file).map_err(|e| anyhow!("Failed to read {}: {}", file.to_string_lossy(), e))?;
+    let arch = read_arch(&data)?;
+    let imports = read_imports(&data)?;
+
+    let map_file = match map_file {
+        Some(map_file) => map_file.to_owned(),
+        None => find_map_file(file).ok_or_else(|| {
+            anyhow!(
+                "No link map of {} found, link it with /MAP",
+                file.to_string_lossy()
+            )
+        })?,
+    };
+    let map = LinkMap::load(&map_file)?;
+
+    let mut dirs = lib_dirs.to_vec();
+    if let Some(dir) = file.parent() {
+        dirs.extend([dir.to_owned(), dir.join("deps")]);
+    }
+    dirs.extend(get_rust_target_libdir(arch));
+    let objects = index_objects(&dirs);
+
+    Ok(BlameReport {
+        artifact: file.to_string_lossy().to_string(),
+        imports: blame_imports(&imports, &map, &objects),
+    })
+}
+
+/// Print a blame report as a table.
+pub fn print_blame(report: &BlameReport) {
+    println!("Blame imports of {}:", report.artifact);
+    println!("{:<48} {:<24} Object", "Import", "Crate");
+    for blame in &report.imports {
+        println!(
+            "{:<48} {:<24} {}",
+            blame.import, blame.crate_name, blame.object
+        );
+    }
+}
+
+/// Write blame reports as JSON.
+pub fn save_blame_json(reports: &[BlameReport], path: &Path) -> Result<()> {
+    fs::write(path, serde_json::to_string_pretty(reports)?)?;
+    Ok(())
+}
+
+/// Blame each import of a build to the crate and object that introduced it
+#[derive(Debug, Args)]
+pub struct BlameArgs {
+    /// The exe or dll to blame, linked with /MAP
+    file: PathBuf,
+    /// Link map (default: the newest map of the file in the same or the deps directory)
+    #[arg(long, value_name = "FILE")]
+    map: Option<PathBuf>,
+    /// More directories of libraries and objects to search
+    #[arg(short = 'L', long, value_name = "DIR")]
+    lib_dir: Vec<PathBuf>,
+    /// Also write the report as JSON
+    #[arg(long, value_name = "FILE")]
+    json: Option<PathBuf>,
+}
+
+impl BlameArgs {
+    pub fn run(self) -> Result<()> {
+        let report = blame_file(&self.file, self.map.as_deref(), &self.lib_dir)?;
+        print_blame(&report);
+        if let Some(json) = &self.json {
+            save_blame_json(&[report], json)?;
+            println!("Report is saved to {}", json.to_string_lossy());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crate_name() {
+        assert_eq!(crate_name(Some("libstd-1a2b3c4d"), "std.o"), "std");
+        assert_eq!(crate_name(Some("libcmt"), "chkstk.obj"), "libcmt");
+        assert_eq!(crate_name(None, "app-0f1e2d.app.5a6b-cgu.0.rcgu.o"), "app");
+        assert_eq!(crate_name(None, "helper.obj"), "helper");
+    }
+
+    #[test]
+    fn test_blame_imports() {
+        let map = LinkMap::parse(
+            " 0001:00000000       _main      00401000 f   libfoo-1a2b:foo-1a2b.foo.x-cgu.0.rcgu.o
+ 0001:00000010       _other     00401010 f   app.o",
+        );
+        let objects = vec![
+            IndexedObject {
+                lib: Some("libfoo-1a2b".to_owned()),
+                name: "foo-1a2b.foo.x-cgu.0.rcgu.o".to_owned(),
+                references: BTreeSet::from(["GetTickCount64".to_owned()]),
+            },
+            // Not linked
+            IndexedObject {
+                lib: Some("libbar-3c4d".to_owned()),
+                name: "bar-3c4d.bar.y-cgu.0.rcgu.o".to_owned(),
+                references: BTreeSet::from(["GetTickCount64".to_owned()]),
+            },
+        ];
+        let import = |name: &str| Import {
+            dll: "KERNEL32.dll".to_owned(),
+            name: ImportName::Name(name.to_owned()),
+            delayed: false,
+        };
+
+        let blames = blame_imports(
+            &[import("GetTickCount64"), import("ExitProcess")],
+            &map,
+            &objects,
+        );
+        assert_eq!(
+            blames,
+            vec![
+                ImportBlame {
+                    import: "KERNEL32.dll!ExitProcess".to_owned(),
+                    crate_name: "-".to_owned(),
+                    object: "-".to_owned(),
+                },
+                ImportBlame {
+                    import: "KERNEL32.dll!GetTickCount64".to_owned(),
+                    crate_name: "foo".to_owned(),
+                    object: "foo-1a2b.foo.x-cgu.0.rcgu.o".to_owned(),
+                },
+            ]
+        );
+    }
+}
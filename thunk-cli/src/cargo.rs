@@ -0,0 +1,258 @@
+use anyhow::Result;
+use clap::{ArgAction, Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+use crate::ThunkBuilder;
+
+/// The options of `cargo build` that Thunk understands, and passes on to cargo.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Args)]
+pub struct CargoArgs {
+    /// Package to build
+    #[arg(short, long, value_name = "SPEC")]
+    pub package: Vec<String>,
+    /// Build all packages in the workspace
+    #[arg(long)]
+    pub workspace: bool,
+    /// Exclude packages from the build
+    #[arg(long, value_name = "SPEC", requires = "workspace")]
+    pub exclude: Vec<String>,
+    /// Build only the specified binary
+    #[arg(long, value_name = "NAME")]
+    pub bin: Vec<String>,
+    /// Build all binaries
+    #[arg(long)]
+    pub bins: bool,
+    /// Build only the specified example
+    #[arg(long, value_name = "NAME")]
+    pub example: Vec<String>,
+    /// Build all examples
+    #[arg(long)]
+    pub examples: bool,
+    /// Build artifacts in release mode, with optimizations
+    #[arg(short, long, conflicts_with = "profile")]
+    pub release: bool,
+    /// Build artifacts with the specified profile
+    #[arg(long, value_name = "PROFILE-NAME")]
+    pub profile: Option<String>,
+    /// Space or comma separated list of features to activate
+    #[arg(short = 'F', long)]
+    pub features: Vec<String>,
+    /// Activate all available features
+    #[arg(long)]
+    pub all_features: bool,
+    /// Do not activate the `default` feature
+    #[arg(long)]
+    pub no_default_features: bool,
+    /// Build for the target triple
+    #[arg(long, value_name = "TRIPLE")]
+    pub target: Option<String>,
+    /// Path to Cargo.toml
+    #[arg(long, value_name = "PATH")]
+    pub manifest_path: Option<PathBuf>,
+    /// Number of parallel jobs
+    #[arg(short, long, value_name = "N")]
+    pub jobs: Option<String>,
+    /// Assert that `Cargo.lock` will remain unchanged
+    #[arg(long)]
+    pub locked: bool,
+    /// Run without accessing the network
+    #[arg(long)]
+    pub offline: bool,
+    /// Equivalent to specifying both --locked and --offline
+    #[arg(long)]
+    pub frozen: bool,
+    /// Use verbose output (-vv very verbose/build.rs output)
+    #[arg(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// Do not print cargo log messages
+    #[arg(short, long)]
+    pub quiet: bool,
+    /// Error format
+    #[arg(long, value_name = "FMT")]
+    pub message_format: Vec<String>,
+    /// Build only this package's library, it is the same as `--lib` of Thunk
+    #[arg(skip)]
+    pub lib: bool,
+}
+
+impl CargoArgs {
+    /// Get the args of `cargo build`.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut push = |flag: &str, values: &[String]| {
+            for value in values {
+                args.extend([flag.to_owned(), value.clone()]);
+            }
+        };
+        push("--package", &self.package);
+        push("--exclude", &self.exclude);
+        push("--bin", &self.bin);
+        push("--example", &self.example);
+        push("--features", &self.features);
+        push("--target", self.target.as_slice());
+        push("--profile", self.profile.as_slice());
+        push("--jobs", self.jobs.as_slice());
+        let manifest_path: Vec<String> = self
+            .manifest_path
+            .iter()
+            .map(|x| x.to_string_lossy().to_string())
+            .collect();
+        push("--manifest-path", &manifest_path);
+        for format in &self.message_format {
+            args.push(format!("--message-format={}", format));
+        }
+
+        let flags = [
+            (self.workspace, "--workspace"),
+            (self.lib, "--lib"),
+            (self.bins, "--bins"),
+            (self.examples, "--examples"),
+            (self.release, "--release"),
+            (self.all_features, "--all-features"),
+            (self.no_default_features, "--no-default-features"),
+            (self.locked, "--locked"),
+            (self.offline, "--offline"),
+            (self.frozen, "--frozen"),
+            (self.quiet, "--quiet"),
+        ];
+        for (on, flag) in flags {
+            if on {
+                args.push(flag.to_owned());
+            }
+        }
+        args.extend((0..self.verbose).map(|_| "--verbose".to_owned()));
+        args
+    }
+}
+
+/// Cargo runs `cargo-thunk thunk <ARGS>` for `cargo thunk <ARGS>`.
+#[derive(Debug, Parser)]
+#[command(name = "cargo", bin_name = "cargo")]
+pub enum CargoCli {
+    Thunk(CargoThunkArgs),
+}
+
+impl CargoCli {
+    pub fn run(self) -> Result<()> {
+        match self {
+            CargoCli::Thunk(args) => args.run(),
+        }
+    }
+}
+
+/// Use Thunk to build your Rust program that runs on old Windows platforms.
+#[derive(Debug, Args)]
+#[command(version)]
+pub struct CargoThunkArgs {
+    #[command(subcommand)]
+    command: CargoThunkCommands,
+}
+
+#[derive(Debug, Subcommand)]
+enum CargoThunkCommands {
+    Build(BuildArgs),
+}
+
+impl CargoThunkArgs {
+    pub fn run(self) -> Result<()> {
+        match self.command {
+            CargoThunkCommands::Build(args) => args.run(),
+        }
+    }
+}
+
+/// Build with Thunk: cargo thunk build --os xp -p foo --release
+#[derive(Debug, Args)]
+struct BuildArgs {
+    #[command(flatten)]
+    builder: ThunkBuilder,
+    #[command(flatten, next_help_heading = "Cargo Options")]
+    cargo: CargoArgs,
+}
+
+impl BuildArgs {
+    fn run(self) -> Result<()> {
+        let mut builder = self.builder;
+        builder.cargo = CargoArgs {
+            lib: builder.lib,
+            ..self.cargo
+        };
+        builder.build()?.run()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cargo_thunk_build() {
+        let cli = CargoCli::try_parse_from([
+            "cargo-thunk",
+            "thunk",
+            "build",
+            "--os",
+            "xp",
+            "-p",
+            "foo",
+            "--release",
+            "--target=i686-pc-windows-msvc",
+            "--lib",
+            "-vv",
+        ])
+        .unwrap();
+        let CargoCli::Thunk(CargoThunkArgs {
+            command: CargoThunkCommands::Build(args),
+        }) = cli;
+        assert!(args.builder.lib);
+        assert_eq!(args.cargo.package, ["foo"]);
+        assert_eq!(
+            CargoArgs {
+                lib: true,
+                ..args.cargo
+            }
+            .to_args(),
+            [
+                "--package",
+                "foo",
+                "--target",
+                "i686-pc-windows-msvc",
+                "--lib",
+                "--release",
+                "--verbose",
+                "--verbose"
+            ]
+        );
+
+        let cli = CargoCli::try_parse_from([
+            "cargo-thunk",
+            "thunk",
+            "build",
+            "--patch-pe",
+            "--no-patch-pe",
+        ])
+        .unwrap();
+        let CargoCli::Thunk(CargoThunkArgs {
+            command: CargoThunkCommands::Build(args),
+        }) = cli;
+        let builder = args.builder;
+        assert_eq!(
+            crate::get_flag(builder.patch_pe, builder.no_patch_pe),
+            Some(false)
+        );
+        assert_eq!(
+            crate::get_flag(builder.patch_load_config, builder.no_patch_load_config),
+            None
+        );
+
+        assert!(CargoCli::try_parse_from([
+            "cargo-thunk",
+            "thunk",
+            "build",
+            "-r",
+            "--profile",
+            "x"
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use anyhow::{anyhow, Result};
+use clap::Args;
+use std::{fs, path::PathBuf};
+
+use crate::apiset::{describe_api_set_import, read_api_set_imports};
+use crate::crt::{diagnose_crt_import, get_crt_symbols, get_vc_ltl_dir, is_vc_redist_dll};
+use crate::db::ExportDb;
+use crate::pe::{read_arch, read_imports, Import, ImportName};
+use crate::sys::*;
+use crate::thunks::{find_thunked_apis, ThunkedApis};
+
+const ENV_VAR_DB: &str = "THUNK_DB";
+
+/// An import that the loader of the target OS fails to resolve.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingImport {
+    pub import: Import,
+    /// The DLL does not exist on the target OS at all.
+    pub missing_dll: bool,
+}
+
+impl MissingImport {
+    /// Whether the loader fails on it, a delay-load import only fails when it is called, the
+    /// usual way to use an optional newer API.
+    pub fn fails_to_load(&self) -> bool {
+        !self.import.delayed
+    }
+}
+
+/// Resolve imports against the export database, skipping the APIs YY-Thunks provides.
+pub fn check_imports(
+    imports: &[Import],
+    db: &ExportDb,
+    os: OS,
+    arch: Arch,
+    thunked: &ThunkedApis,
+) -> Result<Vec<MissingImport>> {
+    if !db.has_target(os, arch) {
+        return Err(anyhow!(
+            "The export database has nothing about Windows {}({})",
+            os,
+            arch
+        ));
+    }
+
+    let missing = imports
+        .iter()
+        .filter(|import| !is_thunked(import, thunked))
+        .filter_map(|import| match db.get(os, arch, &import.dll) {
+            Some(exports) if exports.contains(&import.name) => None,
+            exports => Some(MissingImport {
+                import: import.clone(),
+                missing_dll: exports.is_none(),
+            }),
+        })
+        .collect();
+
+    Ok(missing)
+}
+
+fn is_thunked(import: &Import, thunked: &ThunkedApis) -> bool {
+    match &import.name {
+        ImportName::Name(name) => thunked.contains(&import.dll, name),
+        ImportName::Ordinal(_) => false,
+    }
+}
+
+/// Check whether a built PE file loads on the target OS
+#[derive(Debug, Args)]
+pub struct CheckArgs {
+    /// The exe or dll to check
+    file: PathBuf,
+    /// Operating system: xp, vista, win7, win10, 20h1 (dafault: win7)
+    #[arg(short, long, value_name = "OS")]
+    os: Option<OS>,
+    /// Export databases built by `thunk db build` (default: THUNK_DB environment variable)
+    #[arg(long, value_name = "FILE")]
+    db: Vec<PathBuf>,
+}
+
+impl CheckArgs {
+    pub fn run(self) -> Result<()> {
+        let os = self.os.unwrap_or(OS::Windows7);
+
+        let db_paths = match std::env::var_os(ENV_VAR_DB) {
+            Some(paths) if self.db.is_empty() => std::env::split_paths(&paths).collect(),
+            _ => self.db,
+        };
+        if db_paths.is_empty() {
+            return Err(anyhow!(
+                "You need to pass --db or set {} environment variable.",
+                ENV_VAR_DB
+            ));
+        }
+        let db = ExportDb::load_all(&db_paths)?;
+
+        let data = fs::read(&self.file)?;
+        let arch = read_arch(&data)?;
+        let imports = read_imports(&data)?;
+
+        let thunked = find_thunked_apis(None, os, arch)?;
+
+        let missing = check_imports(&imports, &db, os, arch, &thunked)?;
+
+        println!(
+            "Check {} for Windows {}({}):",
+            self.file.to_string_lossy(),
+            os,
+            arch
+        );
+        println!(" * Imports: {}", imports.len());
+        println!(
+            " * Provided by YY-Thunks: {}",
+            imports.iter().filter(|x| is_thunked(x, &thunked)).count()
+        );
+        let (missing, delayed): (Vec<_>, Vec<_>) =
+            missing.into_iter().partition(|x| x.fails_to_load());
+        for item in &missing {
+            if item.missing_dll {
+                println!(" * {} (DLL not found)", item.import);
+            } else {
+                println!(" * {}", item.import);
+            }
+        }
+        if !delayed.is_empty() {
+            println!(
+                "Delay-load imports that fail when called on Windows {}:",
+                os
+            );
+            for item in &delayed {
+                if item.missing_dll {
+                    println!(" * {} (DLL not found)", item.import);
+                } else {
+                    println!(" * {}", item.import);
+                }
+            }
+        }
+
+        let api_sets: Vec<_> = read_api_set_imports(&imports)
+            .into_iter()
+            .filter(|x| x.api_set.min_os > os)
+            .collect();
+        if !api_sets.is_empty() {
+            println!("API sets unknown to Windows {}:", os);
+            for import in &api_sets {
+                println!(" * {}", describe_api_set_import(import));
+            }
+        }
+
+        let redist: Vec<&Import> = imports
+            .iter()
+            .filter(|import| is_vc_redist_dll(&import.dll, os))
+            .collect();
+        if !redist.is_empty() {
+            println!("Depends on the VC++ redistributable:");
+            let crt = get_vc_ltl_dir().and_then(|vc_ltl| get_crt_symbols(&vc_ltl, os, arch));
+            for import in redist {
+                match &crt {
+                    Ok(crt) => println!(" * {}: {}", import, diagnose_crt_import(import, crt)),
+                    Err(_) => println!(" * {}", import),
+                }
+            }
+            if let Err(e) = &crt {
+                println!(
+                    "Note: {} The VC++ redistributable imports are not diagnosed.",
+                    e
+                );
+            }
+        }
+
+        if missing.is_empty() {
+            println!("All imports resolve on Windows {}({}).", os, arch);
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "{} imports fail to resolve on Windows {}({})",
+                missing.len(),
+                os,
+                arch
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(dll: &str, name: &str) -> Import {
+        Import {
+            dll: dll.to_owned(),
+            name: ImportName::Name(name.to_owned()),
+            delayed: false,
+        }
+    }
+
+    #[test]
+    fn test_check_imports() {
+        let db = ExportDb::parse("thunk-db 1\ntarget XP Win32\ndll kernel32.dll\n1 GetTickCount")
+            .unwrap();
+        let imports = vec![
+            import("KERNEL32.dll", "GetTickCount"),
+            import("KERNEL32.dll", "GetTickCount64"),
+            import("KERNEL32.dll", "InitializeSRWLock"),
+            import("bcrypt.dll", "BCryptGenRandom"),
+            import("myhelper.dll", "InitializeSRWLock"),
+            Import {
+                delayed: true,
+                ..import("KERNEL32.dll", "GetTickCount64")
+            },
+        ];
+        let thunked = ThunkedApis::from([("kernel32", "InitializeSRWLock")]);
+
+        let missing = check_imports(&imports, &db, OS::WindowsXP, Arch::Win32, &thunked).unwrap();
+        assert_eq!(
+            missing,
+            vec![
+                MissingImport {
+                    import: imports[1].clone(),
+                    missing_dll: false
+                },
+                MissingImport {
+                    import: imports[3].clone(),
+                    missing_dll: true
+                },
+                MissingImport {
+                    import: imports[4].clone(),
+                    missing_dll: true
+                },
+                MissingImport {
+                    import: imports[5].clone(),
+                    missing_dll: false
+                },
+            ]
+        );
+        assert!(missing[0].fails_to_load());
+        assert!(!missing[3].fails_to_load());
+
+        assert!(check_imports(&imports, &db, OS::Windows7, Arch::Win32, &thunked).is_err());
+    }
+}
@@ -0,0 +1,453 @@
+use anyhow::{anyhow, Result};
+use clap::{Args, Subcommand};
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::mitigation::MitigationOverride;
+use crate::resource::{self, Package};
+use crate::sys::*;
+use crate::{ENV_VAR_SYSROOT, ENV_VAR_VC_LTL5, ENV_VAR_YY_THUNKS};
+
+pub(crate) const ENV_VAR_OS: &str = "THUNK_OS";
+pub(crate) const ENV_VAR_ARCH: &str = "THUNK_ARCH";
+pub(crate) const ENV_VAR_SUBSYSTEM: &str = "THUNK_SUBSYSTEM";
+pub(crate) const ENV_VAR_LINKER: &str = "THUNK_LINKER";
+pub(crate) const ENV_VAR_PATCH_PE: &str = "THUNK_PATCH_PE";
+pub(crate) const ENV_VAR_PATCH_LOAD_CONFIG: &str = "THUNK_PATCH_LOAD_CONFIG";
+pub(crate) const ENV_VAR_MITIGATION: &str = "THUNK_MITIGATION";
+const ENV_VAR_CONFIG: &str = "THUNK_CONFIG";
+
+const PROJECT_CONFIG: &str = "thunk.toml";
+
+/// The keys of the configuration and their environment variables.
+const KEYS: [(&str, &str); 10] = [
+    ("os", ENV_VAR_OS),
+    ("arch", ENV_VAR_ARCH),
+    ("subsystem", ENV_VAR_SUBSYSTEM),
+    ("vc-ltl", ENV_VAR_VC_LTL5),
+    ("yy-thunks", ENV_VAR_YY_THUNKS),
+    ("sysroot", ENV_VAR_SYSROOT),
+    ("linker", ENV_VAR_LINKER),
+    ("patch-pe", ENV_VAR_PATCH_PE),
+    ("patch-load-config", ENV_VAR_PATCH_LOAD_CONFIG),
+    ("mitigation", ENV_VAR_MITIGATION),
+];
+
+/// Where a value of the configuration comes from, from the lowest precedence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// The user config file.
+    User(PathBuf),
+    /// `[workspace.metadata.thunk]` of the workspace Cargo.toml.
+    Workspace(PathBuf),
+    /// `thunk.toml` at the workspace root.
+    Project(PathBuf),
+    /// `[package.metadata.thunk]` of the package Cargo.toml.
+    Package(PathBuf),
+    Env(&'static str),
+    Cli,
+}
+
+impl Source {
+    /// The directory relative paths of the source are relative to.
+    fn dir(&self) -> Option<&Path> {
+        match self {
+            Source::User(path)
+            | Source::Workspace(path)
+            | Source::Project(path)
+            | Source::Package(path) => path.parent(),
+            Source::Env(_) | Source::Cli => None,
+        }
+    }
+
+    fn value<T>(&self, value: T) -> Value<T> {
+        Value {
+            value,
+            source: self.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::User(path) => write!(f, "user config {}", path.to_string_lossy()),
+            Source::Workspace(path) => write!(
+                f,
+                "[workspace.metadata.thunk] of {}",
+                path.to_string_lossy()
+            ),
+            Source::Project(path) => f.write_str(&path.to_string_lossy()),
+            Source::Package(path) => {
+                write!(f, "[package.metadata.thunk] of {}", path.to_string_lossy())
+            }
+            Source::Env(name) => write!(f, "environment variable {}", name),
+            Source::Cli => f.write_str("command line"),
+        }
+    }
+}
+
+/// A value of the configuration and where it comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Value<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+/// The configuration of a build, from the user config, the project and the environment.
+///
+/// The command line takes precedence over the environment, then `[package.metadata.thunk]`,
+/// `thunk.toml` at the workspace root, `[workspace.metadata.thunk]` and the user config.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub os: Option<Value<OS>>,
+    pub arch: Option<Value<Arch>>,
+    pub subsystem: Option<Value<Subsystem>>,
+    pub vc_ltl: Option<Value<PathBuf>>,
+    pub yy_thunks: Option<Value<PathBuf>>,
+    pub sysroot: Option<Value<PathBuf>>,
+    pub linker: Option<Value<String>>,
+    pub patch_pe: Option<Value<bool>>,
+    pub patch_load_config: Option<Value<bool>>,
+    /// The overrides of every source, from the lowest precedence, as they apply in order.
+    pub mitigation: Vec<Value<MitigationOverride>>,
+    /// The config files found, from the lowest precedence.
+    pub files: Vec<Source>,
+    /// The package being built.
+    pub package: Option<Package>,
+    /// A package of the workspace has an icon in `[package.metadata.thunk]`.
+    pub package_icons: bool,
+}
+
+/// Get the user config file: `THUNK_CONFIG`, or `thunk/config.toml` in the config directory of
+/// the user.
+pub fn get_user_config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os(ENV_VAR_CONFIG) {
+        return Some(PathBuf::from(path));
+    }
+    let dir = match cfg!(windows) {
+        true => env::var_os("APPDATA").map(PathBuf::from),
+        false => env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|x| PathBuf::from(x).join(".config"))),
+    };
+    dir.map(|x| x.join("thunk").join("config.toml"))
+}
+
+fn read_table(path: &Path) -> Result<toml::Table> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read {}: {}", path.to_string_lossy(), e))?;
+    text.parse()
+        .map_err(|e| anyhow!("Failed to read {}: {}", path.to_string_lossy(), e))
+}
+
+fn parse_text(value: &toml::Value) -> Result<String> {
+    value
+        .as_str()
+        .map(str::to_owned)
+        .ok_or_else(|| anyhow!("expected a string, got {}", value))
+}
+
+fn parse_bool(value: &toml::Value) -> Result<bool> {
+    match value {
+        toml::Value::Boolean(on) => Ok(*on),
+        toml::Value::String(text) => match text.to_ascii_lowercase().as_str() {
+            "1" | "on" | "yes" | "true" => Ok(true),
+            "0" | "off" | "no" | "false" | "" => Ok(false),
+            _ => Err(anyhow!("expected a bool, got {}", text)),
+        },
+        _ => Err(anyhow!("expected a bool, got {}", value)),
+    }
+}
+
+/// Parse a name such as the OS, `names` lists the ones `parse` knows.
+fn parse_name<T>(value: &toml::Value, parse: fn(&str) -> Option<T>, names: &str) -> Result<T> {
+    let text = parse_text(value)?;
+    parse(&text).ok_or_else(|| anyhow!("expected one of {}, got {}", names, text))
+}
+
+/// Parse mitigation overrides, an array such as `["guard-cf=off"]` or a comma separated string.
+fn parse_mitigations(value: &toml::Value) -> Result<Vec<MitigationOverride>> {
+    let items = match value {
+        toml::Value::Array(items) => items.iter().map(parse_text).collect::<Result<Vec<_>>>()?,
+        _ => parse_text(value)?.split(',').map(str::to_owned).collect(),
+    };
+    items
+        .iter()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.parse().map_err(|e| anyhow!("{}", e)))
+        .collect()
+}
+
+impl Config {
+    /// Read the configuration of the package of `--package`, `--manifest-path` or the current
+    /// directory, without the command line.
+    pub fn load(package: Option<&str>, manifest_path: Option<&Path>) -> Result<Config> {
+        let mut config = Config::default();
+
+        if let Some(path) = get_user_config_path().filter(|x| x.is_file()) {
+            let table = read_table(&path)?;
+            config.apply(Source::User(path), &table)?;
+        }
+
+        // Outside of a cargo project, only thunk.toml in the current directory.
+        let (root, metadata) = match resource::read_cargo_metadata(manifest_path) {
+            Ok(metadata) => (
+                metadata["workspace_root"]
+                    .as_str()
+                    .map(PathBuf::from)
+                    .unwrap_or_default(),
+                Some(metadata),
+            ),
+            Err(_) => (env::current_dir()?, None),
+        };
+        if let Some(metadata) = &metadata {
+            let workspace = &metadata["metadata"]["thunk"];
+            if !workspace.is_null() {
+                let table: toml::Table = serde_json::from_value(workspace.clone())
+                    .map_err(|e| anyhow!("Failed to read [workspace.metadata.thunk]: {}", e))?;
+                config.apply(Source::Workspace(root.join("Cargo.toml")), &table)?;
+            }
+        }
+        let path = root.join(PROJECT_CONFIG);
+        if path.is_file() {
+            let table = read_table(&path)?;
+            config.apply(Source::Project(path), &table)?;
+        }
+        if let Some(metadata) = &metadata {
+            config.package = resource::find_package(metadata, package, manifest_path)?;
+            config.package_icons = resource::has_package_icons(metadata);
+        }
+        if let Some(package) = config.package.clone() {
+            if !package.metadata.is_empty() {
+                let source = Source::Package(package.dir.join("Cargo.toml"));
+                config.apply(source, &package.metadata)?;
+            }
+        }
+
+        for (key, name) in KEYS {
+            if let Some(value) = env::var_os(name) {
+                let mut table = toml::Table::new();
+                let value = toml::Value::String(value.to_string_lossy().to_string());
+                table.insert(key.to_owned(), value);
+                config.apply(Source::Env(name), &table)?;
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Apply the keys of a source over the ones of the sources before.
+    ///
+    /// Unknown keys are ignored in the metadata of `Cargo.toml`, which holds the resources and the
+    /// keys of thunk-rs too, and are errors in the config files, as a typo would go unnoticed.
+    pub fn apply(&mut self, source: Source, table: &toml::Table) -> Result<()> {
+        if !matches!(source, Source::Env(_) | Source::Cli) {
+            self.files.push(source.clone());
+        }
+        if matches!(source, Source::User(_) | Source::Project(_)) {
+            if let Some(key) = table.keys().find(|x| KEYS.iter().all(|(key, _)| key != x)) {
+                let keys: Vec<_> = KEYS.iter().map(|(key, _)| *key).collect();
+                return Err(anyhow!(
+                    "Unknown key {} in {}, expected one of {}",
+                    key,
+                    source,
+                    keys.join(", ")
+                ));
+            }
+        }
+        for (key, value) in table {
+            self.apply_key(&source, key, value)
+                .map_err(|e| anyhow!("Bad {} in {}: {}", key, source, e))?;
+        }
+        Ok(())
+    }
+
+    fn apply_key(&mut self, source: &Source, key: &str, value: &toml::Value) -> Result<()> {
+        let path = |value: &toml::Value| -> Result<PathBuf> {
+            let path = PathBuf::from(parse_text(value)?);
+            Ok(match source.dir() {
+                Some(dir) => dir.join(path),
+                None => path,
+            })
+        };
+        match key {
+            "os" => self.os = Some(source.value(parse_name(value, OS::parse, OS::NAMES)?)),
+            "arch" => self.arch = Some(source.value(parse_name(value, Arch::parse, Arch::NAMES)?)),
+            "subsystem" => {
+                let subsystem = parse_name(value, Subsystem::parse, Subsystem::NAMES)?;
+                self.subsystem = Some(source.value(subsystem));
+            }
+            "vc-ltl" => self.vc_ltl = Some(source.value(path(value)?)),
+            "yy-thunks" => self.yy_thunks = Some(source.value(path(value)?)),
+            "sysroot" => self.sysroot = Some(source.value(path(value)?)),
+            "linker" => self.linker = Some(source.value(parse_text(value)?)),
+            "patch-pe" => self.patch_pe = Some(source.value(parse_bool(value)?)),
+            "patch-load-config" => self.patch_load_config = Some(source.value(parse_bool(value)?)),
+            "mitigation" => {
+                for x in parse_mitigations(value)? {
+                    self.mitigation.push(source.value(x));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Get the mitigation overrides, the ones of the command line last.
+    pub fn get_mitigations(&self, cli: &[MitigationOverride]) -> Vec<MitigationOverride> {
+        self.mitigation
+            .iter()
+            .map(|x| x.value)
+            .chain(cli.iter().copied())
+            .collect()
+    }
+}
+
+fn print_value<T: fmt::Display>(key: &str, value: &Option<Value<T>>) {
+    match value {
+        Some(x) => println!(" * {} = {} ({})", key, x.value, x.source),
+        None => println!(" * {} is not set", key),
+    }
+}
+
+/// Manage the configuration of Thunk
+#[derive(Debug, Args)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    command: ConfigCommands,
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommands {
+    /// Show the configuration and where each value comes from
+    Show {
+        /// The package, as `cargo build -p`
+        #[arg(short, long, value_name = "SPEC")]
+        package: Option<String>,
+        /// Path to Cargo.toml
+        #[arg(long, value_name = "PATH")]
+        manifest_path: Option<PathBuf>,
+    },
+}
+
+impl ConfigArgs {
+    pub fn run(self) -> Result<()> {
+        match self.command {
+            ConfigCommands::Show {
+                package,
+                manifest_path,
+            } => {
+                let config = Config::load(package.as_deref(), manifest_path.as_deref())?;
+                println!("Config files:");
+                if let Some(path) = get_user_config_path().filter(|x| !x.is_file()) {
+                    println!(" * user config {} (not found)", path.to_string_lossy());
+                }
+                for file in &config.files {
+                    println!(" * {}", file);
+                }
+
+                let path = |x: &Option<Value<PathBuf>>| {
+                    x.as_ref().map(|x| Value {
+                        value: x.value.to_string_lossy().to_string(),
+                        source: x.source.clone(),
+                    })
+                };
+                println!("Config (the command line takes precedence):");
+                print_value("os", &config.os);
+                print_value("arch", &config.arch);
+                print_value("subsystem", &config.subsystem);
+                print_value("vc-ltl", &path(&config.vc_ltl));
+                print_value("yy-thunks", &path(&config.yy_thunks));
+                print_value("sysroot", &path(&config.sysroot));
+                print_value("linker", &config.linker);
+                print_value("patch-pe", &config.patch_pe);
+                print_value("patch-load-config", &config.patch_load_config);
+                for x in &config.mitigation {
+                    let on = if x.value.on { "on" } else { "off" };
+                    println!(" * mitigation {}={} ({})", x.value.mitigation, on, x.source);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let user: toml::Table =
+            "os = \"win7\"\nvc-ltl = \"VC-LTL\"\nmitigation = [\"guard-cf=off\"]"
+                .parse()
+                .unwrap();
+        let project: toml::Table = "os = \"xp\"\nmitigation = \"cetcompat=off,guard-cf=on\""
+            .parse()
+            .unwrap();
+        let mut config = Config::default();
+        config
+            .apply(
+                Source::User(PathBuf::from("/home/me/thunk/config.toml")),
+                &user,
+            )
+            .unwrap();
+        config
+            .apply(Source::Project(PathBuf::from("/work/thunk.toml")), &project)
+            .unwrap();
+
+        let os = config.os.as_ref().unwrap();
+        assert_eq!(os.value, OS::WindowsXP);
+        assert_eq!(
+            os.source,
+            Source::Project(PathBuf::from("/work/thunk.toml"))
+        );
+        assert_eq!(
+            config.vc_ltl.as_ref().unwrap().value,
+            Path::new("/home/me/thunk/VC-LTL")
+        );
+        assert_eq!(config.files.len(), 2);
+
+        // The last override wins.
+        let cli = ["guard-cf=off".parse().unwrap()];
+        let mitigations = config.get_mitigations(&cli);
+        assert_eq!(mitigations.len(), 4);
+        assert!(!mitigations[3].on);
+
+        let bad: toml::Table = "patch-pe = \"maybe\"".parse().unwrap();
+        assert!(config.apply(Source::Env(ENV_VAR_PATCH_PE), &bad).is_err());
+        let bad: toml::Table = "os = \"win11\"".parse().unwrap();
+        let error = config
+            .apply(Source::Project(PathBuf::from("/work/thunk.toml")), &bad)
+            .unwrap_err();
+        assert!(error.to_string().contains("os in /work/thunk.toml"));
+        assert_eq!(config.os.as_ref().unwrap().value, OS::WindowsXP);
+
+        // Only the metadata of Cargo.toml may have keys of others.
+        let typo: toml::Table = "yy_thunk = \"YY-Thunks\"".parse().unwrap();
+        let error = config
+            .apply(Source::Project(PathBuf::from("/work/thunk.toml")), &typo)
+            .unwrap_err();
+        assert!(error.to_string().contains("yy_thunk in /work/thunk.toml"));
+        assert!(config
+            .apply(
+                Source::User(PathBuf::from("/home/me/thunk/config.toml")),
+                &typo
+            )
+            .is_err());
+        let package: toml::Table = "yy_thunk = \"YY-Thunks\"\nicon = \"a.ico\""
+            .parse()
+            .unwrap();
+        config
+            .apply(
+                Source::Package(PathBuf::from("/work/app/Cargo.toml")),
+                &package,
+            )
+            .unwrap();
+        assert!(config.yy_thunks.is_none());
+    }
+}
@@ -0,0 +1,458 @@
+use anyhow::{anyhow, Result};
+use clap::Args;
+use iced_x86::{CpuidFeature, Decoder, DecoderOptions, Instruction};
+use object::{Object, ObjectSection, SectionKind};
+use pdb::FallibleIterator;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::map::{find_map_file, LinkMap};
+use crate::pe::read_arch;
+use crate::sys::*;
+
+/// The instruction set extensions an old CPU supports, from the oldest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CpuBaseline {
+    /// Pentium, the `i586-pc-windows-msvc` target.
+    I586,
+    /// Pentium Pro and Pentium II: CMOV, MMX and FXSR.
+    I686,
+    /// Pentium III and Athlon XP.
+    Sse,
+    /// Pentium 4 and Athlon 64, the `i686-pc-windows-msvc` target.
+    Sse2,
+    Sse3,
+    Ssse3,
+    Sse41,
+    Sse42,
+    Avx,
+    Avx2,
+}
+
+impl FromStr for CpuBaseline {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.to_ascii_lowercase().replace(['.', '_'], "").as_str() {
+            "i586" | "586" | "pentium" => Ok(CpuBaseline::I586),
+            "i686" | "686" | "p6" | "mmx" | "cmov" => Ok(CpuBaseline::I686),
+            "sse" => Ok(CpuBaseline::Sse),
+            "sse2" => Ok(CpuBaseline::Sse2),
+            "sse3" => Ok(CpuBaseline::Sse3),
+            "ssse3" => Ok(CpuBaseline::Ssse3),
+            "sse41" => Ok(CpuBaseline::Sse41),
+            "sse42" => Ok(CpuBaseline::Sse42),
+            "avx" => Ok(CpuBaseline::Avx),
+            "avx2" => Ok(CpuBaseline::Avx2),
+            _ => Err(format!(
+                "Unknown baseline {}, expected i586, i686, sse, sse2, sse3, ssse3, sse4.1, sse4.2, avx or avx2",
+                text
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CpuBaseline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            CpuBaseline::I586 => "i586",
+            CpuBaseline::I686 => "i686",
+            CpuBaseline::Sse => "SSE",
+            CpuBaseline::Sse2 => "SSE2",
+            CpuBaseline::Sse3 => "SSE3",
+            CpuBaseline::Ssse3 => "SSSE3",
+            CpuBaseline::Sse41 => "SSE4.1",
+            CpuBaseline::Sse42 => "SSE4.2",
+            CpuBaseline::Avx => "AVX",
+            CpuBaseline::Avx2 => "AVX2",
+        };
+        f.write_str(text)
+    }
+}
+
+/// Get the oldest baseline with a CPUID feature, `None` if no baseline has it.
+pub fn get_feature_baseline(feature: CpuidFeature) -> Option<CpuBaseline> {
+    use CpuidFeature::*;
+    match feature {
+        INTEL8086 | INTEL186 | INTEL286 | INTEL386 | INTEL486 | FPU | FPU287 | FPU387 | CPUID
+        | TSC | CX8 | MSR | PAUSE => Some(CpuBaseline::I586),
+        CMOV | MMX | FXSR | MULTIBYTENOP | SEP | RDPMC => Some(CpuBaseline::I686),
+        SSE => Some(CpuBaseline::Sse),
+        SSE2 | CLFSH | X64 => Some(CpuBaseline::Sse2),
+        SSE3 | MONITOR => Some(CpuBaseline::Sse3),
+        SSSE3 => Some(CpuBaseline::Ssse3),
+        SSE4_1 => Some(CpuBaseline::Sse41),
+        SSE4_2 | POPCNT | CMPXCHG16B => Some(CpuBaseline::Sse42),
+        AVX | AES | PCLMULQDQ => Some(CpuBaseline::Avx),
+        AVX2 | FMA | BMI1 | BMI2 | F16C | LZCNT | MOVBE => Some(CpuBaseline::Avx2),
+        _ => None,
+    }
+}
+
+/// An instruction the baseline CPU can not run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuInstruction {
+    pub address: u64,
+    pub mnemonic: String,
+    /// CPUID features above the baseline, such as `SSE4_1`.
+    pub features: BTreeSet<String>,
+}
+
+/// Disassemble machine code and find the instructions above the baseline.
+pub fn scan_code(
+    code: &[u8],
+    bitness: u32,
+    address: u64,
+    baseline: CpuBaseline,
+) -> Vec<CpuInstruction> {
+    let mut decoder = Decoder::with_ip(bitness, code, address, DecoderOptions::NONE);
+    let mut instruction = Instruction::default();
+    let mut found = Vec::new();
+
+    while decoder.can_decode() {
+        decoder.decode_out(&mut instruction);
+        if instruction.is_invalid() {
+            continue;
+        }
+        let features: BTreeSet<String> = instruction
+            .cpuid_features()
+            .iter()
+            .filter(|&&feature| get_feature_baseline(feature).is_none_or(|x| x > baseline))
+            .map(|feature| format!("{:?}", feature))
+            .collect();
+        if !features.is_empty() {
+            found.push(CpuInstruction {
+                address: instruction.ip(),
+                mnemonic: format!("{:?}", instruction.mnemonic()).to_ascii_lowercase(),
+                features,
+            });
+        }
+    }
+
+    found
+}
+
+/// A function with instructions above the baseline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuFunction {
+    /// Symbol name, or the address if there are no symbols.
+    pub name: String,
+    pub address: u64,
+    pub instructions: Vec<CpuInstruction>,
+}
+
+impl CpuFunction {
+    pub fn features(&self) -> BTreeSet<&str> {
+        self.instructions
+            .iter()
+            .flat_map(|x| x.features.iter().map(|x| x.as_str()))
+            .collect()
+    }
+}
+
+/// Group the instructions into the functions of the symbols, addresses sorted.
+///
+/// An instruction belongs to the nearest symbol before it, but not before the start of its section.
+pub fn group_by_function(
+    instructions: Vec<CpuInstruction>,
+    symbols: &BTreeMap<u64, String>,
+    section_start: u64,
+) -> Vec<CpuFunction> {
+    let mut functions: BTreeMap<u64, CpuFunction> = BTreeMap::new();
+    for instruction in instructions {
+        let (address, name) = match symbols.range(..=instruction.address).next_back() {
+            Some((&address, name)) if address >= section_start => (address, name.clone()),
+            _ => (instruction.address, format!("{:#x}", instruction.address)),
+        };
+        functions
+            .entry(address)
+            .or_insert_with(|| CpuFunction {
+                name,
+                address,
+                instructions: Vec::new(),
+            })
+            .instructions
+            .push(instruction);
+    }
+    functions.into_values().collect()
+}
+
+/// Demangle a Rust symbol, leaving other names as they are.
+fn demangle(name: &str, is_x86: bool) -> String {
+    let name = if is_x86 {
+        name.strip_prefix('_').unwrap_or(name)
+    } else {
+        name
+    };
+    match rustc_demangle::try_demangle(name) {
+        Ok(demangled) => format!("{:#}", demangled),
+        Err(_) => name.to_owned(),
+    }
+}
+
+/// Read the symbols of a PDB, procedure names win over the public ones.
+///
+/// Public symbols of assembly code are not marked as functions, so data symbols are read too, they
+/// never start inside a code section.
+pub fn read_pdb_functions(
+    path: &Path,
+    image_base: u64,
+    is_x86: bool,
+) -> Result<BTreeMap<u64, String>> {
+    let file = fs::File::open(path)
+        .map_err(|e| anyhow!("Failed to read {}: {}", path.to_string_lossy(), e))?;
+    let mut pdb = pdb::PDB::open(file)?;
+    let address_map = pdb.address_map()?;
+    let mut functions = BTreeMap::new();
+
+    let globals = pdb.global_symbols()?;
+    let mut symbols = globals.iter();
+    while let Some(symbol) = symbols.next()? {
+        if let Ok(pdb::SymbolData::Public(public)) = symbol.parse() {
+            if let Some(rva) = public.offset.to_rva(&address_map) {
+                functions.insert(
+                    image_base + u64::from(rva.0),
+                    demangle(&public.name.to_string(), is_x86),
+                );
+            }
+        }
+    }
+
+    let dbi = pdb.debug_information()?;
+    let mut modules = dbi.modules()?;
+    while let Some(module) = modules.next()? {
+        let info = match pdb.module_info(&module)? {
+            Some(info) => info,
+            None => continue,
+        };
+        let mut symbols = info.symbols()?;
+        while let Some(symbol) = symbols.next()? {
+            if let Ok(pdb::SymbolData::Procedure(procedure)) = symbol.parse() {
+                if let Some(rva) = procedure.offset.to_rva(&address_map) {
+                    functions.insert(
+                        image_base + u64::from(rva.0),
+                        procedure.name.to_string().into_owned(),
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(functions)
+}
+
+/// Read the symbols of a link map, the `f` flag is missing for assembly code.
+pub fn read_map_functions(map: &LinkMap, is_x86: bool) -> BTreeMap<u64, String> {
+    map.symbols
+        .iter()
+        .filter(|symbol| symbol.section != 0)
+        .map(|symbol| (symbol.address, demangle(&symbol.name, is_x86)))
+        .collect()
+}
+
+/// Find the PDB of a build, next to it or where its debug directory says.
+pub fn find_pdb_file(file: &Path, data: &[u8]) -> Option<PathBuf> {
+    let mut candidates = vec![file.with_extension("pdb")];
+    let code_view = object::File::parse(data).and_then(|x| {
+        x.pdb_info()
+            .map(|x| x.map(|x| String::from_utf8_lossy(x.path()).to_string()))
+    });
+    if let Ok(Some(path)) = code_view {
+        let name = path.rsplit(['/', '\\']).next().unwrap_or(&path).to_owned();
+        if let Some(dir) = file.parent() {
+            candidates.push(dir.join(&name));
+            candidates.push(dir.join("deps").join(&name));
+        }
+        candidates.push(PathBuf::from(path));
+    }
+    candidates.into_iter().find(|path| path.is_file())
+}
+
+/// The functions of a build with instructions above the baseline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuReport {
+    /// Where the symbols come from: `pdb`, `map` or `exports`.
+    pub symbols: String,
+    pub functions: Vec<CpuFunction>,
+}
+
+/// Scan the code sections of a build for instructions above the baseline.
+pub fn check_file(
+    file: &Path,
+    baseline: CpuBaseline,
+    pdb_file: Option<&Path>,
+    map_file: Option<&Path>,
+) -> Result<CpuReport> {
+    let data =
+        fs::read(file).map_err(|e| anyhow!("Failed to read {}: {}", file.to_string_lossy(), e))?;
+    let arch = read_arch(&data)?;
+    let bitness = match arch {
+        Arch::Win32 => 32,
+        Arch::X64 if baseline >= CpuBaseline::Sse2 => 64,
+        Arch::X64 => {
+            return Err(anyhow!(
+                "Every x64 CPU has SSE2, use sse2 or a newer baseline"
+            ))
+        }
+        Arch::ARM64 => return Err(anyhow!("Only x86 and x64 builds can be checked")),
+    };
+    let pe = object::File::parse(data.as_slice())?;
+    let image_base = pe.relative_address_base();
+
+    let pdb_file = pdb_file
+        .map(|x| x.to_owned())
+        .or_else(|| find_pdb_file(file, &data));
+    let map_file = map_file
+        .map(|x| x.to_owned())
+        .or_else(|| find_map_file(file));
+    let (source, symbols) = if let Some(pdb_file) = pdb_file {
+        (
+            "pdb",
+            read_pdb_functions(&pdb_file, image_base, bitness == 32)?,
+        )
+    } else if let Some(map_file) = map_file {
+        (
+            "map",
+            read_map_functions(&LinkMap::load(&map_file)?, bitness == 32),
+        )
+    } else {
+        let mut symbols: BTreeMap<u64, String> = pe
+            .exports()?
+            .iter()
+            .map(|x| (x.address(), String::from_utf8_lossy(x.name()).to_string()))
+            .collect();
+        symbols
+            .entry(pe.entry())
+            .or_insert_with(|| "<entry>".to_owned());
+        ("exports", symbols)
+    };
+
+    let mut functions = Vec::new();
+    for section in pe.sections().filter(|x| x.kind() == SectionKind::Text) {
+        let instructions = scan_code(section.data()?, bitness, section.address(), baseline);
+        functions.extend(group_by_function(instructions, &symbols, section.address()));
+    }
+
+    Ok(CpuReport {
+        symbols: source.to_owned(),
+        functions,
+    })
+}
+
+/// Print the functions of a report.
+pub fn print_cpu_report(report: &CpuReport) {
+    for function in &report.functions {
+        let features: Vec<&str> = function.features().into_iter().collect();
+        let first = &function.instructions[0];
+        println!(
+            " * {} ({:#x}): {} instructions need {}, first {} at {:#x}",
+            function.name,
+            function.address,
+            function.instructions.len(),
+            features.join(", "),
+            first.mnemonic,
+            first.address
+        );
+    }
+}
+
+/// Report the functions with instructions the baseline CPU can not run
+#[derive(Debug, Args)]
+pub struct CpuCheckArgs {
+    /// The exe or dll to check
+    file: PathBuf,
+    /// CPU baseline: i586, i686, sse, sse2, sse3, ssse3, sse4.1, sse4.2, avx, avx2
+    #[arg(short, long, value_name = "CPU", default_value = "sse2")]
+    baseline: CpuBaseline,
+    /// PDB file (default: the PDB of the file in the same or the deps directory)
+    #[arg(long, value_name = "FILE")]
+    pdb: Option<PathBuf>,
+    /// Link map, used without a PDB (default: the newest map of the file)
+    #[arg(long, value_name = "FILE")]
+    map: Option<PathBuf>,
+}
+
+impl CpuCheckArgs {
+    pub fn run(self) -> Result<()> {
+        let report = check_file(
+            &self.file,
+            self.baseline,
+            self.pdb.as_deref(),
+            self.map.as_deref(),
+        )?;
+        println!(
+            "Check instructions of {} for {} CPUs (symbols from {}):",
+            self.file.to_string_lossy(),
+            self.baseline,
+            report.symbols
+        );
+        print_cpu_report(&report);
+
+        if report.functions.is_empty() {
+            println!("All instructions run on {} CPUs.", self.baseline);
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "{} functions have instructions {} CPUs can not run",
+                report.functions.len(),
+                self.baseline
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // cmovz eax, ecx; movaps xmm0, xmm1; movdqa xmm0, xmm1; vpaddd ymm0, ymm0, ymm1; ret
+    const CODE: &[u8] = &[
+        0x0F, 0x44, 0xC1, 0x0F, 0x28, 0xC1, 0x66, 0x0F, 0x6F, 0xC1, 0xC5, 0xFD, 0xFE, 0xC1, 0xC3,
+    ];
+
+    #[test]
+    fn test_scan_code() {
+        let addresses = |baseline| {
+            scan_code(CODE, 32, 0x401000, baseline)
+                .iter()
+                .map(|x| x.address)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            addresses(CpuBaseline::I586),
+            [0x401000, 0x401003, 0x401006, 0x40100A]
+        );
+        assert_eq!(addresses(CpuBaseline::Sse), [0x401006, 0x40100A]);
+        assert!(addresses(CpuBaseline::Avx2).is_empty());
+
+        let found = scan_code(CODE, 32, 0x401000, CpuBaseline::Sse2);
+        assert_eq!(found[0].mnemonic, "vpaddd");
+        assert_eq!(found[0].features, BTreeSet::from(["AVX2".to_owned()]));
+    }
+
+    #[test]
+    fn test_group_by_function() {
+        let instructions = scan_code(CODE, 32, 0x401000, CpuBaseline::I586);
+        let symbols = BTreeMap::from([
+            (0x400f00, "other_section".to_owned()),
+            (0x401006, "simd".to_owned()),
+        ]);
+        let functions = group_by_function(instructions, &symbols, 0x401000);
+        assert_eq!(functions.len(), 3);
+        assert_eq!(functions[0].name, "0x401000");
+        assert_eq!(functions[2].name, "simd");
+        assert_eq!(functions[2].features(), BTreeSet::from(["AVX2", "SSE2"]));
+    }
+
+    #[test]
+    fn test_parse_baseline() {
+        assert_eq!("SSE4.1".parse(), Ok(CpuBaseline::Sse41));
+        assert_eq!("i586".parse(), Ok(CpuBaseline::I586));
+        assert!("avx512".parse::<CpuBaseline>().is_err());
+        assert!(CpuBaseline::Sse < CpuBaseline::Sse2);
+    }
+}
@@ -0,0 +1,265 @@
+use anyhow::{anyhow, Result};
+use clap::{Args, Subcommand};
+use object::{
+    coff::{ImportFile, ImportName as CoffImportName},
+    read::archive::ArchiveFile,
+    Architecture, FileKind, Object, ObjectSymbol,
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::config::Config;
+use crate::pe::{Import, ImportName};
+use crate::sys::*;
+use crate::thunks::undecorate;
+use crate::ENV_VAR_VC_LTL5;
+
+const IMPORT_DESCRIPTOR_PREFIX: &str = "__IMPORT_DESCRIPTOR_";
+
+/// Where a CRT function found in the VC-LTL libraries comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrtSymbol {
+    /// The `.lib` file providing the function.
+    pub lib: String,
+    /// The DLL the function binds to, linked statically if `None`.
+    pub dll: Option<String>,
+}
+
+/// Get the VC-LTL directory from the environment variable or the configuration of the current
+/// directory.
+pub fn get_vc_ltl_dir() -> Result<PathBuf> {
+    Config::load(None, None)?
+        .vc_ltl
+        .map(|x| x.value)
+        .ok_or_else(|| {
+            anyhow!(
+                "You need to set {} environment variable or vc-ltl in thunk.toml.",
+                ENV_VAR_VC_LTL5
+            )
+        })
+}
+
+/// Get the CRT functions VC-LTL provides for the OS and arch.
+pub fn get_crt_symbols(vc_ltl: &Path, os: OS, arch: Arch) -> Result<BTreeMap<String, CrtSymbol>> {
+    let lib_path = get_vc_ltl_os_lib_path(os, arch)
+        .ok_or_else(|| anyhow!("VC-LTL does not support Windows {}({})", os, arch))?;
+    read_crt_symbols(&vc_ltl.join(lib_path))
+}
+
+/// Read every `.lib` archive of a VC-LTL `TargetPlatform/<ver>/lib/<arch>` directory.
+pub fn read_crt_symbols(lib_dir: &Path) -> Result<BTreeMap<String, CrtSymbol>> {
+    let mut symbols = BTreeMap::new();
+
+    let entries = fs::read_dir(lib_dir)
+        .map_err(|e| anyhow!("Failed to read {}: {}", lib_dir.to_string_lossy(), e))?;
+    let mut libs: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("lib"))
+        })
+        .collect();
+    libs.sort();
+
+    for lib in libs {
+        let lib_name = lib.file_name().unwrap().to_string_lossy().to_string();
+        let data = fs::read(&lib)?;
+        for (name, dll) in read_lib_symbols(&data)
+            .map_err(|e| anyhow!("Failed to parse {}: {}", lib.to_string_lossy(), e))?
+        {
+            symbols.entry(name).or_insert_with(|| CrtSymbol {
+                lib: lib_name.clone(),
+                dll,
+            });
+        }
+    }
+
+    Ok(symbols)
+}
+
+/// Read the functions of a COFF archive, with the DLL each one binds to.
+fn read_lib_symbols(data: &[u8]) -> Result<Vec<(String, Option<String>)>> {
+    let archive = ArchiveFile::parse(data)?;
+    let mut symbols = Vec::new();
+
+    for member in archive.members() {
+        let member = member?.data(data)?;
+        match FileKind::parse(member) {
+            // Short import library member
+            Ok(FileKind::CoffImport) => {
+                let import = ImportFile::parse(member)?;
+                let name = match import.import() {
+                    CoffImportName::Name(name) => String::from_utf8_lossy(name).into_owned(),
+                    CoffImportName::Ordinal(_) => undecorate(
+                        &String::from_utf8_lossy(import.symbol()),
+                        import.architecture() == Architecture::I386,
+                    ),
+                };
+                let dll = String::from_utf8_lossy(import.dll()).into_owned();
+                symbols.push((name, Some(dll)));
+            }
+            Ok(FileKind::Coff | FileKind::CoffBig) => {
+                let file = object::File::parse(member)?;
+                let is_x86 = file.architecture() == Architecture::I386;
+
+                // Long import members reference the import descriptor of their DLL.
+                let dll = file
+                    .symbols()
+                    .filter(|symbol| symbol.is_undefined())
+                    .filter_map(|symbol| symbol.name().ok())
+                    .find_map(|name| name.strip_prefix(IMPORT_DESCRIPTOR_PREFIX))
+                    .map(|name| format!("{}.dll", name));
+
+                for symbol in file.symbols() {
+                    if !symbol.is_global() || !symbol.is_definition() {
+                        continue;
+                    }
+                    let name = symbol.name()?;
+                    if is_import_lib_symbol(name) {
+                        continue;
+                    }
+                    symbols.push((undecorate(name, is_x86), dll.clone()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(symbols)
+}
+
+/// Symbols the librarian generates for the import descriptors, not CRT functions.
+fn is_import_lib_symbol(name: &str) -> bool {
+    name.starts_with("__imp_")
+        || name.starts_with(IMPORT_DESCRIPTOR_PREFIX)
+        || name == "__NULL_IMPORT_DESCRIPTOR"
+        || name.ends_with("_NULL_THUNK_DATA")
+}
+
+/// Whether the DLL belongs to the VC++ redistributable on the target OS.
+pub fn is_vc_redist_dll(dll: &str, os: OS) -> bool {
+    let dll = dll.to_ascii_lowercase();
+    [
+        "vcruntime",
+        "msvcp1",
+        "concrt",
+        "vccorlib",
+        "api-ms-win-crt-",
+    ]
+    .iter()
+    .any(|prefix| dll.starts_with(prefix))
+        || (dll == "ucrtbase.dll" && os < OS::Windows10)
+}
+
+/// Explain why an import still binds to the VC++ redistributable.
+pub fn diagnose_crt_import(import: &Import, crt: &BTreeMap<String, CrtSymbol>) -> String {
+    let symbol = match &import.name {
+        ImportName::Name(name) => crt.get(name),
+        ImportName::Ordinal(_) => None,
+    };
+    match symbol {
+        Some(CrtSymbol {
+            lib,
+            dll: Some(dll),
+        }) => format!(
+            "VC-LTL binds it to {} ({}), VC-LTL was not in front of the library search path",
+            dll, lib
+        ),
+        Some(CrtSymbol { lib, dll: None }) => format!(
+            "VC-LTL links it statically ({}), VC-LTL was not in front of the library search path",
+            lib
+        ),
+        None => "VC-LTL does not provide it for this platform".to_owned(),
+    }
+}
+
+/// Inspect the CRT libraries of VC-LTL
+#[derive(Debug, Args)]
+pub struct CrtArgs {
+    #[command(subcommand)]
+    command: CrtCommands,
+}
+
+#[derive(Debug, Subcommand)]
+enum CrtCommands {
+    /// List the CRT functions VC-LTL provides and the DLL each one binds to
+    List {
+        /// Operating system: xp, vista, win7, win10, 20h1 (dafault: win7)
+        #[arg(short, long, value_name = "OS")]
+        os: Option<OS>,
+        /// Operating system arch: x86, x64, arm64 (dafault: x86)
+        #[arg(short, long)]
+        arch: Option<Arch>,
+        /// Only list the functions bound to this DLL, use `static` for static ones
+        #[arg(long)]
+        dll: Option<String>,
+    },
+}
+
+impl CrtArgs {
+    pub fn run(self) -> Result<()> {
+        match self.command {
+            CrtCommands::List { os, arch, dll } => {
+                let os = os.unwrap_or(OS::Windows7);
+                let arch = arch.unwrap_or(Arch::Win32);
+                let symbols = get_crt_symbols(&get_vc_ltl_dir()?, os, arch)?;
+
+                println!("VC-LTL CRT functions for Windows {}({}):", os, arch);
+                let mut count = 0;
+                for (name, symbol) in &symbols {
+                    let bind = symbol.dll.as_deref().unwrap_or("static");
+                    if dll
+                        .as_ref()
+                        .is_some_and(|dll| !dll.eq_ignore_ascii_case(bind))
+                    {
+                        continue;
+                    }
+                    println!(" * {} -> {} ({})", name, bind, symbol.lib);
+                    count += 1;
+                }
+                println!("Total: {}", count);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_vc_redist_dll() {
+        assert!(is_vc_redist_dll("VCRUNTIME140.dll", OS::Windows10));
+        assert!(is_vc_redist_dll(
+            "api-ms-win-crt-runtime-l1-1-0.dll",
+            OS::Windows7
+        ));
+        assert!(is_vc_redist_dll("ucrtbase.dll", OS::Windows7));
+        assert!(!is_vc_redist_dll("ucrtbase.dll", OS::Windows10));
+        assert!(!is_vc_redist_dll("msvcrt.dll", OS::WindowsXP));
+    }
+
+    #[test]
+    fn test_diagnose_crt_import() {
+        let crt = BTreeMap::from([(
+            "malloc".to_owned(),
+            CrtSymbol {
+                lib: "msvcrt.lib".to_owned(),
+                dll: Some("msvcrt.dll".to_owned()),
+            },
+        )]);
+        let import = |name: &str| Import {
+            dll: "vcruntime140.dll".to_owned(),
+            name: ImportName::Name(name.to_owned()),
+            delayed: false,
+        };
+        assert!(diagnose_crt_import(&import("malloc"), &crt).contains("msvcrt.dll"));
+        assert!(diagnose_crt_import(&import("memset"), &crt).contains("does not provide"));
+        assert!(is_import_lib_symbol("\x7fmsvcrt_NULL_THUNK_DATA"));
+        assert!(!is_import_lib_symbol("mainCRTStartup"));
+    }
+}
@@ -0,0 +1,422 @@
+use anyhow::{anyhow, Result};
+use clap::{Args, Subcommand};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::pe::{read_arch, read_exports, ImportName};
+use crate::sys::{parse_arch_arg, parse_os_arg, Arch, OS};
+
+const DB_MAGIC: &str = "thunk-db";
+const DB_VERSION: u32 = 1;
+
+/// A function exported by a system DLL.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Export {
+    pub ordinal: u32,
+    /// Exported by ordinal only if `None`.
+    pub name: Option<String>,
+    /// Forwarder such as `NTDLL.RtlAcquireSRWLockExclusive` or `NTDLL.#5`.
+    pub forward: Option<String>,
+}
+
+/// Exports of a system DLL.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DllExports {
+    pub exports: BTreeSet<Export>,
+}
+
+impl DllExports {
+    pub fn contains(&self, name: &ImportName) -> bool {
+        match name {
+            ImportName::Name(name) => self
+                .exports
+                .iter()
+                .any(|export| export.name.as_deref() == Some(name.as_str())),
+            ImportName::Ordinal(ordinal) => self
+                .exports
+                .iter()
+                .any(|export| export.ordinal == u32::from(*ordinal)),
+        }
+    }
+}
+
+/// Export lists of the system DLLs of every OS and arch.
+///
+/// The database is a line based text file:
+///
+/// ```text
+/// thunk-db 1
+/// target XP Win32
+/// dll kernel32.dll
+/// 1 AddAtomA
+/// 2 AcquireSRWLockExclusive NTDLL.RtlAcquireSRWLockExclusive
+/// 17 -
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExportDb {
+    targets: BTreeMap<(OS, Arch), BTreeMap<String, DllExports>>,
+}
+
+impl ExportDb {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read {}: {}", path.to_string_lossy(), e))?;
+        Self::parse(&text).map_err(|e| anyhow!("{}: {}", path.to_string_lossy(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+        match lines
+            .next()
+            .map(|(_, line)| line.split_whitespace().collect::<Vec<_>>())
+        {
+            Some(header) if header.first() == Some(&DB_MAGIC) => {
+                let version: u32 = header.get(1).and_then(|x| x.parse().ok()).unwrap_or(0);
+                if version != DB_VERSION {
+                    return Err(anyhow!("database version {} is not support!", version));
+                }
+            }
+            _ => return Err(anyhow!("not a thunk export database")),
+        }
+
+        let mut db = Self::default();
+        let mut target = None;
+        let mut dll = None;
+
+        for (number, line) in lines {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            match tokens.as_slice() {
+                ["target", os, arch] => {
+                    let os = OS::parse(os).ok_or_else(|| {
+                        anyhow!("line {}: unknown os {} of `{}`", number, os, line)
+                    })?;
+                    let arch = Arch::parse(arch).ok_or_else(|| {
+                        anyhow!("line {}: unknown arch {} of `{}`", number, arch, line)
+                    })?;
+                    target = Some((os, arch));
+                    dll = None;
+                }
+                ["dll", name] => {
+                    let key =
+                        target.ok_or_else(|| anyhow!("line {}: dll before target", number))?;
+                    let name = name.to_ascii_lowercase();
+                    db.targets
+                        .entry(key)
+                        .or_default()
+                        .entry(name.clone())
+                        .or_default();
+                    dll = Some((key, name));
+                }
+                [ordinal, name, rest @ ..] if rest.len() <= 1 => {
+                    let (key, dll) = dll
+                        .as_ref()
+                        .ok_or_else(|| anyhow!("line {}: export before dll", number))?;
+                    let ordinal = ordinal
+                        .parse()
+                        .map_err(|_| anyhow!("line {}: bad ordinal {}", number, ordinal))?;
+                    let export = Export {
+                        ordinal,
+                        name: (*name != "-").then(|| name.to_string()),
+                        forward: rest.first().map(|x| x.to_string()),
+                    };
+                    db.targets
+                        .entry(*key)
+                        .or_default()
+                        .entry(dll.clone())
+                        .or_default()
+                        .exports
+                        .insert(export);
+                }
+                _ => return Err(anyhow!("line {}: unexpected `{}`", number, line)),
+            }
+        }
+
+        Ok(db)
+    }
+
+    /// Load and merge several databases, later files win.
+    pub fn load_all<I, P>(paths: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut db = Self::default();
+        for path in paths {
+            db.merge(Self::load(path.as_ref())?);
+        }
+        Ok(db)
+    }
+
+    /// Merge another database into this one, its DLLs replace the same DLLs of this one.
+    pub fn merge(&mut self, other: ExportDb) {
+        for (key, dlls) in other.targets {
+            self.targets.entry(key).or_default().extend(dlls);
+        }
+    }
+
+    /// Replace all DLLs of a target.
+    pub fn set_target(&mut self, os: OS, arch: Arch, dlls: BTreeMap<String, DllExports>) {
+        let dlls = dlls
+            .into_iter()
+            .map(|(dll, exports)| (dll.to_ascii_lowercase(), exports))
+            .collect();
+        self.targets.insert((os, arch), dlls);
+    }
+
+    pub fn has_target(&self, os: OS, arch: Arch) -> bool {
+        self.targets.contains_key(&(os, arch))
+    }
+
+    pub fn targets(&self) -> impl Iterator<Item = (OS, Arch)> + '_ {
+        self.targets.keys().copied()
+    }
+
+    /// Get the exports of a DLL, the name is case insensitive.
+    pub fn get(&self, os: OS, arch: Arch, dll: &str) -> Option<&DllExports> {
+        self.targets
+            .get(&(os, arch))?
+            .get(&dll.to_ascii_lowercase())
+    }
+}
+
+/// Read the exports of every DLL of the arch in a directory copied from a reference machine.
+pub fn read_dll_dir(dir: &Path, arch: Arch) -> Result<BTreeMap<String, DllExports>> {
+    let mut dlls = BTreeMap::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_dll = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("dll"));
+        if !is_dll || !path.is_file() {
+            continue;
+        }
+
+        let data = fs::read(&path)?;
+        let exports = match read_arch(&data) {
+            Ok(dll_arch) if dll_arch == arch => read_exports(&data),
+            Ok(dll_arch) => Err(anyhow!("arch is {}", dll_arch)),
+            Err(e) => Err(e),
+        };
+
+        match exports {
+            Ok(exports) => {
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                dlls.insert(name.to_ascii_lowercase(), exports);
+            }
+            Err(e) => println!(" * Skip {}: {}", path.to_string_lossy(), e),
+        }
+    }
+
+    Ok(dlls)
+}
+
+/// Manage the export database used by `thunk check`
+#[derive(Debug, Args)]
+pub struct DbArgs {
+    #[command(subcommand)]
+    command: DbCommands,
+}
+
+#[derive(Debug, Subcommand)]
+enum DbCommands {
+    /// Add the exports of a folder of system DLLs to a database
+    Build {
+        /// Operating system the DLLs are copied from: xp, vista, win7, win10, 20h1
+        #[arg(short, long, value_name = "OS", value_parser = parse_os_arg)]
+        os: OS,
+        /// Operating system arch: x86, x64, arm64
+        #[arg(short, long, value_parser = parse_arch_arg)]
+        arch: Arch,
+        /// Folder of DLLs, such as a copy of system32
+        #[arg(long, value_name = "DIR")]
+        from: PathBuf,
+        /// Database file, updated if it exists
+        #[arg(long, value_name = "FILE", default_value = "thunk.db")]
+        output: PathBuf,
+    },
+    /// Merge several databases into one
+    Merge {
+        /// Databases to merge, later files win
+        #[arg(required = true, value_name = "FILES")]
+        inputs: Vec<PathBuf>,
+        /// Database file to write
+        #[arg(long, value_name = "FILE")]
+        output: PathBuf,
+    },
+}
+
+impl DbArgs {
+    pub fn run(self) -> Result<()> {
+        match self.command {
+            DbCommands::Build {
+                os,
+                arch,
+                from,
+                output,
+            } => {
+                println!(
+                    "Read exports of Windows {}({}) from {}:",
+                    os,
+                    arch,
+                    from.to_string_lossy()
+                );
+                let dlls = read_dll_dir(&from, arch)?;
+                if dlls.is_empty() {
+                    return Err(anyhow!(
+                        "No {} DLL found in {}",
+                        arch,
+                        from.to_string_lossy()
+                    ));
+                }
+
+                let mut db = if output.exists() {
+                    ExportDb::load(&output)?
+                } else {
+                    ExportDb::default()
+                };
+                println!(" * DLLs: {}", dlls.len());
+                db.set_target(os, arch, dlls);
+                db.save(&output)?;
+                println!("Database is saved to {}", output.to_string_lossy());
+            }
+            DbCommands::Merge { inputs, output } => {
+                let db = ExportDb::load_all(&inputs)?;
+                db.save(&output)?;
+                println!(
+                    "Merged {} databases into {}",
+                    inputs.len(),
+                    output.to_string_lossy()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ExportDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} {}", DB_MAGIC, DB_VERSION)?;
+        for ((os, arch), dlls) in &self.targets {
+            writeln!(f, "target {} {}", os, arch)?;
+            for (dll, exports) in dlls {
+                writeln!(f, "dll {}", dll)?;
+                for export in &exports.exports {
+                    let name = export.name.as_deref().unwrap_or("-");
+                    write!(f, "{} {}", export.ordinal, name)?;
+                    if let Some(forward) = &export.forward {
+                        write!(f, " {}", forward)?;
+                    }
+                    writeln!(f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DB: &str = "thunk-db 1
+target XP Win32
+dll kernel32.dll
+1 AddAtomA
+2 AcquireSRWLockExclusive NTDLL.RtlAcquireSRWLockExclusive
+17 -
+";
+
+    #[test]
+    fn test_parse_db() {
+        let db = ExportDb::parse(DB).unwrap();
+        let exports = db.get(OS::WindowsXP, Arch::Win32, "KERNEL32.dll").unwrap();
+        assert!(exports.contains(&ImportName::Name("AddAtomA".into())));
+        assert!(exports.contains(&ImportName::Name("AcquireSRWLockExclusive".into())));
+        assert!(exports.contains(&ImportName::Ordinal(17)));
+        assert!(!exports.contains(&ImportName::Name("GetTickCount64".into())));
+        assert!(db.get(OS::WindowsXP, Arch::X64, "kernel32.dll").is_none());
+        assert_eq!(DB, db.to_string());
+    }
+
+    #[test]
+    fn test_merge_db() {
+        let mut db = ExportDb::parse(DB).unwrap();
+        let other = ExportDb::parse(
+            "thunk-db 1\ntarget XP Win32\ndll kernel32.dll\n1 GetTickCount\ndll user32.dll\n1 MessageBoxW\ntarget 7 x64\ndll kernel32.dll\n1 GetTickCount64",
+        )
+        .unwrap();
+        db.merge(other);
+
+        let exports = db.get(OS::WindowsXP, Arch::Win32, "kernel32.dll").unwrap();
+        assert!(exports.contains(&ImportName::Name("GetTickCount".into())));
+        assert!(!exports.contains(&ImportName::Name("AddAtomA".into())));
+        assert!(db.get(OS::WindowsXP, Arch::Win32, "user32.dll").is_some());
+        assert!(db.has_target(OS::Windows7, Arch::X64));
+    }
+
+    #[test]
+    fn test_parse_wrong_db() {
+        assert!(ExportDb::parse("").is_err());
+        assert!(ExportDb::parse("thunk-db 0").is_err());
+        assert!(ExportDb::parse("thunk-db 1\ndll kernel32.dll").is_err());
+        assert!(ExportDb::parse("thunk-db 1\ntarget XP Win32\n1 AddAtomA").is_err());
+
+        let error = ExportDb::parse("thunk-db 1\ntarget 11 x64\ndll kernel32.dll")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("line 2") && error.contains("target 11 x64"));
+        let error = ExportDb::parse("thunk-db 1\ntarget 7 mips\ndll kernel32.dll")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("line 2") && error.contains("mips"));
+    }
+
+    #[derive(Debug, clap::Parser)]
+    struct DbCli {
+        #[command(flatten)]
+        db: DbArgs,
+    }
+
+    #[test]
+    fn test_db_build_args() {
+        use clap::Parser;
+
+        let cli = DbCli::try_parse_from([
+            "thunk", "build", "--os", "win7", "--arch", "amd64", "--from", "dlls",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.db.command,
+            DbCommands::Build {
+                os: OS::Windows7,
+                arch: Arch::X64,
+                ..
+            }
+        ));
+
+        let error = DbCli::try_parse_from([
+            "thunk", "build", "--os", "win11", "--arch", "x64", "--from", "dlls",
+        ])
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("win11") && error.contains(OS::NAMES));
+        assert!(DbCli::try_parse_from([
+            "thunk", "build", "--os", "xp", "--arch", "mips", "--from", "dlls",
+        ])
+        .is_err());
+    }
+}
@@ -1,12 +1,89 @@
 use anyhow::anyhow;
-use clap::Parser;
-use std::{collections::HashMap, path::PathBuf, process::Command};
+use clap::{Parser, Subcommand};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
 
+pub mod apiset;
+pub mod artifact;
+pub mod baseline;
+pub mod blame;
+pub mod cargo;
+pub mod check;
+pub mod config;
+pub mod cpu;
+pub mod crt;
+pub mod db;
+pub mod link;
+pub mod map;
+pub mod mitigation;
+pub mod patch;
+pub mod pe;
+pub mod reproducible;
+pub mod resource;
+pub mod sign;
 mod sys;
+pub mod thunks;
+pub mod tls;
+pub mod verify;
+
 use sys::*;
+pub use sys::{Arch, Subsystem, OS};
 
 const ENV_VAR_VC_LTL5: &str = "VC_LTL";
 const ENV_VAR_YY_THUNKS: &str = "YY_THUNKS";
+const ENV_VAR_SYSROOT: &str = "THUNK_SYSROOT";
+const DEFAULT_CROSS_LINKER: &str = "rust-lld";
+
+/// Use Thunk to build your Rust program that runs on old Windows platforms.
+#[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+    #[command(flatten)]
+    builder: ThunkBuilder,
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+    Apiset(apiset::ApiSetArgs),
+    Baseline(baseline::BaselineArgs),
+    Blame(blame::BlameArgs),
+    Check(check::CheckArgs),
+    Config(config::ConfigArgs),
+    CpuCheck(cpu::CpuCheckArgs),
+    Crt(crt::CrtArgs),
+    Db(db::DbArgs),
+    Patch(patch::PatchArgs),
+    Sign(sign::SignArgs),
+    Thunks(thunks::ThunksArgs),
+    Tls(tls::TlsArgs),
+    Verify(verify::VerifyArgs),
+}
+
+impl Cli {
+    pub fn run(self) -> anyhow::Result<()> {
+        match self.command {
+            Some(Commands::Apiset(args)) => args.run(),
+            Some(Commands::Baseline(args)) => args.run(),
+            Some(Commands::Blame(args)) => args.run(),
+            Some(Commands::Check(args)) => args.run(),
+            Some(Commands::Config(args)) => args.run(),
+            Some(Commands::CpuCheck(args)) => args.run(),
+            Some(Commands::Crt(args)) => args.run(),
+            Some(Commands::Db(args)) => args.run(),
+            Some(Commands::Patch(args)) => args.run(),
+            Some(Commands::Sign(args)) => args.run(),
+            Some(Commands::Thunks(args)) => args.run(),
+            Some(Commands::Tls(args)) => args.run(),
+            Some(Commands::Verify(args)) => args.run(),
+            None => self.builder.build()?.run(),
+        }
+    }
+}
 
 /// Use Thunk to build your Rust program that runs on old Windows platforms.
 #[derive(Debug, Parser)]
@@ -23,43 +100,118 @@ pub struct ThunkBuilder {
     /// Link arg: console, windows (default: console)
     #[arg(short, long)]
     subsystem: Option<Subsystem>,
+    /// MSVC CRT and Windows SDK directory (xwin layout) to cross compile from a non-Windows host
+    #[arg(long, value_name = "DIR")]
+    sysroot: Option<PathBuf>,
+    /// Linker used when cross compiling (default: rust-lld)
+    #[arg(long)]
+    linker: Option<String>,
+    /// Patch the OS and subsystem version of the builds instead of passing them to the linker
+    #[arg(long, overrides_with = "no_patch_pe")]
+    patch_pe: bool,
+    /// Do not patch the builds, even if patch-pe is on in the configuration
+    #[arg(long, overrides_with = "patch_pe")]
+    no_patch_pe: bool,
+    /// Rewrite the load config directory size of the builds for the Windows XP loader
+    #[arg(long, overrides_with = "no_patch_load_config")]
+    patch_load_config: bool,
+    /// Do not rewrite the load config, even if patch-load-config is on in the configuration
+    #[arg(long, overrides_with = "patch_load_config")]
+    no_patch_load_config: bool,
+    /// Turn a mitigation of the OS profile on or off: dynamicbase, highentropyva, nxcompat,
+    /// guard-cf, cetcompat, such as `--mitigation guard-cf=off`
+    #[arg(long, value_name = "MITIGATION=on|off")]
+    mitigation: Vec<mitigation::MitigationOverride>,
+    /// Embed a manifest generated for the OS
+    #[arg(long)]
+    embed_manifest: bool,
+    /// Merge a manifest fragment into the manifest generated for the OS, implies --embed-manifest
+    #[arg(long, value_name = "FILE")]
+    manifest: Vec<PathBuf>,
+    /// Embed the icon, in place of the one in [package.metadata.thunk]
+    #[arg(long, value_name = "FILE")]
+    icon: Option<PathBuf>,
+    /// Embed the version information generated from Cargo.toml
+    #[arg(long)]
+    version_info: bool,
+    /// Build reproducibly: remap the paths, link with /Brepro and normalize the timestamps, then
+    /// build again to check
+    #[arg(long)]
+    reproducible: bool,
+    #[command(flatten)]
+    sign: sign::SignOptions,
+    /// Fail if the builds import APIs that are not in this baseline (see `thunk baseline`)
+    #[arg(long, value_name = "FILE")]
+    baseline: Option<PathBuf>,
+    /// Blame each import of the builds to a crate
+    #[arg(long)]
+    blame: bool,
+    /// Write the blame of the imports as JSON, implies --blame
+    #[arg(long, value_name = "FILE")]
+    blame_json: Option<PathBuf>,
+    /// Write the YY-Thunks shims linked into the builds as JSON
+    #[arg(long, value_name = "FILE")]
+    thunks_json: Option<PathBuf>,
     /// Args pass to cargo: cargo build <CARGO_ARGS>
     #[arg(last = true, value_name = "CARGO_ARGS")]
     cargo_args: Vec<String>,
+    /// The cargo options `cargo thunk build` understands
+    #[arg(skip)]
+    cargo: cargo::CargoArgs,
 }
 
 impl ThunkBuilder {
     pub fn build(mut self) -> anyhow::Result<Thunk> {
-        let env_vars: HashMap<String, String> = std::env::vars().collect();
+        let package = (self.cargo.package.first().cloned())
+            .or_else(|| get_package_from_args(&self.cargo_args));
+        let config = config::Config::load(package.as_deref(), self.cargo.manifest_path.as_deref())?;
 
-        let mut vc_ltl = {
-            let vc_ltl_env_path = env_vars.get(ENV_VAR_VC_LTL5).ok_or_else(|| {
-                anyhow!("You need to set {} environment variable.", ENV_VAR_VC_LTL5)
-            })?;
-            PathBuf::from(vc_ltl_env_path)
-        };
+        // The command line takes precedence over the configuration.
+        self.mitigation = config.get_mitigations(&self.mitigation);
+        self.os = self.os.or(config.os.map(|x| x.value));
+        self.arch = self.arch.or(config.arch.map(|x| x.value));
+        self.subsystem = self.subsystem.or(config.subsystem.map(|x| x.value));
+        self.sysroot = self.sysroot.or(config.sysroot.map(|x| x.value));
+        self.linker = self.linker.or(config.linker.map(|x| x.value));
+        self.patch_pe = get_flag(self.patch_pe, self.no_patch_pe)
+            .or(config.patch_pe.map(|x| x.value))
+            .unwrap_or_default();
+        self.patch_load_config = get_flag(self.patch_load_config, self.no_patch_load_config)
+            .or(config.patch_load_config.map(|x| x.value))
+            .unwrap_or_default();
+
+        let vc_ltl = config.vc_ltl.map(|x| x.value).ok_or_else(|| {
+            anyhow!(
+                "You need to set {} environment variable or vc-ltl in thunk.toml.",
+                ENV_VAR_VC_LTL5
+            )
+        })?;
 
         let os = self.os.unwrap_or(OS::Windows7);
 
-        let arch = if let Ok(arch_from_args) = get_arch_from_args(self.cargo_args.as_slice()) {
+        let sysroot = self.sysroot.clone();
+
+        let is_cross = sysroot.is_some() || !cfg!(windows);
+
+        let arch = if let Some(target) = &self.cargo.target {
+            Arch::from_rust_target(target)?
+        } else if let Ok(arch_from_args) = get_arch_from_args(self.cargo_args.as_slice()) {
             arch_from_args
         } else {
-            let un_arch = self.arch.unwrap_or(get_default_arch()?);
+            let un_arch = match self.arch {
+                Some(arch) => arch,
+                None if is_cross => {
+                    return Err(anyhow!("You need to pass --arch when cross compiling."))
+                }
+                None => get_default_arch()?,
+            };
             let target = un_arch
                 .to_rust_target()
-                .ok_or_else(|| anyhow!("arch {} fail translate to target", un_arch.to_string()))?;
+                .ok_or_else(|| anyhow!("arch {} fail translate to target", un_arch))?;
             self.cargo_args.extend(["--target".to_owned(), target]);
             un_arch
         };
 
-        let os_lib =
-            get_vc_ltl_os_lib_path(os, arch).ok_or_else(|| anyhow!("os or arch is wrong"))?;
-
-        vc_ltl.push(os_lib);
-
-        let os_version =
-            get_os_version(os, arch).ok_or_else(|| anyhow!("failed to get os version"))?;
-
         let is_lib = { get_is_lib_from_args(self.cargo_args.as_slice()) || self.lib };
 
         let mut subsystem = Some(self.subsystem.unwrap_or(Subsystem::Console));
@@ -68,55 +220,104 @@ impl ThunkBuilder {
             subsystem = None;
         }
 
-        let subsystem_args =
-            subsystem.map(|x| format!("-Clink-args=/SUBSYSTEM:{},{}", x.to_string(), os_version));
-
-        let mut rust_flags = vec!["-L".into(), format!("{}", vc_ltl.to_string_lossy())];
+        if is_cross && sysroot.is_none() {
+            return Err(anyhow!(
+                "You need to set {} environment variable or pass --sysroot to cross compile.",
+                ENV_VAR_SYSROOT
+            ));
+        }
 
-        if let Some(args) = subsystem_args {
-            rust_flags.push(args.into());
+        let link_options = link::LinkOptions {
+            os,
+            arch,
+            subsystem,
+            patch_pe: self.patch_pe,
+            vc_ltl: vc_ltl.clone(),
+            yy_thunks: config.yy_thunks.map(|x| x.value).ok_or_else(|| {
+                anyhow!(
+                    "You need to set {} environment variable or yy-thunks in thunk.toml.",
+                    ENV_VAR_YY_THUNKS
+                )
+            })?,
+            sysroot: sysroot.clone().filter(|_| is_cross),
+        };
 
-            if let Some(Subsystem::Windows) = subsystem {
-                rust_flags.push("-Clink-args=/ENTRY:mainCRTStartup".into())
-            }
+        let mut rust_flags = Vec::new();
+        for dir in link_options.get_lib_dirs()? {
+            rust_flags.extend(["-L".into(), format!("{}", dir.to_string_lossy())]);
         }
 
-        let thunks_obj = {
-            let mut thunks = {
-                let yy_thunks_env_path = env_vars.get(ENV_VAR_YY_THUNKS).ok_or_else(|| {
-                    anyhow!(
-                        "You need to set {} environment variable.",
-                        ENV_VAR_YY_THUNKS
-                    )
-                })?;
-                PathBuf::from(yy_thunks_env_path)
-            };
+        if self.reproducible {
+            let paths = reproducible::get_remap_paths(
+                &vc_ltl,
+                Some(&link_options.yy_thunks),
+                sysroot.as_deref(),
+            );
+            rust_flags.extend(reproducible::get_reproducible_args(&paths));
+        }
 
-            let os_obj = get_yy_thunks_obj_path(os, arch).ok_or_else(|| anyhow!(""))?;
-            thunks.push(os_obj);
-            Some(thunks)
+        let linker = match is_cross {
+            true => Some(self.linker.as_deref().unwrap_or(DEFAULT_CROSS_LINKER)),
+            false => None,
         };
 
-        if let Some(obj) = thunks_obj {
-            rust_flags.push(format!("-Clink-args={}", obj.to_string_lossy()));
+        for arg in link_options.get_link_args()? {
+            rust_flags.push(format!("-Clink-args={}", arg));
         }
 
+        let mitigations = mitigation::get_mitigations(os, arch, &self.mitigation);
+        rust_flags.extend(mitigation::get_mitigation_args(&mitigations));
+
+        let resource_options = resource::ResourceOptions {
+            manifest: self.embed_manifest || !self.manifest.is_empty(),
+            manifest_fragments: self.manifest,
+            version_info: self.version_info,
+            icon: self.icon,
+        };
+        // The resources differ by package and crate kind, so thunk-link adds them to each build.
+        let has_resources = resource_options.manifest
+            || resource_options.version_info
+            || resource_options.icon.is_some()
+            || config.package_icons;
+        let mut link_envs = Vec::new();
+        if has_resources {
+            let thunk_link = link::find_thunk_link()?;
+            rust_flags.push(format!("-Clinker={}", thunk_link.to_string_lossy()));
+            link_envs = link::get_resource_envs(os, arch, linker, &resource_options)?;
+        } else if let Some(linker) = linker {
+            rust_flags.push(format!("-Clinker={}", linker));
+        }
         let target_dir = format!("./target/win{}_build", os.to_string().to_ascii_lowercase());
 
+        let blame = self.blame || self.blame_json.is_some();
+
         let mut cargo_args = vec![
             "build".to_owned(),
             "--target-dir".to_owned(),
             target_dir.clone(),
         ];
 
+        cargo_args.extend(self.cargo.to_args());
         cargo_args.extend(self.cargo_args);
 
         let thunk = Thunk {
             rust_flags,
+            link_envs,
             cargo_args,
             os,
             arch,
             target_dir,
+            subsystem,
+            yy_thunks: link_options.yy_thunks,
+            patch_pe: self.patch_pe,
+            patch_load_config: self.patch_load_config,
+            mitigations: self.mitigation,
+            reproducible: self.reproducible,
+            sign: self.sign,
+            baseline: self.baseline,
+            blame,
+            blame_json: self.blame_json,
+            thunks_json: self.thunks_json,
         };
 
         Ok(thunk)
@@ -126,35 +327,273 @@ impl ThunkBuilder {
 #[derive(Debug)]
 pub struct Thunk {
     rust_flags: Vec<String>,
+    /// The environment of thunk-link, which links the resources.
+    link_envs: Vec<(&'static str, std::ffi::OsString)>,
     cargo_args: Vec<String>,
     os: OS,
     arch: Arch,
     target_dir: String,
+    subsystem: Option<Subsystem>,
+    /// The YY-Thunks directory linked, for the post-build steps.
+    yy_thunks: PathBuf,
+    patch_pe: bool,
+    patch_load_config: bool,
+    mitigations: Vec<mitigation::MitigationOverride>,
+    reproducible: bool,
+    sign: sign::SignOptions,
+    baseline: Option<PathBuf>,
+    blame: bool,
+    blame_json: Option<PathBuf>,
+    thunks_json: Option<PathBuf>,
 }
 
 impl Thunk {
-    pub fn run(self) {
-        let rust_flags = self.rust_flags.join(" ");
-        let cargo_args = self.cargo_args;
+    pub fn run(self) -> anyhow::Result<()> {
+        // Separated by 0x1f, as the remapped paths may have spaces.
+        let rust_flags = self.rust_flags.join("\x1f");
+        let mut cargo_args = self.cargo_args.clone();
+
+        // The post-build steps find the builds from the cargo messages.
+        let post_build = !cargo_args.iter().any(|x| x.starts_with("--message-format"));
+        if !post_build {
+            let options = self.get_post_build_options();
+            if !options.is_empty() {
+                return Err(anyhow!(
+                    "{} do not work with --message-format",
+                    options.join(", ")
+                ));
+            }
+            println!("Note: the builds are not verified with --message-format.");
+        }
+        let baseline = match &self.baseline {
+            Some(path) => Some(baseline::Baseline::load(path)?),
+            None => None,
+        };
+        if post_build {
+            cargo_args.push("--message-format=json-render-diagnostics".to_owned());
+        }
+        // Examples are only fixed up when they are asked for.
+        let examples = cargo_args
+            .iter()
+            .any(|x| x.starts_with("--example") || x == "--all-targets");
 
         println!(
             "Start to build for Windows {}({}) using VC-LTL and YY-Thunks: ",
-            self.os.to_string(),
-            self.arch.to_string(),
+            self.os, self.arch,
         );
-        println!(" * RUSTFLAGS = {}", rust_flags);
+        println!(" * RUSTFLAGS = {}", self.rust_flags.join(" "));
         println!(" * Command = cargo {}", cargo_args.join(" "));
+
+        // Build once more from scratch, the builds must not change.
+        let hashes = match self.reproducible {
+            true => {
+                println!("Cargo Output (reproducible check):");
+                let output = self.cargo_build(&rust_flags, &cargo_args, post_build)?;
+                let mut hashes = HashMap::new();
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                for artifact in artifact::read_artifacts(&stdout, examples) {
+                    println!("Post build {}:", artifact.path.to_string_lossy());
+                    self.fix_artifact(&artifact)?;
+                    let hash = reproducible::hash_file(&artifact.path)?;
+                    hashes.insert(artifact.path, hash);
+                }
+                reproducible::clean_target_dir(Path::new(&self.target_dir))?;
+                Some(hashes)
+            }
+            false => None,
+        };
+
         println!("Cargo Output:");
+        let output = self.cargo_build(&rust_flags, &cargo_args, post_build)?;
+
+        if post_build {
+            let artifacts =
+                artifact::read_artifacts(&String::from_utf8_lossy(&output.stdout), examples);
+            let mut added = 0;
+            let mut blames = Vec::new();
+            let mut linked_thunks = Vec::new();
+            let mut not_verified = Vec::new();
+            let mut not_reproducible = Vec::new();
+            for artifact in &artifacts {
+                println!("Post build {}:", artifact.path.to_string_lossy());
+                self.fix_artifact(artifact)?;
+                if let Some(hashes) = &hashes {
+                    let hash = reproducible::hash_file(&artifact.path)?;
+                    println!(" * SHA-256: {}", hash);
+                    if hashes.get(&artifact.path) != Some(&hash) {
+                        println!("Error: the build is not reproducible");
+                        not_reproducible.push(artifact.path.to_string_lossy().to_string());
+                    }
+                }
+                // The signature covers the patched headers.
+                if self.sign.is_enabled() {
+                    sign::fix_signature(&artifact.path, self.os, &self.sign)?;
+                }
+                let problems = verify::verify_file(&artifact.path, Some(&self.yy_thunks), self.os)?;
+                for problem in &problems {
+                    println!("Error: {}", problem);
+                }
+                if !problems.is_empty() {
+                    not_verified.push(artifact.path.to_string_lossy().to_string());
+                }
+
+                if artifact.is_lib {
+                    if let Some(message) = tls::check_tls(&artifact.path, self.os)? {
+                        println!("Warning: {}", message);
+                    }
+                }
+
+                let imports = pe::read_imports(&std::fs::read(&artifact.path)?)?;
+                let api_sets = apiset::read_api_set_imports(&imports);
+                if api_sets.is_empty() {
+                    println!(" * No API set imports");
+                }
+                for import in &api_sets {
+                    let description = apiset::describe_api_set_import(import);
+                    if import.api_set.min_os > self.os {
+                        println!("Warning: {}", description);
+                    } else {
+                        println!(" * {}", description);
+                    }
+                }
+
+                let linked = thunks::get_linked_thunks(
+                    &artifact.path,
+                    None,
+                    Some(&self.yy_thunks),
+                    self.os,
+                )?;
+                thunks::print_linked_thunks(&linked);
+                linked_thunks.push(linked);
+
+                if self.blame {
+                    let report = blame::blame_file(&artifact.path, None, &[])?;
+                    blame::print_blame(&report);
+                    blames.push(report);
+                }
 
-        let _status = Command::new("cargo")
-            .env("RUSTFLAGS", rust_flags)
-            .args(cargo_args)
-            .status()
-            .unwrap();
+                if let Some(baseline) = &baseline {
+                    let yy_thunks = Some(self.yy_thunks.as_path());
+                    added += baseline::verify_file(baseline, &artifact.path, yy_thunks, self.os)?
+                        .added
+                        .len();
+                }
+            }
+
+            if let Some(path) = &self.thunks_json {
+                thunks::save_linked_thunks_json(&linked_thunks, path)?;
+                println!("YY-Thunks report is saved to {}", path.to_string_lossy());
+            }
+            if let Some(path) = &self.blame_json {
+                blame::save_blame_json(&blames, path)?;
+                println!("Blame report is saved to {}", path.to_string_lossy());
+            }
+
+            if !not_reproducible.is_empty() {
+                return Err(anyhow!(
+                    "Two builds of {} are different",
+                    not_reproducible.join(", ")
+                ));
+            }
+            if !not_verified.is_empty() {
+                return Err(anyhow!(
+                    "VC-LTL or YY-Thunks did not take effect in {}",
+                    not_verified.join(", ")
+                ));
+            }
+            if added > 0 {
+                return Err(anyhow!(
+                    "{} new imports are not in the baseline {}",
+                    added,
+                    self.baseline.unwrap().to_string_lossy()
+                ));
+            }
+        }
 
         println!(
             "You can find the builds in target directory: {}",
             self.target_dir
         );
+
+        Ok(())
+    }
+
+    fn cargo_build(
+        &self,
+        rust_flags: &str,
+        cargo_args: &[String],
+        post_build: bool,
+    ) -> anyhow::Result<Output> {
+        let mut command = Command::new("cargo");
+        command
+            .env("CARGO_ENCODED_RUSTFLAGS", rust_flags)
+            .envs(self.link_envs.iter().cloned())
+            .args(cargo_args);
+        // Incremental builds do not give the same code as clean ones.
+        if self.reproducible {
+            command.env("CARGO_INCREMENTAL", "0");
+        }
+        let output = command
+            .stdout(if post_build {
+                Stdio::piped()
+            } else {
+                Stdio::inherit()
+            })
+            .stderr(Stdio::inherit())
+            .output()?;
+
+        if !output.status.success() {
+            return Err(anyhow!("cargo build failed with {}", output.status));
+        }
+        Ok(output)
+    }
+
+    /// Get the options asked for that only take effect in the post-build steps.
+    fn get_post_build_options(&self) -> Vec<&'static str> {
+        let options = [
+            ("--baseline", self.baseline.is_some()),
+            ("--blame", self.blame),
+            ("--reproducible", self.reproducible),
+            ("--patch-load-config", self.patch_load_config),
+            ("--patch-pe", self.patch_pe),
+            ("--sign", self.sign.is_enabled()),
+            ("--thunks-json", self.thunks_json.is_some()),
+        ];
+        options
+            .into_iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(option, _)| option)
+            .collect()
+    }
+
+    /// Patch a build, the steps that change it and so go before hashing and signing.
+    fn fix_artifact(&self, artifact: &artifact::Artifact) -> anyhow::Result<()> {
+        patch::fix_load_config(&artifact.path, self.os, self.patch_load_config)?;
+        if self.patch_pe {
+            mitigation::fix_mitigations(&artifact.path, self.os, &self.mitigations)?;
+        }
+        if self.reproducible {
+            reproducible::fix_timestamps(&artifact.path)?;
+        }
+        // The header goes last, so that the printed checksum is the final one.
+        if self.patch_pe {
+            let subsystem = if artifact.is_lib {
+                None
+            } else {
+                self.subsystem
+            };
+            let (before, after) = patch::patch_file(&artifact.path, self.os, subsystem)?;
+            patch::print_patch(&before, &after);
+        }
+        Ok(())
+    }
+}
+
+/// Get a flag of the command line that has a `--no-` form, `None` if neither is passed.
+fn get_flag(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
     }
 }
@@ -0,0 +1,539 @@
+use anyhow::{anyhow, Result};
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::config::{
+    ENV_VAR_ARCH, ENV_VAR_LINKER, ENV_VAR_MITIGATION, ENV_VAR_OS, ENV_VAR_PATCH_LOAD_CONFIG,
+    ENV_VAR_PATCH_PE, ENV_VAR_SUBSYSTEM,
+};
+use crate::mitigation::{self, MitigationOverride};
+use crate::pe::read_object_arch;
+use crate::resource::{self, Package, ResourceOptions};
+use crate::sys::*;
+use crate::{patch, tls, verify};
+use crate::{ENV_VAR_SYSROOT, ENV_VAR_VC_LTL5, ENV_VAR_YY_THUNKS};
+
+const ENV_VAR_EMBED_MANIFEST: &str = "THUNK_EMBED_MANIFEST";
+const ENV_VAR_VERSION_INFO: &str = "THUNK_VERSION_INFO";
+const ENV_VAR_MANIFEST: &str = "THUNK_MANIFEST";
+const ENV_VAR_ICON: &str = "THUNK_ICON";
+/// Only add the resources, `thunk` passes the rest of the link to rustc.
+const ENV_VAR_RESOURCES_ONLY: &str = "THUNK_RESOURCES_ONLY";
+
+/// What Thunk links into a build for the OS, the same for `thunk` and `thunk-link`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkOptions {
+    pub os: OS,
+    pub arch: Arch,
+    /// `None` for a DLL.
+    pub subsystem: Option<Subsystem>,
+    /// Link without the subsystem version, the build is patched after linking.
+    pub patch_pe: bool,
+    /// The root of VC-LTL.
+    pub vc_ltl: PathBuf,
+    /// The root of YY-Thunks.
+    pub yy_thunks: PathBuf,
+    /// The MSVC CRT and Windows SDK in xwin layout, to cross compile.
+    pub sysroot: Option<PathBuf>,
+}
+
+impl LinkOptions {
+    /// Get the library directories, VC-LTL in front of the sysroot so that its CRT libraries win.
+    pub fn get_lib_dirs(&self) -> Result<Vec<PathBuf>> {
+        let os_lib = get_vc_ltl_os_lib_path(self.os, self.arch)
+            .ok_or_else(|| anyhow!("os or arch is wrong"))?;
+        let vc_ltl = self.vc_ltl.join(os_lib);
+        if !vc_ltl.is_dir() {
+            return Err(anyhow!(
+                "VC-LTL library directory {} does not exist.",
+                vc_ltl.to_string_lossy()
+            ));
+        }
+        let mut dirs = vec![vc_ltl];
+
+        if let Some(sysroot) = &self.sysroot {
+            for lib_path in get_sysroot_lib_paths(self.arch) {
+                let lib_path = sysroot.join(lib_path);
+                if !lib_path.is_dir() {
+                    return Err(anyhow!(
+                        "Windows sysroot is missing {}, expected an xwin style crt and sdk tree.",
+                        lib_path.to_string_lossy()
+                    ));
+                }
+                dirs.push(lib_path);
+            }
+        }
+        Ok(dirs)
+    }
+
+    /// Get the linker args: the YY-Thunks object, the subsystem and the link map, which tells
+    /// the post-build steps which objects and shims are linked.
+    pub fn get_link_args(&self) -> Result<Vec<String>> {
+        let os_obj = get_yy_thunks_obj_path(self.os, self.arch).ok_or_else(|| anyhow!(""))?;
+        let thunks = self.yy_thunks.join(os_obj);
+        if !thunks.is_file() {
+            return Err(anyhow!(
+                "YY-Thunks object {} does not exist.",
+                thunks.to_string_lossy()
+            ));
+        }
+        let mut args = vec![thunks.to_string_lossy().to_string()];
+
+        if let Some(subsystem) = self.subsystem {
+            let os_version = get_os_version(self.os, self.arch)
+                .ok_or_else(|| anyhow!("failed to get os version"))?;
+            // Newer linkers refuse old subsystem versions, the builds are patched after linking.
+            args.push(match self.patch_pe {
+                true => format!("/SUBSYSTEM:{}", subsystem),
+                false => format!("/SUBSYSTEM:{},{}", subsystem, os_version),
+            });
+            if subsystem == Subsystem::Windows {
+                args.push("/ENTRY:mainCRTStartup".into());
+            }
+        }
+
+        args.push("/MAP".into());
+        Ok(args)
+    }
+}
+
+/// Read the args of the linker command line, with the response files rustc writes for long
+/// command lines expanded.
+pub fn expand_args(args: &[OsString]) -> Result<Vec<String>> {
+    let mut expanded = Vec::new();
+    for arg in args {
+        let arg = arg.to_string_lossy();
+        match arg.strip_prefix('@') {
+            Some(path) => {
+                let data = fs::read(path).map_err(|e| anyhow!("Failed to read {}: {}", path, e))?;
+                expanded.extend(parse_response_file(&data));
+            }
+            None => expanded.push(arg.to_string()),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Parse a response file of rustc, UTF-16 with a BOM for MSVC linkers or UTF-8, one quoted arg
+/// per line.
+fn parse_response_file(data: &[u8]) -> Vec<String> {
+    let text = match data {
+        [0xff, 0xfe, rest @ ..] => String::from_utf16_lossy(
+            &rest
+                .chunks_exact(2)
+                .map(|x| u16::from_le_bytes([x[0], x[1]]))
+                .collect::<Vec<_>>(),
+        ),
+        _ => String::from_utf8_lossy(data).to_string(),
+    };
+    text.lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(
+            |line| match line.strip_prefix('"').and_then(|x| x.strip_suffix('"')) {
+                Some(quoted) => quoted.replace("\\\"", "\""),
+                None => line.to_owned(),
+            },
+        )
+        .collect()
+}
+
+/// Get the value of a linker option such as `/OUT:`, in any case and with `-` or `/`.
+fn get_option<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter().rev().find_map(|arg| {
+        let option = arg.strip_prefix(['/', '-'])?;
+        let (key, value) = option.split_once(':')?;
+        key.eq_ignore_ascii_case(name).then_some(value)
+    })
+}
+
+fn has_flag(args: &[String], name: &str) -> bool {
+    args.iter().any(|arg| {
+        arg.strip_prefix(['/', '-'])
+            .is_some_and(|x| x.eq_ignore_ascii_case(name))
+    })
+}
+
+/// Get the arch from `/MACHINE`, `THUNK_ARCH` or the first object.
+fn get_arch(args: &[String]) -> Result<Arch> {
+    let parse = |name: &str, arch: &str| {
+        Arch::parse(arch).ok_or_else(|| {
+            anyhow!(
+                "Bad {}: expected one of {}, got {}",
+                name,
+                Arch::NAMES,
+                arch
+            )
+        })
+    };
+    if let Some(machine) = get_option(args, "MACHINE") {
+        return parse("/MACHINE", machine);
+    }
+    if let Ok(arch) = env::var(ENV_VAR_ARCH) {
+        return parse(ENV_VAR_ARCH, &arch);
+    }
+    args.iter()
+        .filter(|x| x.ends_with(".o") || x.ends_with(".obj"))
+        .find_map(|x| read_object_arch(&fs::read(x).ok()?).ok())
+        .ok_or_else(|| anyhow!("Do not know arch, set {}", ENV_VAR_ARCH))
+}
+
+fn is_env_on(name: &str) -> bool {
+    matches!(
+        env::var(name)
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+            .as_str(),
+        "1" | "on" | "yes" | "true"
+    )
+}
+
+/// Get the OS from `THUNK_OS`, Windows 7 without it.
+fn get_os() -> Result<OS> {
+    match env::var(ENV_VAR_OS) {
+        Ok(os) => OS::parse(&os).ok_or_else(|| {
+            anyhow!(
+                "Bad {}: expected one of {}, got {}",
+                ENV_VAR_OS,
+                OS::NAMES,
+                os
+            )
+        }),
+        Err(_) => Ok(OS::Windows7),
+    }
+}
+
+/// Get the link options of the linker command line and the `THUNK_*` environment variables.
+pub fn get_link_options(args: &[String]) -> Result<LinkOptions> {
+    let os = get_os()?;
+    let subsystem = match has_flag(args, "DLL") {
+        true => None,
+        false => Some(match env::var(ENV_VAR_SUBSYSTEM) {
+            Ok(subsystem) => Subsystem::parse(&subsystem).ok_or_else(|| {
+                anyhow!(
+                    "Bad {}: expected one of {}, got {}",
+                    ENV_VAR_SUBSYSTEM,
+                    Subsystem::NAMES,
+                    subsystem
+                )
+            })?,
+            // #![windows_subsystem] of the crate
+            Err(_) => match get_option(args, "SUBSYSTEM") {
+                Some(x) if x.to_ascii_lowercase().starts_with("windows") => Subsystem::Windows,
+                _ => Subsystem::Console,
+            },
+        }),
+    };
+    let env_path = |name: &str| {
+        env::var_os(name)
+            .map(PathBuf::from)
+            .ok_or_else(|| anyhow!("You need to set {} environment variable.", name))
+    };
+
+    Ok(LinkOptions {
+        os,
+        arch: get_arch(args)?,
+        subsystem,
+        patch_pe: is_env_on(ENV_VAR_PATCH_PE),
+        vc_ltl: env_path(ENV_VAR_VC_LTL5)?,
+        yy_thunks: env_path(ENV_VAR_YY_THUNKS)?,
+        sysroot: env::var_os(ENV_VAR_SYSROOT).map(PathBuf::from),
+    })
+}
+
+/// Get the real linker and its leading args.
+fn get_linker() -> (PathBuf, Vec<String>) {
+    let linker = env::var_os(ENV_VAR_LINKER)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            PathBuf::from(if cfg!(windows) {
+                "link.exe"
+            } else {
+                crate::DEFAULT_CROSS_LINKER
+            })
+        });
+    let is_rust_lld = linker.file_stem().is_some_and(|x| x == "rust-lld");
+    let args = match is_rust_lld {
+        true => vec!["-flavor".to_owned(), "link".to_owned()],
+        false => Vec::new(),
+    };
+    (linker, args)
+}
+
+/// Link a build as `thunk` does: forward the command line of rustc to the real linker with the
+/// args of Thunk, then patch and check the build.
+///
+/// Returns the exit code of the linker.
+pub fn run_linker(args: &[OsString]) -> Result<i32> {
+    let expanded = expand_args(args)?;
+    if is_env_on(ENV_VAR_RESOURCES_ONLY) {
+        return run_resource_linker(args, &expanded);
+    }
+    let options = get_link_options(&expanded)?;
+    let is_lib = options.subsystem.is_none();
+    let overrides = env::var(ENV_VAR_MITIGATION)
+        .unwrap_or_default()
+        .split(',')
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| {
+            x.trim()
+                .parse::<MitigationOverride>()
+                .map_err(|e| anyhow!(e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let output = get_option(&expanded, "OUT").map(PathBuf::from);
+
+    let (linker, mut linker_args) = get_linker();
+    // In front of the library directories of rustc
+    for dir in options.get_lib_dirs()? {
+        linker_args.push(format!("/LIBPATH:{}", dir.to_string_lossy()));
+    }
+    let mut command = Command::new(&linker);
+    command.args(&linker_args).args(args);
+    command.args(options.get_link_args()?);
+    let mitigations = mitigation::get_mitigations(options.os, options.arch, &overrides);
+    command.args(mitigation::get_linker_args(&mitigations));
+
+    if let Some(output) = &output {
+        add_resources(&mut command, output, options.os, options.arch, is_lib)?;
+    }
+
+    let status = command
+        .status()
+        .map_err(|e| anyhow!("Failed to run {}: {}", linker.to_string_lossy(), e))?;
+    if !status.success() {
+        return Ok(status.code().unwrap_or(1));
+    }
+
+    if let Some(output) = &output {
+        check_build(output, &options, &overrides)?;
+    }
+    Ok(0)
+}
+
+/// Find `thunk-link` next to the running `thunk`.
+pub fn find_thunk_link() -> Result<PathBuf> {
+    let path = env::current_exe()?.with_file_name(format!("thunk-link{}", env::consts::EXE_SUFFIX));
+    match path.is_file() {
+        true => Ok(path),
+        false => Err(anyhow!(
+            "{} is not found, it links the resources into each build",
+            path.to_string_lossy()
+        )),
+    }
+}
+
+/// Get the environment variables for `thunk-link` to only add the resources of each build, and
+/// then run `linker`, `link.exe` if `None`.
+pub fn get_resource_envs(
+    os: OS,
+    arch: Arch,
+    linker: Option<&str>,
+    options: &ResourceOptions,
+) -> Result<Vec<(&'static str, OsString)>> {
+    // The linker runs in the directory of the package.
+    let fragments = options
+        .manifest_fragments
+        .iter()
+        .map(std::path::absolute)
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let icon = match &options.icon {
+        Some(icon) => std::path::absolute(icon)?.into_os_string(),
+        None => OsString::new(),
+    };
+    let flag = |on: bool| OsString::from(if on { "1" } else { "0" });
+    Ok(vec![
+        (ENV_VAR_RESOURCES_ONLY, flag(true)),
+        (ENV_VAR_LINKER, linker.unwrap_or("link.exe").into()),
+        (ENV_VAR_OS, os.to_string().into()),
+        (ENV_VAR_ARCH, arch.to_string().into()),
+        (ENV_VAR_EMBED_MANIFEST, flag(options.manifest)),
+        (ENV_VAR_MANIFEST, env::join_paths(fragments)?),
+        (ENV_VAR_VERSION_INFO, flag(options.version_info)),
+        (ENV_VAR_ICON, icon),
+    ])
+}
+
+/// Link a build of `thunk` with the resources of its package and crate kind, rustc has the
+/// rest of the args of Thunk.
+///
+/// Returns the exit code of the linker.
+fn run_resource_linker(args: &[OsString], expanded: &[String]) -> Result<i32> {
+    let (linker, linker_args) = get_linker();
+    let mut command = Command::new(&linker);
+    command.args(&linker_args).args(args);
+    if let Some(output) = get_option(expanded, "OUT") {
+        let is_lib = has_flag(expanded, "DLL");
+        add_resources(
+            &mut command,
+            Path::new(output),
+            get_os()?,
+            get_arch(expanded)?,
+            is_lib,
+        )?;
+    }
+
+    let status = command
+        .status()
+        .map_err(|e| anyhow!("Failed to run {}: {}", linker.to_string_lossy(), e))?;
+    Ok(status.code().unwrap_or(1))
+}
+
+/// Add the resources of the package rustc links for, from the `THUNK_*` environment variables.
+///
+/// The resources of each build go next to it, as builds link in parallel.
+fn add_resources(
+    command: &mut Command,
+    output: &Path,
+    os: OS,
+    arch: Arch,
+    is_lib: bool,
+) -> Result<()> {
+    let resource_options = ResourceOptions {
+        manifest: is_env_on(ENV_VAR_EMBED_MANIFEST),
+        manifest_fragments: env::var_os(ENV_VAR_MANIFEST)
+            .map(|x| {
+                env::split_paths(&x)
+                    .filter(|x| !x.as_os_str().is_empty())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        version_info: is_env_on(ENV_VAR_VERSION_INFO),
+        icon: env::var_os(ENV_VAR_ICON)
+            .filter(|x| !x.is_empty())
+            .map(PathBuf::from),
+    };
+    let package = Package::from_env()?;
+    let dir = output.with_extension("thunk");
+    let res =
+        resource::write_resources(&dir, os, arch, is_lib, package.as_ref(), &resource_options)?;
+    if let Some(res) = res {
+        command.arg(res);
+    }
+    if resource_options.manifest {
+        command.arg("/MANIFEST:NO");
+    }
+    Ok(())
+}
+
+/// Patch and check a build after linking, as the post-build steps of `thunk`.
+fn check_build(file: &Path, options: &LinkOptions, overrides: &[MitigationOverride]) -> Result<()> {
+    println!("Post build {}:", file.to_string_lossy());
+    patch::fix_load_config(file, options.os, is_env_on(ENV_VAR_PATCH_LOAD_CONFIG))?;
+    if options.patch_pe {
+        mitigation::fix_mitigations(file, options.os, overrides)?;
+        let (before, after) = patch::patch_file(file, options.os, options.subsystem)?;
+        patch::print_patch(&before, &after);
+    }
+
+    let problems = verify::verify_file(file, Some(&options.yy_thunks), options.os)?;
+    for problem in &problems {
+        println!("Error: {}", problem);
+    }
+    if !problems.is_empty() {
+        return Err(anyhow!(
+            "VC-LTL or YY-Thunks did not take effect in {}",
+            file.to_string_lossy()
+        ));
+    }
+
+    if options.subsystem.is_none() {
+        if let Some(message) = tls::check_tls(file, options.os)? {
+            println!("Warning: {}", message);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response_file() {
+        let text = "\"/NOLOGO\"\n\"C:\\\\a b\\\\main.o\"\n\"/OUT:C:\\\\target\\\\a.exe\"\n";
+        let mut data = vec![0xff, 0xfe];
+        data.extend(text.encode_utf16().flat_map(|x| x.to_le_bytes()));
+        let args = parse_response_file(&data);
+        assert_eq!(args.len(), 3);
+        assert_eq!(args[1], "C:\\\\a b\\\\main.o");
+        assert_eq!(get_option(&args, "out"), Some("C:\\\\target\\\\a.exe"));
+        assert_eq!(parse_response_file(text.as_bytes()), args);
+    }
+
+    #[test]
+    fn test_get_arch() {
+        let args = ["/NOLOGO".to_owned(), "/MACHINE:X86".to_owned()];
+        assert_eq!(get_arch(&args).unwrap(), Arch::Win32);
+        let args = ["-machine:arm64".to_owned()];
+        assert_eq!(get_arch(&args).unwrap(), Arch::ARM64);
+        let args = ["/MACHINE:MIPS".to_owned()];
+        let error = get_arch(&args).unwrap_err().to_string();
+        assert!(error.contains("/MACHINE") && error.contains("MIPS"));
+    }
+
+    #[test]
+    fn test_get_link_args() {
+        let args = ["/NOLOGO".to_owned(), "-dll".to_owned()];
+        assert!(has_flag(&args, "DLL"));
+        assert!(!has_flag(&args, "DEBUG"));
+
+        // Unique to the test run, as test runs may go in parallel
+        let dir = env::temp_dir().join(format!("thunk-test_get_link_args-{}", std::process::id()));
+        let obj = dir.join("objs/x86/YY_Thunks_for_WinXP.obj");
+        fs::create_dir_all(obj.parent().unwrap()).unwrap();
+        fs::write(&obj, []).unwrap();
+        let options = LinkOptions {
+            os: OS::WindowsXP,
+            arch: Arch::Win32,
+            subsystem: Some(Subsystem::Windows),
+            patch_pe: false,
+            vc_ltl: dir.clone(),
+            yy_thunks: dir.clone(),
+            sysroot: None,
+        };
+        assert_eq!(
+            options.get_link_args().unwrap()[1..],
+            ["/SUBSYSTEM:WINDOWS,5.01", "/ENTRY:mainCRTStartup", "/MAP"]
+        );
+        assert!(options.get_lib_dirs().is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_resource_envs() {
+        let options = ResourceOptions {
+            manifest: true,
+            manifest_fragments: vec![PathBuf::from("admin.manifest")],
+            ..Default::default()
+        };
+        let envs = get_resource_envs(OS::WindowsXP, Arch::Win32, Some("rust-lld"), &options)
+            .unwrap()
+            .into_iter()
+            .collect::<std::collections::HashMap<_, _>>();
+        assert_eq!(envs[ENV_VAR_RESOURCES_ONLY], "1");
+        assert_eq!(envs[ENV_VAR_LINKER], "rust-lld");
+        assert_eq!(
+            OS::parse(envs[ENV_VAR_OS].to_str().unwrap()),
+            Some(OS::WindowsXP)
+        );
+        assert_eq!(
+            Arch::parse(envs[ENV_VAR_ARCH].to_str().unwrap()),
+            Some(Arch::Win32)
+        );
+        assert_eq!(envs[ENV_VAR_EMBED_MANIFEST], "1");
+        assert_eq!(envs[ENV_VAR_VERSION_INFO], "0");
+        assert_eq!(envs[ENV_VAR_ICON], "");
+        // The linker runs in another directory
+        let fragments: Vec<_> = env::split_paths(&envs[ENV_VAR_MANIFEST]).collect();
+        assert_eq!(
+            fragments,
+            [env::current_dir().unwrap().join("admin.manifest")]
+        );
+    }
+}
@@ -1,6 +1,5 @@
 use clap::Parser;
 
-fn main() {
-    let thunk = thunk_cli::ThunkBuilder::parse().build().unwrap();
-    thunk.run();
+fn main() -> anyhow::Result<()> {
+    thunk_cli::Cli::parse().run()
 }
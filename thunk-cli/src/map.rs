@@ -0,0 +1,196 @@
+use anyhow::{anyhow, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// A section of the `Start Length Name Class` table of a link map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapSection {
+    pub section: u16,
+    pub offset: u32,
+    pub length: u32,
+    pub name: String,
+    pub class: String,
+}
+
+/// A public or static symbol of a link map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapSymbol {
+    pub section: u16,
+    pub offset: u32,
+    pub name: String,
+    /// The `Rva+Base` column.
+    pub address: u64,
+    pub is_function: bool,
+    /// Library without extension, `None` for objects passed to the linker directly.
+    pub lib: Option<String>,
+    /// Object or DLL name, or `<absolute>` and `<linker-defined>`.
+    pub object: String,
+}
+
+/// A link map written by `link.exe /MAP` or `lld-link /MAP`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkMap {
+    pub preferred_base: Option<u64>,
+    pub sections: Vec<MapSection>,
+    pub symbols: Vec<MapSymbol>,
+}
+
+impl LinkMap {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read {}: {}", path.to_string_lossy(), e))?;
+        Ok(Self::parse(&text))
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let mut map = Self::default();
+
+        for line in text.lines() {
+            let line = line.trim();
+            if let Some(base) = line.strip_prefix("Preferred load address is ") {
+                map.preferred_base = u64::from_str_radix(base.trim(), 16).ok();
+                continue;
+            }
+
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let (section, offset) = match tokens.first().and_then(|x| parse_address(x)) {
+                Some(address) => address,
+                None => continue,
+            };
+
+            match tokens.as_slice() {
+                // 0001:00000000 00000034H .text CODE
+                [_, length, name, class] if length.ends_with('H') => {
+                    if let Ok(length) = u32::from_str_radix(length.trim_end_matches('H'), 16) {
+                        map.sections.push(MapSection {
+                            section,
+                            offset,
+                            length,
+                            name: name.to_string(),
+                            class: class.to_string(),
+                        });
+                    }
+                }
+                // 0001:00000000 _mainCRTStartup 00401000 f libfoo:foo.o
+                [_, name, address, flags @ .., lib_object] if flags.len() <= 2 => {
+                    let address = match u64::from_str_radix(address, 16) {
+                        Ok(address) => address,
+                        Err(_) => continue,
+                    };
+                    let (lib, object) = split_lib_object(lib_object);
+                    map.symbols.push(MapSymbol {
+                        section,
+                        offset,
+                        name: name.to_string(),
+                        address,
+                        is_function: flags.contains(&"f"),
+                        lib,
+                        object,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        map
+    }
+}
+
+/// Parse a `0001:00000000` section and offset.
+fn parse_address(text: &str) -> Option<(u16, u32)> {
+    let (section, offset) = text.split_once(':')?;
+    if section.len() != 4 || offset.len() != 8 {
+        return None;
+    }
+    Some((
+        u16::from_str_radix(section, 16).ok()?,
+        u32::from_str_radix(offset, 16).ok()?,
+    ))
+}
+
+/// Split the `Lib:Object` column, a drive letter is not a library.
+fn split_lib_object(text: &str) -> (Option<String>, String) {
+    match text.split_once(':') {
+        Some((lib, object)) if lib.len() > 1 => (Some(lib.to_owned()), object.to_owned()),
+        _ => (None, text.to_owned()),
+    }
+}
+
+/// Find the link map of a build, the linker writes it next to the file in `deps`.
+pub fn find_map_file(artifact: &Path) -> Option<PathBuf> {
+    let dir = artifact.parent()?;
+    let stem = artifact.file_stem()?.to_string_lossy().to_string();
+    let names = [stem.clone(), stem.replace('-', "_")];
+
+    let mut candidates: Vec<PathBuf> = vec![artifact.with_extension("map")];
+    if let Ok(entries) = fs::read_dir(dir.join("deps")) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+            let matched = names.iter().any(|name| {
+                file_name == format!("{}.map", name)
+                    || (file_name.starts_with(&format!("{}-", name)) && file_name.ends_with(".map"))
+            });
+            if matched {
+                candidates.push(path);
+            }
+        }
+    }
+
+    // The newest map belongs to the last link.
+    candidates
+        .into_iter()
+        .filter(|path| path.is_file())
+        .max_by_key(|path| path.metadata().and_then(|x| x.modified()).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = " m
+
+ Timestamp is 6ad4778a (Sun Oct 18 07:38:50 2026)
+
+ Preferred load address is 0000000000400000
+
+ Start         Length     Name                   Class
+ 0001:00000000 00000034H .text                   CODE
+
+  Address         Publics by Value              Rva+Base               Lib:Object
+
+ 0001:00000000       _mainCRTStartup            0000000000401000 f   libfoo-1a2b3c:foo-1a2b3c.foo.x-cgu.0.rcgu.o
+ 0002:00000050       __imp__ExitProcess@4       0000000000402050     kernel32:kernel32.dll
+ 0000:00000000       ___safe_se_handler_count   0000000000000000     <absolute>
+ 0001:00000010       _helper                    0000000000401010 f   C:\\build\\helper.obj
+
+ entry point at         0001:00000000
+";
+
+    #[test]
+    fn test_parse_map() {
+        let map = LinkMap::parse(MAP);
+        assert_eq!(map.preferred_base, Some(0x400000));
+        assert_eq!(
+            map.sections,
+            vec![MapSection {
+                section: 1,
+                offset: 0,
+                length: 0x34,
+                name: ".text".to_owned(),
+                class: "CODE".to_owned(),
+            }]
+        );
+        assert_eq!(map.symbols.len(), 4);
+        assert_eq!(map.symbols[0].name, "_mainCRTStartup");
+        assert!(map.symbols[0].is_function);
+        assert_eq!(map.symbols[0].lib.as_deref(), Some("libfoo-1a2b3c"));
+        assert_eq!(map.symbols[1].address, 0x402050);
+        assert_eq!(map.symbols[1].object, "kernel32.dll");
+        assert_eq!(map.symbols[2].lib, None);
+        assert_eq!(map.symbols[3].lib, None);
+        assert_eq!(map.symbols[3].object, "C:\\build\\helper.obj");
+    }
+}
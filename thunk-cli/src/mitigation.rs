@@ -0,0 +1,328 @@
+use anyhow::{anyhow, Result};
+use object::pe;
+use std::{collections::BTreeMap, fmt, fs, path::Path, str::FromStr};
+
+use crate::pe::{
+    patch_dll_characteristics, read_arch, read_dll_characteristics, read_has_relocations,
+};
+use crate::sys::*;
+
+/// An exploit mitigation the linker marks in the image for the loader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Mitigation {
+    /// ASLR, `/DYNAMICBASE`.
+    DynamicBase,
+    /// 64-bit ASLR, `/HIGHENTROPYVA`.
+    HighEntropyVa,
+    /// DEP, `/NXCOMPAT`.
+    NxCompat,
+    /// Control Flow Guard, `/guard:cf`.
+    GuardCf,
+    /// CET shadow stack compatible, `/CETCOMPAT`.
+    CetCompat,
+}
+
+impl Mitigation {
+    pub const ALL: [Mitigation; 5] = [
+        Mitigation::DynamicBase,
+        Mitigation::HighEntropyVa,
+        Mitigation::NxCompat,
+        Mitigation::GuardCf,
+        Mitigation::CetCompat,
+    ];
+
+    /// Whether the linker supports the mitigation for the arch.
+    pub fn is_supported(self, arch: Arch) -> bool {
+        match self {
+            Mitigation::HighEntropyVa => arch != Arch::Win32,
+            Mitigation::CetCompat => arch != Arch::ARM64,
+            _ => true,
+        }
+    }
+
+    /// Get the DllCharacteristics flag, `None` for CET which lives in the debug directory.
+    pub fn dll_characteristic(self) -> Option<u16> {
+        match self {
+            Mitigation::DynamicBase => Some(pe::IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE),
+            Mitigation::HighEntropyVa => Some(pe::IMAGE_DLLCHARACTERISTICS_HIGH_ENTROPY_VA),
+            Mitigation::NxCompat => Some(pe::IMAGE_DLLCHARACTERISTICS_NX_COMPAT),
+            Mitigation::GuardCf => Some(pe::IMAGE_DLLCHARACTERISTICS_GUARD_CF),
+            Mitigation::CetCompat => None,
+        }
+    }
+
+    /// Whether link.exe and rust-lld turn the mitigation on without an arg.
+    pub fn linker_default(self) -> bool {
+        match self {
+            Mitigation::DynamicBase | Mitigation::HighEntropyVa | Mitigation::NxCompat => true,
+            Mitigation::GuardCf | Mitigation::CetCompat => false,
+        }
+    }
+
+    pub fn linker_arg(self, on: bool) -> &'static str {
+        match (self, on) {
+            (Mitigation::DynamicBase, true) => "/DYNAMICBASE",
+            (Mitigation::DynamicBase, false) => "/DYNAMICBASE:NO",
+            (Mitigation::HighEntropyVa, true) => "/HIGHENTROPYVA",
+            (Mitigation::HighEntropyVa, false) => "/HIGHENTROPYVA:NO",
+            (Mitigation::NxCompat, true) => "/NXCOMPAT",
+            (Mitigation::NxCompat, false) => "/NXCOMPAT:NO",
+            (Mitigation::GuardCf, true) => "/guard:cf",
+            (Mitigation::GuardCf, false) => "/guard:no",
+            (Mitigation::CetCompat, true) => "/CETCOMPAT",
+            (Mitigation::CetCompat, false) => "/CETCOMPAT:NO",
+        }
+    }
+}
+
+impl fmt::Display for Mitigation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Mitigation::DynamicBase => "dynamicbase",
+            Mitigation::HighEntropyVa => "highentropyva",
+            Mitigation::NxCompat => "nxcompat",
+            Mitigation::GuardCf => "guard-cf",
+            Mitigation::CetCompat => "cetcompat",
+        };
+        f.write_str(text)
+    }
+}
+
+impl FromStr for Mitigation {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.to_ascii_lowercase().replace(['-', '_', '/'], "").as_str() {
+            "dynamicbase" | "aslr" => Ok(Mitigation::DynamicBase),
+            "highentropyva" => Ok(Mitigation::HighEntropyVa),
+            "nxcompat" | "dep" => Ok(Mitigation::NxCompat),
+            "guardcf" | "cfg" => Ok(Mitigation::GuardCf),
+            "cetcompat" | "cet" => Ok(Mitigation::CetCompat),
+            _ => Err(format!(
+                "Unknown mitigation {}, expected dynamicbase, highentropyva, nxcompat, guard-cf or cetcompat",
+                text
+            )),
+        }
+    }
+}
+
+/// A mitigation turned on or off against the profile of the OS, such as `dynamicbase=off`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MitigationOverride {
+    pub mitigation: Mitigation,
+    pub on: bool,
+}
+
+impl FromStr for MitigationOverride {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (name, value) = text
+            .split_once('=')
+            .ok_or_else(|| format!("Expected <MITIGATION>=on|off, got {}", text))?;
+        let on = match value.to_ascii_lowercase().as_str() {
+            "on" | "yes" | "true" => true,
+            "off" | "no" | "false" => false,
+            _ => return Err(format!("Expected on or off for {}, got {}", name, value)),
+        };
+        Ok(MitigationOverride {
+            mitigation: name.parse()?,
+            on,
+        })
+    }
+}
+
+/// Whether the profile of the OS turns a mitigation on.
+///
+/// Old loaders ignore the flags they do not know, and a build for XP mostly runs on newer Windows,
+/// so ASLR and DEP stay on for every OS. CFG is off until it is turned on, as rustc has to emit
+/// its checks into the code, and CET starts with 20H1.
+fn get_profile(os: OS, mitigation: Mitigation) -> bool {
+    match mitigation {
+        Mitigation::DynamicBase | Mitigation::HighEntropyVa | Mitigation::NxCompat => true,
+        Mitigation::GuardCf => false,
+        Mitigation::CetCompat => os >= OS::Windows10_20H1,
+    }
+}
+
+/// Get the mitigations of the OS profile for the arch, with the overrides applied in order.
+pub fn get_mitigations(
+    os: OS,
+    arch: Arch,
+    overrides: &[MitigationOverride],
+) -> BTreeMap<Mitigation, bool> {
+    let mut mitigations: BTreeMap<Mitigation, bool> = Mitigation::ALL
+        .into_iter()
+        .filter(|x| x.is_supported(arch))
+        .map(|x| (x, get_profile(os, x)))
+        .collect();
+    for x in overrides {
+        if let Some(on) = mitigations.get_mut(&x.mitigation) {
+            *on = x.on;
+        }
+    }
+    // High entropy ASLR is a part of ASLR.
+    if mitigations.get(&Mitigation::DynamicBase) == Some(&false) {
+        mitigations
+            .entry(Mitigation::HighEntropyVa)
+            .and_modify(|x| *x = false);
+    }
+    mitigations
+}
+
+/// Get the linker args of the mitigations that differ from the linker default.
+///
+/// Older linkers, such as the one of the v141_xp toolset, reject args like `/CETCOMPAT:NO`.
+pub fn get_linker_args(mitigations: &BTreeMap<Mitigation, bool>) -> Vec<&'static str> {
+    mitigations
+        .iter()
+        .filter(|(mitigation, &on)| on != mitigation.linker_default())
+        .map(|(mitigation, &on)| mitigation.linker_arg(on))
+        .collect()
+}
+
+/// Get the rustc args to link with the mitigations.
+pub fn get_mitigation_args(mitigations: &BTreeMap<Mitigation, bool>) -> Vec<String> {
+    let mut args: Vec<String> = get_linker_args(mitigations)
+        .into_iter()
+        .map(|arg| format!("-Clink-args={}", arg))
+        .collect();
+    // The guard:cf flag is useless without the checks emitted by rustc, which change the code.
+    if mitigations.get(&Mitigation::GuardCf) == Some(&true) {
+        args.push("-Ccontrol-flow-guard=checks".to_owned());
+    }
+    args
+}
+
+/// Apply the mitigations to DllCharacteristics.
+///
+/// Flags that need data only the linker writes are only cleared: ASLR needs relocations and CFG
+/// needs the guard tables. Returns the new flags and the mitigations that can not be set.
+pub fn apply_mitigations(
+    characteristics: u16,
+    mitigations: &BTreeMap<Mitigation, bool>,
+    has_relocations: bool,
+) -> (u16, Vec<Mitigation>) {
+    let mut result = characteristics;
+    let mut unset = Vec::new();
+    for (&mitigation, &on) in mitigations {
+        let flag = match mitigation.dll_characteristic() {
+            Some(flag) => flag,
+            None => continue,
+        };
+        let can_set = match mitigation {
+            Mitigation::DynamicBase | Mitigation::HighEntropyVa => has_relocations,
+            Mitigation::GuardCf => false,
+            _ => true,
+        };
+        if !on {
+            result &= !flag;
+        } else if result & flag == 0 {
+            if can_set {
+                result |= flag;
+            } else {
+                unset.push(mitigation);
+            }
+        }
+    }
+    (result, unset)
+}
+
+/// Patch DllCharacteristics of a PE file to the mitigations, then verify it by reading it back.
+///
+/// Returns the flags before and after patching, and the mitigations that need relinking.
+pub fn patch_mitigations(
+    file: &Path,
+    mitigations: &BTreeMap<Mitigation, bool>,
+) -> Result<(u16, u16, Vec<Mitigation>)> {
+    let mut data =
+        fs::read(file).map_err(|e| anyhow!("Failed to read {}: {}", file.to_string_lossy(), e))?;
+    let before = read_dll_characteristics(&data)?;
+    let (after, unset) = apply_mitigations(before, mitigations, read_has_relocations(&data)?);
+
+    if after != before {
+        patch_dll_characteristics(&mut data, after)?;
+        fs::write(file, &data)?;
+        if read_dll_characteristics(&fs::read(file)?)? != after {
+            return Err(anyhow!(
+                "Failed to patch DllCharacteristics of {}",
+                file.to_string_lossy()
+            ));
+        }
+    }
+
+    Ok((before, after, unset))
+}
+
+/// Patch the mitigations of a PE file for the OS, printing what is changed.
+pub fn fix_mitigations(file: &Path, os: OS, overrides: &[MitigationOverride]) -> Result<()> {
+    let arch = read_arch(&fs::read(file)?)?;
+    let mitigations = get_mitigations(os, arch, overrides);
+    let (before, after, unset) = patch_mitigations(file, &mitigations)?;
+    println!(" * DllCharacteristics: {:#x} -> {:#x}", before, after);
+    for mitigation in unset {
+        println!(
+            "Warning: {} can not be turned on after linking, link with {}",
+            mitigation,
+            mitigation.linker_arg(true)
+        );
+    }
+    Ok(())
+}
+
+/// Describe the mitigations, such as `dynamicbase=on nxcompat=on`.
+pub fn describe_mitigations(mitigations: &BTreeMap<Mitigation, bool>) -> String {
+    mitigations
+        .iter()
+        .map(|(mitigation, &on)| format!("{}={}", mitigation, if on { "on" } else { "off" }))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_mitigations() {
+        let xp = get_mitigations(OS::WindowsXP, Arch::X64, &[]);
+        assert_eq!(
+            describe_mitigations(&xp),
+            "dynamicbase=on highentropyva=on nxcompat=on guard-cf=off cetcompat=off"
+        );
+        assert!(get_mitigation_args(&xp).is_empty());
+        let win10 = get_mitigations(OS::Windows10_20H1, Arch::X64, &[]);
+        assert_eq!(get_linker_args(&win10), ["/CETCOMPAT"]);
+        let win10 = get_mitigations(OS::Windows10, Arch::X64, &["cfg=on".parse().unwrap()]);
+        assert_eq!(
+            get_mitigation_args(&win10),
+            ["-Clink-args=/guard:cf", "-Ccontrol-flow-guard=checks"]
+        );
+
+        let overrides = ["guard-cf=off".parse().unwrap(), "ASLR=no".parse().unwrap()];
+        let win10 = get_mitigations(OS::Windows10, Arch::X64, &overrides);
+        assert_eq!(
+            describe_mitigations(&win10),
+            "dynamicbase=off highentropyva=off nxcompat=on guard-cf=off cetcompat=off"
+        );
+        assert!("guard-cf".parse::<MitigationOverride>().is_err());
+        assert!("seh=on".parse::<MitigationOverride>().is_err());
+    }
+
+    #[test]
+    fn test_apply_mitigations() {
+        // DYNAMIC_BASE | NX_COMPAT | GUARD_CF | TERMINAL_SERVER_AWARE
+        let characteristics = 0xc140;
+        let xp = get_mitigations(OS::WindowsXP, Arch::Win32, &[]);
+        assert_eq!(
+            apply_mitigations(characteristics, &xp, true),
+            (0x8140, vec![])
+        );
+
+        let win10 = get_mitigations(OS::Windows10, Arch::Win32, &["cfg=on".parse().unwrap()]);
+        assert_eq!(
+            apply_mitigations(0x8000, &win10, false),
+            (0x8100, vec![Mitigation::DynamicBase, Mitigation::GuardCf])
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use anyhow::{anyhow, Result};
+use clap::Args;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::mitigation::{fix_mitigations, MitigationOverride};
+use crate::pe::{
+    compute_checksum, parse_os_version, patch_load_config_size, patch_pe_header, read_arch,
+    read_load_config, read_pe_header, subsystem_to_pe, LoadConfig, PeHeader,
+};
+use crate::reproducible::fix_timestamps;
+use crate::sys::*;
+
+/// Patch the OS and subsystem version of a PE file for the OS, then verify it by reading it back.
+///
+/// Returns the header before and after patching.
+pub fn patch_file(
+    file: &Path,
+    os: OS,
+    subsystem: Option<Subsystem>,
+) -> Result<(PeHeader, PeHeader)> {
+    let mut data =
+        fs::read(file).map_err(|e| anyhow!("Failed to read {}: {}", file.to_string_lossy(), e))?;
+    let arch = read_arch(&data)?;
+    let version = get_os_version(os, arch)
+        .ok_or_else(|| anyhow!("Windows {} does not support {}", os, arch))?;
+    let version = parse_os_version(&version)?;
+    let subsystem = subsystem.map(subsystem_to_pe);
+
+    let before = read_pe_header(&data)?;
+    patch_pe_header(&mut data, version, subsystem)?;
+    fs::write(file, &data)?;
+
+    let data = fs::read(file)?;
+    let after = read_pe_header(&data)?;
+    let expected = PeHeader {
+        os_version: version,
+        subsystem_version: version,
+        subsystem: subsystem.unwrap_or(before.subsystem),
+        checksum: compute_checksum(&data)?,
+    };
+    if after != expected {
+        return Err(anyhow!(
+            "Failed to patch {}, the header reads {:?}",
+            file.to_string_lossy(),
+            after
+        ));
+    }
+
+    Ok((before, after))
+}
+
+/// Print what `patch_file` changed.
+pub fn print_patch(before: &PeHeader, after: &PeHeader) {
+    println!(
+        " * OS version: {}.{} -> {}.{}",
+        before.os_version.0, before.os_version.1, after.os_version.0, after.os_version.1
+    );
+    println!(
+        " * Subsystem version: {}.{} -> {}.{}",
+        before.subsystem_version.0,
+        before.subsystem_version.1,
+        after.subsystem_version.0,
+        after.subsystem_version.1
+    );
+    println!(
+        " * Subsystem: {} -> {}",
+        subsystem_name(before.subsystem),
+        subsystem_name(after.subsystem)
+    );
+    println!(
+        " * Checksum: {:#x} -> {:#x}",
+        before.checksum, after.checksum
+    );
+}
+
+/// Get the load-config directory size the loader of the OS expects, `None` if any size works.
+pub fn get_load_config_size(os: OS, arch: Arch) -> Option<u32> {
+    match (os, arch) {
+        // The x86 loader only reads the SafeSEH table if the size is the 0x40 of the XP SDK.
+        (OS::WindowsXP, Arch::Win32) => Some(0x40),
+        (OS::WindowsXP, Arch::X64) => Some(0x70),
+        _ => None,
+    }
+}
+
+/// Find why the loader of the OS would ignore the load-config directory.
+pub fn diagnose_load_config(config: Option<&LoadConfig>, os: OS, arch: Arch) -> Vec<String> {
+    let mut problems = Vec::new();
+    let (config, expected) = match (config, get_load_config_size(os, arch)) {
+        (Some(config), Some(expected)) => (config, expected),
+        _ => return problems,
+    };
+
+    if config.directory_size != expected {
+        problems.push(format!(
+            "The load config directory size is {:#x}, the Windows {} loader only accepts {:#x}",
+            config.directory_size, os, expected
+        ));
+    }
+    if !config.is_64 && config.se_handler_count.is_none() {
+        problems.push(format!(
+            "The load config structure of {:#x} bytes has no SafeSEH table",
+            config.size
+        ));
+    }
+    problems
+}
+
+/// Rewrite the load-config directory size for the loader of the OS, then verify it by reading it
+/// back.
+///
+/// Returns the directory size before and after patching, `None` if nothing is changed.
+pub fn patch_load_config(file: &Path, os: OS) -> Result<Option<(u32, u32)>> {
+    let mut data =
+        fs::read(file).map_err(|e| anyhow!("Failed to read {}: {}", file.to_string_lossy(), e))?;
+    let arch = read_arch(&data)?;
+    let (config, expected) = match (read_load_config(&data)?, get_load_config_size(os, arch)) {
+        (Some(config), Some(expected)) if config.directory_size != expected => (config, expected),
+        _ => return Ok(None),
+    };
+    if config.size < expected {
+        return Err(anyhow!(
+            "The load config structure of {} is {:#x} bytes, less than {:#x}",
+            file.to_string_lossy(),
+            config.size,
+            expected
+        ));
+    }
+
+    patch_load_config_size(&mut data, expected)?;
+    fs::write(file, &data)?;
+
+    let data = fs::read(file)?;
+    let after = read_load_config(&data)?.map(|x| x.directory_size);
+    if after != Some(expected) || compute_checksum(&data)? != read_pe_header(&data)?.checksum {
+        return Err(anyhow!(
+            "Failed to patch the load config directory of {}",
+            file.to_string_lossy()
+        ));
+    }
+
+    Ok(Some((config.directory_size, expected)))
+}
+
+/// Check the load-config directory of a PE file for the OS, rewriting it if `patch` is set.
+///
+/// Prints the change and the problems left.
+pub fn fix_load_config(file: &Path, os: OS, patch: bool) -> Result<()> {
+    if patch {
+        if let Some((before, after)) = patch_load_config(file, os)? {
+            println!(
+                " * Load config directory size: {:#x} -> {:#x}",
+                before, after
+            );
+        }
+    }
+    let data = fs::read(file)?;
+    let config = read_load_config(&data)?;
+    for problem in diagnose_load_config(config.as_ref(), os, read_arch(&data)?) {
+        println!("Warning: {}", problem);
+    }
+    Ok(())
+}
+
+fn subsystem_name(subsystem: u16) -> String {
+    match subsystem {
+        2 => Subsystem::Windows.to_string(),
+        3 => Subsystem::Console.to_string(),
+        x => x.to_string(),
+    }
+}
+
+/// Patch the OS and subsystem version of a built PE file for the target OS
+#[derive(Debug, Args)]
+pub struct PatchArgs {
+    /// The exe or dll to patch
+    file: PathBuf,
+    /// Operating system: xp, vista, win7, win10, 20h1 (dafault: win7)
+    #[arg(short, long, value_name = "OS")]
+    os: Option<OS>,
+    /// Subsystem: console, windows (default: keep the current one)
+    #[arg(short, long)]
+    subsystem: Option<Subsystem>,
+    /// Also rewrite the load config directory size for the Windows XP loader
+    #[arg(long)]
+    load_config: bool,
+    /// Also patch DllCharacteristics to the mitigation profile of the OS
+    #[arg(long)]
+    mitigations: bool,
+    /// Turn a mitigation of the OS profile on or off, implies --mitigations
+    #[arg(long, value_name = "MITIGATION=on|off")]
+    mitigation: Vec<MitigationOverride>,
+    /// Also normalize the timestamps to SOURCE_DATE_EPOCH or a hash of the content
+    #[arg(long)]
+    timestamps: bool,
+}
+
+impl PatchArgs {
+    pub fn run(self) -> Result<()> {
+        let os = self.os.unwrap_or(OS::Windows7);
+        println!("Patch {} for Windows {}:", self.file.to_string_lossy(), os);
+        // The header goes last, so that the printed checksum is the final one.
+        if self.mitigations || !self.mitigation.is_empty() {
+            fix_mitigations(&self.file, os, &self.mitigation)?;
+        }
+        fix_load_config(&self.file, os, self.load_config)?;
+        if self.timestamps {
+            fix_timestamps(&self.file)?;
+        }
+        let (before, after) = patch_file(&self.file, os, self.subsystem)?;
+        print_patch(&before, &after);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnose_load_config() {
+        let config = LoadConfig {
+            is_64: false,
+            directory_size: 0xc0,
+            size: 0xc0,
+            se_handler_count: Some(2),
+        };
+        assert_eq!(
+            diagnose_load_config(Some(&config), OS::WindowsXP, Arch::Win32).len(),
+            1
+        );
+        assert!(diagnose_load_config(Some(&config), OS::Windows7, Arch::Win32).is_empty());
+        assert!(diagnose_load_config(None, OS::WindowsXP, Arch::Win32).is_empty());
+
+        let config = LoadConfig {
+            directory_size: 0x40,
+            size: 0x40,
+            se_handler_count: None,
+            ..config
+        };
+        assert_eq!(
+            diagnose_load_config(Some(&config), OS::WindowsXP, Arch::Win32),
+            ["The load config structure of 0x40 bytes has no SafeSEH table"]
+        );
+    }
+}
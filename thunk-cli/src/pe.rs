@@ -0,0 +1,572 @@
+use anyhow::{anyhow, Result};
+use object::{
+    pe,
+    read::pe::{ExportTarget, ImageNtHeaders, ImageOptionalHeader, Import as PeImport, PeFile},
+    FileKind, LittleEndian as LE, Object, ObjectSection,
+};
+use std::{collections::HashSet, fmt};
+
+use crate::db::{DllExports, Export};
+use crate::sys::{Arch, Subsystem};
+
+/// How a function is imported from a DLL.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ImportName {
+    Name(String),
+    Ordinal(u16),
+}
+
+impl fmt::Display for ImportName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportName::Name(name) => f.write_str(name),
+            ImportName::Ordinal(ordinal) => write!(f, "#{}", ordinal),
+        }
+    }
+}
+
+/// A function imported by a PE image.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Import {
+    pub dll: String,
+    pub name: ImportName,
+    /// Imported through the delay-load directory.
+    pub delayed: bool,
+}
+
+impl fmt::Display for Import {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}!{}", self.dll, self.name)
+    }
+}
+
+/// Get the arch of a PE image from its machine field.
+pub fn read_arch(data: &[u8]) -> Result<Arch> {
+    match FileKind::parse(data)? {
+        FileKind::Pe32 => arch_from_machine::<pe::ImageNtHeaders32>(data),
+        FileKind::Pe64 => arch_from_machine::<pe::ImageNtHeaders64>(data),
+        _ => Err(anyhow!("Not a PE image")),
+    }
+}
+
+fn arch_from_machine<Pe: ImageNtHeaders>(data: &[u8]) -> Result<Arch> {
+    let file = PeFile::<Pe>::parse(data)?;
+    to_arch(file.nt_headers().file_header().machine.get(LE))
+}
+
+fn to_arch(machine: u16) -> Result<Arch> {
+    match machine {
+        pe::IMAGE_FILE_MACHINE_I386 => Ok(Arch::Win32),
+        pe::IMAGE_FILE_MACHINE_AMD64 => Ok(Arch::X64),
+        pe::IMAGE_FILE_MACHINE_ARM64 => Ok(Arch::ARM64),
+        machine => Err(anyhow!("Machine {:#x} is not support!", machine)),
+    }
+}
+
+/// Get the arch of a COFF object from its machine field.
+pub fn read_object_arch(data: &[u8]) -> Result<Arch> {
+    match FileKind::parse(data)? {
+        FileKind::Coff => to_arch(read_u16(data, 0)),
+        _ => Err(anyhow!("Not a COFF object")),
+    }
+}
+
+/// Whether a PE image is a DLL.
+pub fn read_is_dll(data: &[u8]) -> Result<bool> {
+    match FileKind::parse(data)? {
+        FileKind::Pe32 => is_dll_from::<pe::ImageNtHeaders32>(data),
+        FileKind::Pe64 => is_dll_from::<pe::ImageNtHeaders64>(data),
+        _ => Err(anyhow!("Not a PE image")),
+    }
+}
+
+fn is_dll_from<Pe: ImageNtHeaders>(data: &[u8]) -> Result<bool> {
+    let file = PeFile::<Pe>::parse(data)?;
+    let characteristics = file.nt_headers().file_header().characteristics.get(LE);
+    Ok(characteristics & pe::IMAGE_FILE_DLL != 0)
+}
+
+/// Read the section names of a PE image.
+pub fn read_section_names(data: &[u8]) -> Result<Vec<String>> {
+    let file = object::File::parse(data)?;
+    Ok(file
+        .sections()
+        .filter_map(|section| section.name().ok().map(|x| x.to_owned()))
+        .collect())
+}
+
+/// Read the import and delay-load import tables of a PE image.
+pub fn read_imports(data: &[u8]) -> Result<Vec<Import>> {
+    match FileKind::parse(data)? {
+        FileKind::Pe32 => read_imports_from::<pe::ImageNtHeaders32>(data),
+        FileKind::Pe64 => read_imports_from::<pe::ImageNtHeaders64>(data),
+        _ => Err(anyhow!("Not a PE image")),
+    }
+}
+
+fn read_imports_from<Pe: ImageNtHeaders>(data: &[u8]) -> Result<Vec<Import>> {
+    let file = PeFile::<Pe>::parse(data)?;
+    let mut imports = Vec::new();
+
+    if let Some(table) = file.import_table()? {
+        let mut descriptors = table.descriptors()?;
+        while let Some(descriptor) = descriptors.next()? {
+            let dll = String::from_utf8_lossy(table.name(descriptor.name.get(LE))?).into_owned();
+            // Bound images may only have the IAT left.
+            let address = match descriptor.original_first_thunk.get(LE) {
+                0 => descriptor.first_thunk.get(LE),
+                address => address,
+            };
+            let mut thunks = table.thunks(address)?;
+            while let Some(thunk) = thunks.next::<Pe>()? {
+                let name = to_import_name(table.import::<Pe>(thunk)?);
+                imports.push(Import {
+                    dll: dll.clone(),
+                    name,
+                    delayed: false,
+                });
+            }
+        }
+    }
+
+    let sections = file.section_table();
+    if let Some(table) = file
+        .data_directories()
+        .delay_load_import_table(data, &sections)?
+    {
+        let mut descriptors = table.descriptors()?;
+        while let Some(descriptor) = descriptors.next()? {
+            let dll =
+                String::from_utf8_lossy(table.name(descriptor.dll_name_rva.get(LE))?).into_owned();
+            let mut thunks = table.thunks(descriptor.import_name_table_rva.get(LE))?;
+            while let Some(thunk) = thunks.next::<Pe>()? {
+                let name = to_import_name(table.import::<Pe>(thunk)?);
+                imports.push(Import {
+                    dll: dll.clone(),
+                    name,
+                    delayed: true,
+                });
+            }
+        }
+    }
+
+    Ok(imports)
+}
+
+fn to_import_name(import: PeImport) -> ImportName {
+    match import {
+        PeImport::Ordinal(ordinal) => ImportName::Ordinal(ordinal),
+        PeImport::Name(_hint, name) => ImportName::Name(String::from_utf8_lossy(name).into_owned()),
+    }
+}
+
+/// Read the export directory of a PE image, including forwarded and ordinal-only exports.
+pub fn read_exports(data: &[u8]) -> Result<DllExports> {
+    match FileKind::parse(data)? {
+        FileKind::Pe32 => read_exports_from::<pe::ImageNtHeaders32>(data),
+        FileKind::Pe64 => read_exports_from::<pe::ImageNtHeaders64>(data),
+        _ => Err(anyhow!("Not a PE image")),
+    }
+}
+
+fn read_exports_from<Pe: ImageNtHeaders>(data: &[u8]) -> Result<DllExports> {
+    let file = PeFile::<Pe>::parse(data)?;
+    let mut exports = DllExports::default();
+
+    let table = match file.export_table()? {
+        Some(table) => table,
+        None => return Ok(exports),
+    };
+
+    // A function may have several names, so walk the name table instead of the addresses.
+    let mut named = HashSet::new();
+    for (name_pointer, index) in table.name_iter() {
+        let name = table.name_from_pointer(name_pointer)?;
+        exports.exports.insert(Export {
+            ordinal: table.ordinal_base() + u32::from(index),
+            name: Some(String::from_utf8_lossy(name).into_owned()),
+            forward: to_forward(table.target_by_index(u32::from(index))?),
+        });
+        named.insert(u32::from(index));
+    }
+
+    for (index, address) in (0u32..).zip(table.addresses()) {
+        if named.contains(&index) || address.get(LE) == 0 {
+            continue;
+        }
+        exports.exports.insert(Export {
+            ordinal: table.ordinal_base() + index,
+            name: None,
+            forward: to_forward(table.target_by_index(index)?),
+        });
+    }
+
+    Ok(exports)
+}
+
+fn to_forward(target: ExportTarget) -> Option<String> {
+    match target {
+        ExportTarget::Address(_) => None,
+        ExportTarget::ForwardByName(dll, name) => Some(format!(
+            "{}.{}",
+            String::from_utf8_lossy(dll),
+            String::from_utf8_lossy(name)
+        )),
+        ExportTarget::ForwardByOrdinal(dll, ordinal) => {
+            Some(format!("{}.#{}", String::from_utf8_lossy(dll), ordinal))
+        }
+    }
+}
+
+/// The implicit TLS of a PE image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// Size of the TLS template, the initialized data copied for every thread.
+    pub raw_data_size: u64,
+    pub zero_fill_size: u32,
+    pub callbacks: usize,
+    /// Name and virtual size of the section holding the template, usually `.tls`.
+    pub section: Option<(String, u32)>,
+}
+
+/// Read the TLS directory of a PE image.
+pub fn read_tls(data: &[u8]) -> Result<Option<TlsInfo>> {
+    match FileKind::parse(data)? {
+        FileKind::Pe32 => read_tls_from::<pe::ImageNtHeaders32>(data),
+        FileKind::Pe64 => read_tls_from::<pe::ImageNtHeaders64>(data),
+        _ => Err(anyhow!("Not a PE image")),
+    }
+}
+
+fn read_tls_from<Pe: ImageNtHeaders>(data: &[u8]) -> Result<Option<TlsInfo>> {
+    let file = PeFile::<Pe>::parse(data)?;
+    let sections = file.section_table();
+    let directory = match file.data_directory(pe::IMAGE_DIRECTORY_ENTRY_TLS) {
+        Some(directory) => directory.data(data, &sections)?,
+        None => return Ok(None),
+    };
+
+    // The directory holds virtual addresses, pointer sized on PE32+.
+    let is_64 = file.nt_headers().is_type_64();
+    let pointer_size = if is_64 { 8 } else { 4 };
+    let read_pointer = |bytes: &[u8], index: usize| -> Option<u64> {
+        let bytes = bytes.get(index * pointer_size..(index + 1) * pointer_size)?;
+        Some(match is_64 {
+            true => u64::from_le_bytes(bytes.try_into().unwrap()),
+            false => u64::from(u32::from_le_bytes(bytes.try_into().unwrap())),
+        })
+    };
+    let bad_directory = || anyhow!("Bad TLS directory");
+
+    let start = read_pointer(directory, 0).ok_or_else(bad_directory)?;
+    let end = read_pointer(directory, 1).ok_or_else(bad_directory)?;
+    let callbacks_address = read_pointer(directory, 3).ok_or_else(bad_directory)?;
+    let zero_fill_size = directory
+        .get(pointer_size * 4..pointer_size * 4 + 4)
+        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+        .ok_or_else(bad_directory)?;
+
+    let image_base = file.nt_headers().optional_header().image_base();
+    let to_rva = |address: u64| address.checked_sub(image_base).map(|x| x as u32);
+
+    let mut callbacks = 0;
+    if let Some(table) = to_rva(callbacks_address).and_then(|x| sections.pe_data_at(data, x)) {
+        while read_pointer(table, callbacks).is_some_and(|x| x != 0) {
+            callbacks += 1;
+        }
+    }
+
+    let section = to_rva(start)
+        .and_then(|x| sections.section_containing(x))
+        .map(|section| {
+            let name = String::from_utf8_lossy(section.raw_name())
+                .trim_end_matches('\0')
+                .to_owned();
+            (name, section.virtual_size.get(LE))
+        });
+
+    Ok(Some(TlsInfo {
+        raw_data_size: end.saturating_sub(start),
+        zero_fill_size,
+        callbacks,
+        section,
+    }))
+}
+
+/// Version and subsystem fields of the optional header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeHeader {
+    pub os_version: (u16, u16),
+    pub subsystem_version: (u16, u16),
+    pub subsystem: u16,
+    pub checksum: u32,
+}
+
+// Offsets in the optional header, the same for PE32 and PE32+.
+const OPTIONAL_OS_VERSION: usize = 40;
+const OPTIONAL_SUBSYSTEM_VERSION: usize = 48;
+const OPTIONAL_CHECKSUM: usize = 64;
+const OPTIONAL_SUBSYSTEM: usize = 68;
+const OPTIONAL_DLL_CHARACTERISTICS: usize = 70;
+// Offsets of the data directories in the optional header.
+const PE32_DATA_DIRECTORIES: usize = 96;
+const PE64_DATA_DIRECTORIES: usize = 112;
+
+/// Get the offset of the optional header of a PE32 or PE32+ image.
+fn optional_header_offset(data: &[u8]) -> Result<usize> {
+    match FileKind::parse(data)? {
+        FileKind::Pe32 | FileKind::Pe64 => {
+            let dos_header = pe::ImageDosHeader::parse(data)?;
+            // Signature and file header
+            Ok(dos_header.nt_headers_offset() as usize + 4 + 20)
+        }
+        _ => Err(anyhow!("Not a PE image")),
+    }
+}
+
+/// Get the offset of a data directory entry of a PE32 or PE32+ image.
+fn data_directory_offset(data: &[u8], index: usize) -> Result<usize> {
+    let directories = match FileKind::parse(data)? {
+        FileKind::Pe32 => PE32_DATA_DIRECTORIES,
+        _ => PE64_DATA_DIRECTORIES,
+    };
+    Ok(optional_header_offset(data)? + directories + index * 8)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Read the version and subsystem fields of a PE image.
+pub fn read_pe_header(data: &[u8]) -> Result<PeHeader> {
+    let offset = optional_header_offset(data)?;
+    Ok(PeHeader {
+        os_version: (
+            read_u16(data, offset + OPTIONAL_OS_VERSION),
+            read_u16(data, offset + OPTIONAL_OS_VERSION + 2),
+        ),
+        subsystem_version: (
+            read_u16(data, offset + OPTIONAL_SUBSYSTEM_VERSION),
+            read_u16(data, offset + OPTIONAL_SUBSYSTEM_VERSION + 2),
+        ),
+        subsystem: read_u16(data, offset + OPTIONAL_SUBSYSTEM),
+        checksum: read_u32(data, offset + OPTIONAL_CHECKSUM),
+    })
+}
+
+/// Rewrite the OS and subsystem version, optionally the subsystem, then update the checksum.
+pub fn patch_pe_header(data: &mut [u8], version: (u16, u16), subsystem: Option<u16>) -> Result<()> {
+    let offset = optional_header_offset(data)?;
+    for field in [OPTIONAL_OS_VERSION, OPTIONAL_SUBSYSTEM_VERSION] {
+        data[offset + field..offset + field + 2].copy_from_slice(&version.0.to_le_bytes());
+        data[offset + field + 2..offset + field + 4].copy_from_slice(&version.1.to_le_bytes());
+    }
+    if let Some(subsystem) = subsystem {
+        data[offset + OPTIONAL_SUBSYSTEM..offset + OPTIONAL_SUBSYSTEM + 2]
+            .copy_from_slice(&subsystem.to_le_bytes());
+    }
+    update_checksum(data)
+}
+
+/// Recompute the checksum field of a PE image.
+pub fn update_checksum(data: &mut [u8]) -> Result<()> {
+    let offset = optional_header_offset(data)? + OPTIONAL_CHECKSUM;
+    let checksum = pe_checksum(data, offset);
+    data[offset..offset + 4].copy_from_slice(&checksum.to_le_bytes());
+    Ok(())
+}
+
+/// Compute the checksum a PE image should have.
+pub fn compute_checksum(data: &[u8]) -> Result<u32> {
+    let offset = optional_header_offset(data)? + OPTIONAL_CHECKSUM;
+    Ok(pe_checksum(data, offset))
+}
+
+/// The checksum algorithm of `CheckSumMappedFile`, skipping the checksum field itself.
+fn pe_checksum(data: &[u8], checksum_offset: usize) -> u32 {
+    let mut sum: u64 = 0;
+    for (index, word) in data.chunks(2).enumerate() {
+        let position = index * 2;
+        if position == checksum_offset || position == checksum_offset + 2 {
+            continue;
+        }
+        let word = match word {
+            [low, high] => u16::from_le_bytes([*low, *high]),
+            [low] => u16::from(*low),
+            _ => unreachable!(),
+        };
+        sum += u64::from(word);
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum as u32 + data.len() as u32
+}
+
+/// The load-config directory of a PE image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadConfig {
+    pub is_64: bool,
+    /// Size in the data directory, which old loaders compare with the size they know.
+    pub directory_size: u32,
+    /// The `Size` field of the structure itself.
+    pub size: u32,
+    /// `SEHandlerCount` of a PE32 image whose structure has the SafeSEH fields.
+    pub se_handler_count: Option<u32>,
+}
+
+/// Read the load-config directory of a PE image, `None` if it has none.
+pub fn read_load_config(data: &[u8]) -> Result<Option<LoadConfig>> {
+    match FileKind::parse(data)? {
+        FileKind::Pe32 => read_load_config_from::<pe::ImageNtHeaders32>(data),
+        FileKind::Pe64 => read_load_config_from::<pe::ImageNtHeaders64>(data),
+        _ => Err(anyhow!("Not a PE image")),
+    }
+}
+
+fn read_load_config_from<Pe: ImageNtHeaders>(data: &[u8]) -> Result<Option<LoadConfig>> {
+    let file = PeFile::<Pe>::parse(data)?;
+    let directory = match file.data_directory(pe::IMAGE_DIRECTORY_ENTRY_LOAD_CONFIG) {
+        Some(directory) => directory,
+        None => return Ok(None),
+    };
+    let (address, directory_size) = directory.address_range();
+    // The directory size may be smaller than the structure, so read from the section.
+    let structure = file
+        .section_table()
+        .pe_data_at(data, address)
+        .filter(|x| x.len() >= 4)
+        .ok_or_else(|| anyhow!("Bad load config directory at {:#x}", address))?;
+    let size = read_u32(structure, 0);
+    let is_64 = file.is_64();
+    // SEHandlerTable at 0x40 and SEHandlerCount at 0x44 of IMAGE_LOAD_CONFIG_DIRECTORY32
+    let se_handler_count =
+        (!is_64 && size >= 0x48 && structure.len() >= 0x48).then(|| read_u32(structure, 0x44));
+
+    Ok(Some(LoadConfig {
+        is_64,
+        directory_size,
+        size,
+        se_handler_count,
+    }))
+}
+
+/// Rewrite the size of the load-config data directory, then update the checksum.
+pub fn patch_load_config_size(data: &mut [u8], size: u32) -> Result<()> {
+    let offset = data_directory_offset(data, pe::IMAGE_DIRECTORY_ENTRY_LOAD_CONFIG)? + 4;
+    data[offset..offset + 4].copy_from_slice(&size.to_le_bytes());
+    update_checksum(data)
+}
+
+/// Get the file offsets of the TimeDateStamp fields of a PE image: the file header, the export
+/// and resource directories, and the debug directory entries.
+pub fn read_timestamp_offsets(data: &[u8]) -> Result<Vec<usize>> {
+    match FileKind::parse(data)? {
+        FileKind::Pe32 => read_timestamp_offsets_from::<pe::ImageNtHeaders32>(data),
+        FileKind::Pe64 => read_timestamp_offsets_from::<pe::ImageNtHeaders64>(data),
+        _ => Err(anyhow!("Not a PE image")),
+    }
+}
+
+fn read_timestamp_offsets_from<Pe: ImageNtHeaders>(data: &[u8]) -> Result<Vec<usize>> {
+    let file = PeFile::<Pe>::parse(data)?;
+    let sections = file.section_table();
+    // Signature, Machine and NumberOfSections
+    let mut offsets = vec![pe::ImageDosHeader::parse(data)?.nt_headers_offset() as usize + 8];
+
+    for index in [
+        pe::IMAGE_DIRECTORY_ENTRY_EXPORT,
+        pe::IMAGE_DIRECTORY_ENTRY_RESOURCE,
+    ] {
+        let range = file
+            .data_directory(index)
+            .and_then(|x| sections.pe_file_range_at(x.virtual_address.get(LE)));
+        if let Some((offset, _)) = range.filter(|(_, size)| *size >= 8) {
+            offsets.push(offset as usize + 4);
+        }
+    }
+
+    if let Some(directory) = file.data_directory(pe::IMAGE_DIRECTORY_ENTRY_DEBUG) {
+        let (address, size) = directory.address_range();
+        if let Some((offset, _)) = sections.pe_file_range_at(address) {
+            // IMAGE_DEBUG_DIRECTORY is 28 bytes
+            for index in 0..size as usize / 28 {
+                offsets.push(offset as usize + index * 28 + 4);
+            }
+        }
+    }
+
+    offsets.retain(|x| x + 4 <= data.len());
+    Ok(offsets)
+}
+
+/// Rewrite the TimeDateStamp fields, then update the checksum.
+pub fn patch_timestamps(data: &mut [u8], timestamp: u32) -> Result<()> {
+    for offset in read_timestamp_offsets(data)? {
+        data[offset..offset + 4].copy_from_slice(&timestamp.to_le_bytes());
+    }
+    update_checksum(data)
+}
+
+/// Read the TimeDateStamp field of the file header.
+pub fn read_timestamp(data: &[u8]) -> Result<u32> {
+    let offset = optional_header_offset(data)? - 20 + 4;
+    Ok(read_u32(data, offset))
+}
+
+/// Read the DllCharacteristics field of a PE image.
+pub fn read_dll_characteristics(data: &[u8]) -> Result<u16> {
+    let offset = optional_header_offset(data)?;
+    Ok(read_u16(data, offset + OPTIONAL_DLL_CHARACTERISTICS))
+}
+
+/// Rewrite the DllCharacteristics field, then update the checksum.
+pub fn patch_dll_characteristics(data: &mut [u8], characteristics: u16) -> Result<()> {
+    let offset = optional_header_offset(data)? + OPTIONAL_DLL_CHARACTERISTICS;
+    data[offset..offset + 2].copy_from_slice(&characteristics.to_le_bytes());
+    update_checksum(data)
+}
+
+/// Whether a PE image has base relocations, which ASLR needs.
+pub fn read_has_relocations(data: &[u8]) -> Result<bool> {
+    let offset = data_directory_offset(data, pe::IMAGE_DIRECTORY_ENTRY_BASERELOC)?;
+    Ok(read_u32(data, offset) != 0 && read_u32(data, offset + 4) != 0)
+}
+
+/// Parse a linker version such as `5.01` or `10.0`.
+pub fn parse_os_version(version: &str) -> Result<(u16, u16)> {
+    version
+        .split_once('.')
+        .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)))
+        .ok_or_else(|| anyhow!("Bad os version {}", version))
+}
+
+/// Get the optional header value of a subsystem.
+pub fn subsystem_to_pe(subsystem: Subsystem) -> u16 {
+    match subsystem {
+        Subsystem::Windows => pe::IMAGE_SUBSYSTEM_WINDOWS_GUI,
+        Subsystem::Console => pe::IMAGE_SUBSYSTEM_WINDOWS_CUI,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_os_version() {
+        assert_eq!(parse_os_version("5.01").unwrap(), (5, 1));
+        assert_eq!(parse_os_version("10.0").unwrap(), (10, 0));
+        assert!(parse_os_version("10").is_err());
+    }
+
+    #[test]
+    fn test_pe_checksum() {
+        // Words 0x0201 + 0x0403, the checksum field at 4 is skipped, plus the length.
+        let data = [1, 2, 3, 4, 0xff, 0xff, 0xff, 0xff, 5];
+        assert_eq!(pe_checksum(&data, 4), 0x0201 + 0x0403 + 5 + 9);
+    }
+}
@@ -0,0 +1,139 @@
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    env, fs,
+    path::{self, Path, PathBuf},
+};
+
+use crate::pe::{patch_timestamps, read_timestamp};
+
+/// Get the directories to remap, from the least specific, as rustc takes the last match.
+pub fn get_remap_paths(
+    vc_ltl: &Path,
+    yy_thunks: Option<&Path>,
+    sysroot: Option<&Path>,
+) -> Vec<(PathBuf, &'static str)> {
+    let cargo_home = env::var_os("CARGO_HOME").map(PathBuf::from).or_else(|| {
+        env::var_os(if cfg!(windows) { "USERPROFILE" } else { "HOME" })
+            .map(|x| PathBuf::from(x).join(".cargo"))
+    });
+
+    let mut paths = Vec::new();
+    if let Ok(dir) = env::current_dir() {
+        paths.push((dir, "/build"));
+    }
+    if let Some(dir) = cargo_home {
+        paths.push((dir, "/cargo"));
+    }
+    if let Some(dir) = sysroot {
+        paths.push((dir.to_path_buf(), "/sysroot"));
+    }
+    paths.push((vc_ltl.to_path_buf(), "/VC-LTL"));
+    if let Some(dir) = yy_thunks {
+        paths.push((dir.to_path_buf(), "/YY-Thunks"));
+    }
+
+    // Relative paths of the environment are absolute ones for rustc.
+    paths
+        .into_iter()
+        .map(|(dir, to)| (path::absolute(&dir).unwrap_or(dir), to))
+        .collect()
+}
+
+/// Get the rustc args to build reproducibly: the paths remapped and the timestamps and PDB GUID
+/// of the linker derived from the content.
+pub fn get_reproducible_args(paths: &[(PathBuf, &str)]) -> Vec<String> {
+    let mut args: Vec<String> = paths
+        .iter()
+        .map(|(from, to)| format!("--remap-path-prefix={}={}", from.to_string_lossy(), to))
+        .collect();
+    args.push("-Clink-args=/Brepro".to_owned());
+    // Only the file name of the PDB, not its absolute path
+    args.push("-Clink-args=/PDBALTPATH:%_PDB%".to_owned());
+    args
+}
+
+/// Get the timestamp of a PE image: `SOURCE_DATE_EPOCH`, or a hash of the image with the
+/// timestamps left out.
+pub fn get_timestamp(data: &[u8]) -> Result<u32> {
+    if let Ok(epoch) = env::var("SOURCE_DATE_EPOCH") {
+        return epoch
+            .trim()
+            .parse()
+            .map_err(|e| anyhow!("Bad SOURCE_DATE_EPOCH {}: {}", epoch, e));
+    }
+    let mut data = data.to_vec();
+    patch_timestamps(&mut data, 0)?;
+    let hash = Sha256::digest(&data);
+    Ok(u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]]) & 0x7fffffff)
+}
+
+/// Normalize the timestamps of a PE file, then the checksum.
+///
+/// Returns the timestamp of the file header before and after.
+pub fn normalize_file(file: &Path) -> Result<(u32, u32)> {
+    let data =
+        fs::read(file).map_err(|e| anyhow!("Failed to read {}: {}", file.to_string_lossy(), e))?;
+    let before = read_timestamp(&data)?;
+    let after = get_timestamp(&data)?;
+    let mut patched = data.clone();
+    patch_timestamps(&mut patched, after)?;
+    if patched != data {
+        fs::write(file, &patched)?;
+    }
+    Ok((before, after))
+}
+
+/// Normalize a PE file, printing the timestamp.
+pub fn fix_timestamps(file: &Path) -> Result<()> {
+    let (before, after) = normalize_file(file)?;
+    println!(" * TimeDateStamp: {:#x} -> {:#x}", before, after);
+    Ok(())
+}
+
+/// Remove the builds of a target directory, but not the resources Thunk writes into `thunk`.
+pub fn clean_target_dir(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name() == "thunk" {
+            continue;
+        }
+        match entry.file_type()?.is_dir() {
+            true => fs::remove_dir_all(entry.path())?,
+            false => fs::remove_file(entry.path())?,
+        }
+    }
+    Ok(())
+}
+
+/// Get the SHA-256 of a file in hex.
+pub fn hash_file(file: &Path) -> Result<String> {
+    let data =
+        fs::read(file).map_err(|e| anyhow!("Failed to read {}: {}", file.to_string_lossy(), e))?;
+    Ok(Sha256::digest(data)
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_reproducible_args() {
+        let paths = [
+            (PathBuf::from("/home/me/project"), "/build"),
+            (PathBuf::from("/opt/VC-LTL"), "/VC-LTL"),
+        ];
+        assert_eq!(
+            get_reproducible_args(&paths),
+            [
+                "--remap-path-prefix=/home/me/project=/build",
+                "--remap-path-prefix=/opt/VC-LTL=/VC-LTL",
+                "-Clink-args=/Brepro",
+                "-Clink-args=/PDBALTPATH:%_PDB%",
+            ]
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use thunk::{
+    manifest::Manifest,
+    res::ResourceFile,
+    version::{PackageInfo, VersionInfo},
+};
+
+use crate::sys::*;
+
+/// What to compile into the resource file linked into the builds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceOptions {
+    pub manifest: bool,
+    /// Manifest fragments to merge.
+    pub manifest_fragments: Vec<PathBuf>,
+    pub version_info: bool,
+    /// The icon, in place of the one of `[package.metadata.thunk]`.
+    pub icon: Option<PathBuf>,
+}
+
+/// The package being built, as `cargo metadata` resolves it.
+#[derive(Debug, Clone, Default)]
+pub struct Package {
+    pub info: PackageInfo,
+    /// The directory of its `Cargo.toml`.
+    pub dir: PathBuf,
+    /// `[package.metadata.thunk]`
+    pub metadata: toml::Table,
+}
+
+/// Run `cargo metadata` for the workspace of `--manifest-path` or the current directory.
+pub fn read_cargo_metadata(manifest_path: Option<&Path>) -> Result<Value> {
+    let mut command = Command::new("cargo");
+    command.args(["metadata", "--no-deps", "--format-version", "1"]);
+    if let Some(path) = manifest_path {
+        command.arg("--manifest-path").arg(path);
+    }
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "cargo metadata failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+/// Find the package built by `cargo build`: the one of `--package`, or the one of
+/// `--manifest-path` or the current directory, or the only member of the workspace.
+pub fn read_package(name: Option<&str>, manifest_path: Option<&Path>) -> Result<Option<Package>> {
+    find_package(&read_cargo_metadata(manifest_path)?, name, manifest_path)
+}
+
+/// Find the package built by `cargo build` in the output of `cargo metadata`.
+pub fn find_package(
+    metadata: &Value,
+    name: Option<&str>,
+    manifest_path: Option<&Path>,
+) -> Result<Option<Package>> {
+    let packages = metadata["packages"].as_array().cloned().unwrap_or_default();
+
+    let current = fs::canonicalize(manifest_path.unwrap_or(Path::new("Cargo.toml"))).ok();
+    let package = match name {
+        Some(name) => packages.iter().find(|x| x["name"] == name),
+        None => packages
+            .iter()
+            .find(|x| {
+                let path = x["manifest_path"].as_str().map(fs::canonicalize);
+                matches!((path, &current), (Some(Ok(a)), Some(b)) if &a == b)
+            })
+            .or(match packages.len() {
+                1 => packages.first(),
+                _ => None,
+            }),
+    };
+    let package = match package {
+        Some(package) => package,
+        None => return Ok(None),
+    };
+
+    let text = |key: &str| package[key].as_str().map(str::to_owned);
+    let info = PackageInfo {
+        name: text("name").unwrap_or_default(),
+        version: text("version").unwrap_or_default(),
+        description: text("description"),
+        authors: serde_json::from_value(package["authors"].clone()).unwrap_or_default(),
+    };
+    let dir = text("manifest_path")
+        .map(PathBuf::from)
+        .and_then(|x| x.parent().map(Path::to_path_buf))
+        .unwrap_or_default();
+    let metadata = match &package["metadata"]["thunk"] {
+        Value::Null => toml::Table::new(),
+        value => serde_json::from_value(value.clone())
+            .map_err(|e| anyhow!("Failed to read [package.metadata.thunk]: {}", e))?,
+    };
+    Ok(Some(Package {
+        info,
+        dir,
+        metadata,
+    }))
+}
+
+/// Whether a package of the output of `cargo metadata` has an icon in `[package.metadata.thunk]`.
+pub fn has_package_icons(metadata: &Value) -> bool {
+    metadata["packages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .any(|package| !package["metadata"]["thunk"]["icon"].is_null())
+}
+
+impl Package {
+    /// Get the package from the environment cargo sets for rustc and the linker it runs, `None`
+    /// outside of cargo.
+    pub fn from_env() -> Result<Option<Package>> {
+        let (info, dir) = match (PackageInfo::from_env(), env::var_os("CARGO_MANIFEST_DIR")) {
+            (Some(info), Some(dir)) => (info, PathBuf::from(dir)),
+            _ => return Ok(None),
+        };
+        let path = dir.join("Cargo.toml");
+        let text = fs::read_to_string(&path)
+            .map_err(|e| anyhow!("Failed to read {}: {}", path.to_string_lossy(), e))?;
+        let manifest: toml::Table = text
+            .parse()
+            .map_err(|e| anyhow!("Failed to read {}: {}", path.to_string_lossy(), e))?;
+        let metadata = manifest
+            .get("package")
+            .and_then(|x| x.get("metadata"))
+            .and_then(|x| x.get("thunk"))
+            .and_then(|x| x.as_table())
+            .cloned()
+            .unwrap_or_default();
+        Ok(Some(Package {
+            info,
+            dir,
+            metadata,
+        }))
+    }
+}
+
+/// Build the manifest of the OS with the user fragments.
+pub fn build_manifest(os: OS, fragments: &[PathBuf]) -> Result<Manifest> {
+    let mut manifest = Manifest::new(os);
+    for fragment in fragments {
+        let xml = fs::read_to_string(fragment)
+            .map_err(|e| anyhow!("Failed to read {}: {}", fragment.to_string_lossy(), e))?;
+        manifest.add_fragment(&xml);
+    }
+    Ok(manifest)
+}
+
+/// Compile the resources into `<dir>/<arch>.res`, returning its absolute path, `None` if there is
+/// nothing to link.
+pub fn write_resources(
+    dir: &Path,
+    os: OS,
+    arch: Arch,
+    is_lib: bool,
+    package: Option<&Package>,
+    options: &ResourceOptions,
+) -> Result<Option<PathBuf>> {
+    let mut res = ResourceFile::new();
+    if options.manifest {
+        build_manifest(os, &options.manifest_fragments)?.add_to(&mut res, is_lib);
+    }
+
+    if let (true, Some(package)) = (options.version_info, package) {
+        let mut version_info = VersionInfo::from_package(&package.info, is_lib);
+        version_info
+            .apply_metadata(&package.metadata)
+            .map_err(|e| anyhow!(e))?;
+        version_info.add_to(&mut res);
+    }
+
+    let icon =
+        match (&options.icon, package) {
+            (Some(icon), _) => Some(icon.clone()),
+            (None, Some(package)) => {
+                match package.metadata.get("icon") {
+                    Some(icon) => Some(package.dir.join(icon.as_str().ok_or_else(|| {
+                        anyhow!("icon of [package.metadata.thunk] is not a string")
+                    })?)),
+                    None => None,
+                }
+            }
+            (None, None) => None,
+        };
+    if let Some(icon) = icon {
+        let ico = fs::read(&icon)
+            .map_err(|e| anyhow!("Failed to read {}: {}", icon.to_string_lossy(), e))?;
+        res.add_icon(1, &ico)
+            .map_err(|e| anyhow!("Failed to add icon {}: {}", icon.to_string_lossy(), e))?;
+    }
+    if res.is_empty() {
+        return Ok(None);
+    }
+
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.res", arch));
+    res.save(&path)
+        .map_err(|e| anyhow!("Failed to write {}: {}", path.to_string_lossy(), e))?;
+    Ok(Some(fs::canonicalize(path)?))
+}
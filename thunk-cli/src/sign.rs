@@ -0,0 +1,246 @@
+use anyhow::{anyhow, Result};
+use clap::Args;
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::sys::*;
+
+const DEFAULT_SIGN_TOOL: &str = "osslsigncode";
+
+/// A digest algorithm of an Authenticode signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Digest {
+    Sha1,
+    Sha256,
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Digest::Sha1 => "sha1",
+            Digest::Sha256 => "sha256",
+        };
+        f.write_str(text)
+    }
+}
+
+/// Get the digests to sign with for the OS, the first is the primary signature and the others
+/// are nested in it.
+///
+/// XP, Vista and 7 without KB3033929 only validate SHA-1 signatures and skip nested ones, while
+/// newer systems pick the SHA-256 one.
+pub fn get_digests(os: OS) -> Vec<Digest> {
+    match os {
+        OS::WindowsXP | OS::WindowsVista | OS::Windows7 => vec![Digest::Sha1, Digest::Sha256],
+        _ => vec![Digest::Sha256],
+    }
+}
+
+/// Authenticode signing with osslsigncode.
+#[derive(Debug, Clone, Default, Args)]
+pub struct SignOptions {
+    /// Sign the builds with the certificate and key of this PKCS#12 file, with SHA-1 and a nested
+    /// SHA-256 signature up to win7
+    #[arg(long, value_name = "PFX")]
+    pub sign: Option<PathBuf>,
+    /// Read the password of the PKCS#12 file from a file
+    #[arg(long, value_name = "FILE", requires = "sign")]
+    pub sign_password_file: Option<PathBuf>,
+    /// Timestamp the signatures with a timestamp server
+    #[arg(long, value_name = "URL", requires = "sign")]
+    pub sign_timestamp: Option<String>,
+    /// Timestamp the signatures with a local TSA certificate chain instead of a server
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires_all = ["sign", "sign_tsa_key"],
+        conflicts_with = "sign_timestamp"
+    )]
+    pub sign_tsa_cert: Option<PathBuf>,
+    /// The key of the local TSA certificate
+    #[arg(long, value_name = "FILE", requires = "sign_tsa_cert")]
+    pub sign_tsa_key: Option<PathBuf>,
+    /// The signing tool (default: osslsigncode)
+    #[arg(long, value_name = "PATH", requires = "sign")]
+    pub sign_tool: Option<PathBuf>,
+}
+
+impl SignOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.sign.is_some()
+    }
+}
+
+/// Get the osslsigncode args to add a signature of the digest.
+///
+/// A server timestamps SHA-1 signatures with the legacy Authenticode protocol, which XP
+/// understands, and SHA-256 ones with RFC 3161.
+pub fn get_sign_args(
+    options: &SignOptions,
+    digest: Digest,
+    nested: bool,
+    input: &Path,
+    output: &Path,
+) -> Result<Vec<String>> {
+    let pkcs12 = options
+        .sign
+        .as_ref()
+        .ok_or_else(|| anyhow!("You need to pass --sign to sign."))?;
+    let mut args: Vec<String> = vec!["sign".into(), "-pkcs12".into()];
+    args.push(pkcs12.to_string_lossy().into());
+    if let Some(password) = &options.sign_password_file {
+        args.extend(["-readpass".into(), password.to_string_lossy().into()]);
+    }
+    args.extend(["-h".into(), digest.to_string()]);
+    if nested {
+        args.push("-nest".into());
+    }
+    if let Some(url) = &options.sign_timestamp {
+        let flag = match digest {
+            Digest::Sha1 => "-t",
+            Digest::Sha256 => "-ts",
+        };
+        args.extend([flag.into(), url.clone()]);
+    }
+    if let (Some(cert), Some(key)) = (&options.sign_tsa_cert, &options.sign_tsa_key) {
+        args.extend(["-TSA-certs".into(), cert.to_string_lossy().into()]);
+        args.extend(["-TSA-key".into(), key.to_string_lossy().into()]);
+    }
+    args.extend(["-in".into(), input.to_string_lossy().into()]);
+    args.extend(["-out".into(), output.to_string_lossy().into()]);
+    Ok(args)
+}
+
+/// Sign a PE file for the OS in place, returning the digests it is signed with.
+pub fn sign_file(file: &Path, os: OS, options: &SignOptions) -> Result<Vec<Digest>> {
+    let tool = options
+        .sign_tool
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_SIGN_TOOL));
+    let digests = get_digests(os);
+
+    // osslsigncode does not sign in place.
+    let mut input = file.to_path_buf();
+    for (index, &digest) in digests.iter().enumerate() {
+        let output = file.with_extension(format!("{}.signed", index));
+        let args = get_sign_args(options, digest, index > 0, &input, &output)?;
+        let result = Command::new(&tool).args(&args).output().map_err(|e| {
+            anyhow!(
+                "Failed to run {}: {}, install osslsigncode or pass --sign-tool",
+                tool.to_string_lossy(),
+                e
+            )
+        })?;
+        if input != file {
+            fs::remove_file(&input)?;
+        }
+        if !result.status.success() {
+            let _ = fs::remove_file(&output);
+            return Err(anyhow!(
+                "Failed to sign {} with {}: {}",
+                file.to_string_lossy(),
+                digest,
+                String::from_utf8_lossy(&result.stderr).trim()
+            ));
+        }
+        input = output;
+    }
+    fs::rename(&input, file)?;
+
+    Ok(digests)
+}
+
+/// Sign a PE file for the OS, printing the signatures.
+pub fn fix_signature(file: &Path, os: OS, options: &SignOptions) -> Result<()> {
+    let digests = sign_file(file, os, options)?;
+    let digests: Vec<String> = digests.iter().map(|x| x.to_string()).collect();
+    println!(" * Signed: {}", digests.join(" + nested "));
+    Ok(())
+}
+
+/// Sign an exe or dll with the digests the OS validates
+#[derive(Debug, Args)]
+pub struct SignArgs {
+    /// The exe or dll to sign
+    file: PathBuf,
+    /// Operating system: xp, vista, win7, win10, 20h1 (default: win7)
+    #[arg(short, long, value_name = "OS")]
+    os: Option<OS>,
+    #[command(flatten)]
+    options: SignOptions,
+}
+
+impl SignArgs {
+    pub fn run(self) -> Result<()> {
+        let os = self.os.unwrap_or(OS::Windows7);
+        println!("Sign {} for Windows {}:", self.file.to_string_lossy(), os);
+        fix_signature(&self.file, os, &self.options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_digests() {
+        assert_eq!(
+            get_digests(OS::WindowsXP),
+            vec![Digest::Sha1, Digest::Sha256]
+        );
+        assert_eq!(get_digests(OS::Windows7).len(), 2);
+        assert_eq!(get_digests(OS::Windows8), vec![Digest::Sha256]);
+    }
+
+    #[test]
+    fn test_get_sign_args() {
+        let options = SignOptions {
+            sign: Some("cert.pfx".into()),
+            sign_timestamp: Some("http://timestamp.example".into()),
+            ..Default::default()
+        };
+        let args = get_sign_args(
+            &options,
+            Digest::Sha1,
+            false,
+            Path::new("a.exe"),
+            Path::new("b.exe"),
+        )
+        .unwrap();
+        assert_eq!(
+            args.join(" "),
+            "sign -pkcs12 cert.pfx -h sha1 -t http://timestamp.example -in a.exe -out b.exe"
+        );
+
+        // The local stand-in of a timestamp server
+        let options = SignOptions {
+            sign: Some("cert.pfx".into()),
+            sign_tsa_cert: Some("tsa.pem".into()),
+            sign_tsa_key: Some("tsa.key".into()),
+            ..Default::default()
+        };
+        let args = get_sign_args(
+            &options,
+            Digest::Sha256,
+            true,
+            Path::new("b.exe"),
+            Path::new("c.exe"),
+        )
+        .unwrap();
+        assert_eq!(
+            args.join(" "),
+            "sign -pkcs12 cert.pfx -h sha256 -nest -TSA-certs tsa.pem -TSA-key tsa.key -in b.exe -out c.exe"
+        );
+        assert!(get_sign_args(
+            &SignOptions::default(),
+            Digest::Sha1,
+            false,
+            Path::new("a.exe"),
+            Path::new("b.exe")
+        )
+        .is_err());
+    }
+}
@@ -1,50 +1,9 @@
 use anyhow::Result;
-use std::path::PathBuf;
+use std::{fmt, path::PathBuf};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum OS {
-    WindowsXP,
-    WindowsVista,
-    Windows7,
-    Windows8,
-    Windows10,
-    Windows10_20H1,
-}
+pub use thunk::OS;
 
-impl OS {
-    pub fn from_str(text: &str) -> Self {
-        match text.to_lowercase().as_str() {
-            "windows xp" | "winxp" | "xp" | "5.1" | "5.2" | "2600" | "3790" | "2003" => {
-                OS::WindowsXP
-            }
-            "windows vista" | "winvista" | "vista" | "6.0" | "6000" | "2008" => OS::WindowsVista,
-            "windows 7" | "win7" | "7" | "6.1" | "7600" | "2008r2" => OS::Windows7,
-            "windows 8" | "win8" | "8" | "6.2" | "9200" | "2012" => OS::Windows8,
-            "windows 10" | "win10" | "10" | "10240" | "2016" | "2019" => OS::Windows10,
-            "windows 10 20h1" | "win10 20h1" | "20h1" | "19041" | "2020" => OS::Windows10_20H1,
-            _ => OS::WindowsXP,
-        }
-    }
-
-    pub fn to_string(&self) -> String {
-        match self {
-            OS::WindowsXP => "XP".to_string(),
-            OS::WindowsVista => "Vista".to_string(),
-            OS::Windows7 => "7".to_string(),
-            OS::Windows8 => "8".to_string(),
-            OS::Windows10 => "10".to_string(),
-            OS::Windows10_20H1 => "10_20h1".to_string(),
-        }
-    }
-}
-
-impl From<String> for OS {
-    fn from(value: String) -> Self {
-        Self::from_str(&value)
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Arch {
     Win32,
     X64,
@@ -52,15 +11,24 @@ pub enum Arch {
 }
 
 impl Arch {
-    pub fn from_str(text: &str) -> Self {
+    /// The usual names of the archs, for messages about a name that is none of them.
+    pub const NAMES: &'static str = "x86, x64, arm64";
+
+    /// Parse the name of an arch, `None` if it is none of them.
+    pub fn parse(text: &str) -> Option<Self> {
         match text.to_ascii_lowercase().as_str() {
-            "win32" | "32" | "x86" | "86" | "i686" | "x32" => Arch::Win32,
-            "64" | "x64" | "x86_64" | "x8664" | "amd64" => Arch::X64,
-            "arm" | "aarch64" | "arm64" => Arch::ARM64,
-            _ => Arch::Win32,
+            "win32" | "32" | "x86" | "86" | "i686" | "x32" => Some(Arch::Win32),
+            "64" | "x64" | "x86_64" | "x8664" | "amd64" => Some(Arch::X64),
+            "arm" | "aarch64" | "arm64" => Some(Arch::ARM64),
+            _ => None,
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(text: &str) -> Self {
+        Self::parse(text).unwrap_or(Arch::Win32)
+    }
+
     pub fn from_rust_target(host: &str) -> Result<Arch> {
         match host.to_ascii_lowercase().as_str() {
             "i686-pc-windows-msvc" => Ok(Arch::Win32),
@@ -70,15 +38,7 @@ impl Arch {
         }
     }
 
-    pub fn to_string(&self) -> String {
-        match self {
-            Self::Win32 => "Win32".to_owned(),
-            Self::X64 => "x64".to_owned(),
-            Self::ARM64 => "ARM64".to_owned(),
-        }
-    }
-
-    pub fn to_rust_target(&self) -> Option<String> {
+    pub fn to_rust_target(self) -> Option<String> {
         match self {
             Arch::Win32 => Some("i686-pc-windows-msvc".to_owned()),
             Arch::X64 => Some("x86_64-pc-windows-msvc".to_owned()),
@@ -87,6 +47,17 @@ impl Arch {
     }
 }
 
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Self::Win32 => "Win32",
+            Self::X64 => "x64",
+            Self::ARM64 => "ARM64",
+        };
+        f.write_str(text)
+    }
+}
+
 impl From<String> for Arch {
     fn from(value: String) -> Self {
         Self::from_str(&value)
@@ -120,7 +91,7 @@ pub fn get_vc_ltl_os_lib_path(os: OS, arch: Arch) -> Option<PathBuf> {
             Some(PathBuf::from("TargetPlatform/10.0.19041.0/lib/Win32"))
         }
         (OS::Windows10_20H1, Arch::X64) => {
-            Some(PathBuf::from("TargetPlatform/10.0.19041.0/lib/X64"))
+            Some(PathBuf::from("TargetPlatform/10.0.19041.0/lib/x64"))
         }
         (OS::Windows10_20H1, Arch::ARM64) => {
             Some(PathBuf::from("TargetPlatform/10.0.19041.0/lib/ARM64"))
@@ -147,6 +118,21 @@ pub fn get_yy_thunks_obj_path(os: OS, arch: Arch) -> Option<PathBuf> {
     }
 }
 
+/// Library directories of an xwin-style MSVC CRT + Windows SDK tree, used when
+/// cross compiling from a non-Windows host.
+pub fn get_sysroot_lib_paths(arch: Arch) -> Vec<PathBuf> {
+    let arch = match arch {
+        Arch::Win32 => "x86",
+        Arch::X64 => "x86_64",
+        Arch::ARM64 => "aarch64",
+    };
+    vec![
+        PathBuf::from(format!("crt/lib/{}", arch)),
+        PathBuf::from(format!("sdk/lib/um/{}", arch)),
+        PathBuf::from(format!("sdk/lib/ucrt/{}", arch)),
+    ]
+}
+
 pub fn get_os_version(os: OS, arch: Arch) -> Option<String> {
     match (os, arch) {
         (OS::WindowsXP, Arch::Win32) => Some("5.01".to_owned()),
@@ -160,6 +146,14 @@ pub fn get_os_version(os: OS, arch: Arch) -> Option<String> {
     }
 }
 
+/// The CRT DLL a build links to through VC-LTL.
+pub fn get_vc_ltl_crt_dll(os: OS) -> &'static str {
+    match os {
+        OS::Windows10 | OS::Windows10_20H1 => "ucrtbase.dll",
+        _ => "msvcrt.dll",
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Subsystem {
     Windows,
@@ -167,19 +161,31 @@ pub enum Subsystem {
 }
 
 impl Subsystem {
-    pub fn from_str(subsystem: &str) -> Self {
+    /// The usual names of the subsystems, for messages about a name that is none of them.
+    pub const NAMES: &'static str = "console, windows";
+
+    /// Parse the name of a subsystem, `None` if it is none of them.
+    pub fn parse(subsystem: &str) -> Option<Self> {
         match subsystem.to_lowercase().as_ref() {
-            "window" | "windows" | "win" | "w" | "gui" | "g" | "ui" | "u" => Self::Windows,
-            "console" | "command" | "line" | "c" => Self::Console,
-            _ => Self::Console,
+            "window" | "windows" | "win" | "w" | "gui" | "g" | "ui" | "u" => Some(Self::Windows),
+            "console" | "command" | "line" | "c" => Some(Self::Console),
+            _ => None,
         }
     }
 
-    pub fn to_string(&self) -> String {
-        match self {
-            Subsystem::Windows => "WINDOWS".to_owned(),
-            Subsystem::Console => "CONSOLE".to_owned(),
-        }
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(subsystem: &str) -> Self {
+        Self::parse(subsystem).unwrap_or(Self::Console)
+    }
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Subsystem::Windows => "WINDOWS",
+            Subsystem::Console => "CONSOLE",
+        };
+        f.write_str(text)
     }
 }
 
@@ -189,6 +195,16 @@ impl From<String> for Subsystem {
     }
 }
 
+/// Parse an OS argument, failing on a name that is none of the OSes.
+pub fn parse_os_arg(text: &str) -> std::result::Result<OS, String> {
+    OS::parse(text).ok_or_else(|| format!("expected one of {}", OS::NAMES))
+}
+
+/// Parse an arch argument, failing on a name that is none of the archs.
+pub fn parse_arch_arg(text: &str) -> std::result::Result<Arch, String> {
+    Arch::parse(text).ok_or_else(|| format!("expected one of {}", Arch::NAMES))
+}
+
 pub fn get_default_arch() -> Result<Arch> {
     get_default_arch_from_rustup()
 }
@@ -252,10 +268,41 @@ where
     false
 }
 
+/// Get the package of `-p` or `--package`.
+pub fn get_package_from_args<I, S>(args: I) -> Option<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut has_package = false;
+    for arg in args {
+        let arg = arg.as_ref();
+        if has_package {
+            return Some(arg.to_owned());
+        }
+        if let Some(package) = arg.strip_prefix("--package=") {
+            return Some(package.to_owned());
+        }
+        if let Some(package) = arg.strip_prefix("-p").filter(|x| !x.is_empty()) {
+            return Some(package.trim_start_matches('=').to_owned());
+        }
+        has_package = arg == "-p" || arg == "--package";
+    }
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_get_vc_ltl_os_lib_path() {
+        assert_eq!(
+            get_vc_ltl_os_lib_path(OS::Windows10_20H1, Arch::X64),
+            Some(PathBuf::from("TargetPlatform/10.0.19041.0/lib/x64"))
+        );
+    }
+
     #[test]
     fn test_get_arch_from_args() {
         let args = vec!["--target", "i686-pc-windows-msvc"];
@@ -270,18 +317,30 @@ mod tests {
     #[test]
     fn test_get_arch_from_wrong_args() {
         let args: Vec<&str> = vec![];
-        assert_eq!(get_arch_from_args(&args).is_ok(), false);
+        assert!(get_arch_from_args(&args).is_err());
 
         let args = vec!["target", "i686-pc-windows-msvc"];
-        assert_eq!(get_arch_from_args(&args).is_ok(), false);
+        assert!(get_arch_from_args(&args).is_err());
     }
 
     #[test]
     fn test_get_is_lib_from_args() {
         let args: Vec<&str> = vec!["--lib"];
-        assert_eq!(get_is_lib_from_args(&args), true);
+        assert!(get_is_lib_from_args(&args));
 
         let args = vec![""];
-        assert_eq!(get_is_lib_from_args(&args), false);
+        assert!(!get_is_lib_from_args(&args));
+    }
+
+    #[test]
+    fn test_get_package_from_args() {
+        let args = vec!["--release", "-p", "sample"];
+        assert_eq!(get_package_from_args(&args).as_deref(), Some("sample"));
+
+        let args = vec!["--package=sample"];
+        assert_eq!(get_package_from_args(&args).as_deref(), Some("sample"));
+
+        let args = vec!["--profile", "release"];
+        assert_eq!(get_package_from_args(&args), None);
     }
 }
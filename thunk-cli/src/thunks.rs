@@ -0,0 +1,482 @@
+use anyhow::{anyhow, Result};
+use clap::{Args, Subcommand};
+use object::{
+    Architecture, Object, ObjectSection, ObjectSymbol, RelocationTarget, SymbolKind, SymbolSection,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::config::Config;
+use crate::map::{find_map_file, LinkMap};
+use crate::pe::read_arch;
+use crate::sys::*;
+use crate::ENV_VAR_YY_THUNKS;
+
+const IMP_PREFIX: &str = "__imp_";
+const TRY_GET_PREFIX: &str = "try_get_";
+const TRY_GET_MODULE_PREFIX: &str = "try_get_module_";
+pub(crate) const YY_THUNKS_OBJ_PREFIX: &str = "YY_Thunks_for_";
+
+/// The APIs YY-Thunks provides, keyed on the module and the name.
+///
+/// The same name in another DLL is not provided, such as a `CreateFile2` of a private DLL.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThunkedApis {
+    /// `(module, name)` such as `("kernel32", "GetTickCount64")`, the module is empty if unknown.
+    apis: BTreeSet<(String, String)>,
+}
+
+impl ThunkedApis {
+    /// Add an API of a module, any DLL matches it if the module is empty.
+    pub fn insert(&mut self, module: &str, name: &str) {
+        self.apis.insert((module_name(module), name.to_owned()));
+    }
+
+    /// Whether YY-Thunks provides the API imported from the DLL.
+    pub fn contains(&self, dll: &str, name: &str) -> bool {
+        self.apis.contains(&(module_name(dll), name.to_owned()))
+            || self.apis.contains(&(String::new(), name.to_owned()))
+    }
+
+    /// Whether YY-Thunks provides an API of the name from any DLL.
+    pub fn contains_name(&self, name: &str) -> bool {
+        self.apis.iter().any(|(_, x)| x == name)
+    }
+
+    /// The `(module, name)` of the APIs, ordered by the module.
+    pub fn iter(&self) -> impl Iterator<Item = &(String, String)> {
+        self.apis.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.apis.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.apis.is_empty()
+    }
+}
+
+impl<const N: usize> From<[(&str, &str); N]> for ThunkedApis {
+    fn from(apis: [(&str, &str); N]) -> Self {
+        let mut thunked = Self::default();
+        for (module, name) in apis {
+            thunked.insert(module, name);
+        }
+        thunked
+    }
+}
+
+/// Get the module name YY-Thunks uses for a DLL, such as `api_ms_win_core_synch_l1_2_0` for
+/// `API-MS-Win-Core-Synch-l1-2-0.dll`.
+fn module_name(dll: &str) -> String {
+    let dll = dll.to_ascii_lowercase();
+    let dll = dll.strip_suffix(".dll").unwrap_or(&dll);
+    dll.replace(['-', '.'], "_")
+}
+
+/// Get the APIs YY-Thunks provides for the OS and arch.
+pub fn get_thunked_apis(yy_thunks: &Path, os: OS, arch: Arch) -> Result<ThunkedApis> {
+    let obj = get_yy_thunks_obj_path(os, arch)
+        .ok_or_else(|| anyhow!("YY-Thunks does not support Windows {}({})", os, arch))?;
+    read_thunked_apis(&yy_thunks.join(obj))
+}
+
+/// Get the APIs YY-Thunks provides, empty if YY-Thunks is not set or does not support the target.
+///
+/// `yy_thunks` is the directory the build used, it is found as [`get_yy_thunks_dir`] without one.
+pub fn find_thunked_apis(yy_thunks: Option<&Path>, os: OS, arch: Arch) -> Result<ThunkedApis> {
+    let yy_thunks = match yy_thunks {
+        Some(dir) => Ok(dir.to_owned()),
+        None => get_yy_thunks_dir(),
+    };
+    match yy_thunks {
+        Ok(yy_thunks) if get_yy_thunks_obj_path(os, arch).is_some() => {
+            get_thunked_apis(&yy_thunks, os, arch)
+        }
+        Ok(_) => Ok(ThunkedApis::default()),
+        Err(e) => {
+            println!("Note: {} APIs provided by YY-Thunks are not skipped.", e);
+            Ok(ThunkedApis::default())
+        }
+    }
+}
+
+/// Get the YY-Thunks directory from the environment variable or the configuration of the current
+/// directory.
+pub fn get_yy_thunks_dir() -> Result<PathBuf> {
+    Config::load(None, None)?
+        .yy_thunks
+        .map(|x| x.value)
+        .ok_or_else(|| {
+            anyhow!(
+                "You need to set {} environment variable or yy-thunks in thunk.toml.",
+                ENV_VAR_YY_THUNKS
+            )
+        })
+}
+
+/// Read the APIs a YY-Thunks object provides from the `__imp_` symbols it defines.
+///
+/// The module of an API is the `try_get_module_<module>` its `try_get_<name>` refers to, any
+/// DLL matches the API if the object does not tell.
+pub fn read_thunked_apis(obj: &Path) -> Result<ThunkedApis> {
+    let data =
+        fs::read(obj).map_err(|e| anyhow!("Failed to read {}: {}", obj.to_string_lossy(), e))?;
+    let file = object::File::parse(data.as_slice())?;
+    let is_x86 = file.architecture() == Architecture::I386;
+    let references = read_symbol_references(&file);
+
+    let mut apis = ThunkedApis::default();
+    let names = file
+        .symbols()
+        .filter(|symbol| symbol.is_definition())
+        .filter_map(|symbol| symbol.name().ok().map(|name| name.to_owned()))
+        .filter_map(|name| name.strip_prefix(IMP_PREFIX).map(|x| undecorate(x, is_x86)));
+    for name in names {
+        let modules = find_api_modules(&references, &name);
+        if modules.is_empty() {
+            apis.insert("", &name);
+        }
+        for module in &modules {
+            apis.insert(module, &name);
+        }
+    }
+
+    Ok(apis)
+}
+
+/// Read the symbols each defined symbol of an object refers to by relocations.
+fn read_symbol_references(file: &object::File) -> BTreeMap<String, BTreeSet<String>> {
+    let mut references: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+
+    // Named symbols of each section by address, a relocation belongs to the one before it
+    let mut section_symbols: BTreeMap<_, Vec<(u64, String)>> = BTreeMap::new();
+    for symbol in file.symbols() {
+        if let (SymbolSection::Section(index), Ok(name)) = (symbol.section(), symbol.name()) {
+            if symbol.kind() != SymbolKind::Section && !name.is_empty() {
+                section_symbols
+                    .entry(index.0)
+                    .or_default()
+                    .push((symbol.address(), name.to_owned()));
+            }
+        }
+    }
+    for symbols in section_symbols.values_mut() {
+        symbols.sort();
+    }
+
+    for section in file.sections() {
+        let Some(symbols) = section_symbols.get(&section.index().0) else {
+            continue;
+        };
+        for (offset, relocation) in section.relocations() {
+            let RelocationTarget::Symbol(index) = relocation.target() else {
+                continue;
+            };
+            let Ok(target) = file.symbol_by_index(index) else {
+                continue;
+            };
+            // A section symbol stands for the symbols of its section
+            let targets = match (target.kind(), target.section()) {
+                (SymbolKind::Section, SymbolSection::Section(index)) => section_symbols
+                    .get(&index.0)
+                    .map(|x| x.iter().map(|(_, name)| name.clone()).collect())
+                    .unwrap_or_default(),
+                _ => target
+                    .name()
+                    .map(|x| vec![x.to_owned()])
+                    .unwrap_or_default(),
+            };
+            if let Some((_, source)) = symbols.iter().rev().find(|(address, _)| *address <= offset)
+            {
+                references
+                    .entry(source.clone())
+                    .or_default()
+                    .extend(targets);
+            }
+        }
+    }
+
+    references
+}
+
+/// Find the modules `try_get_<name>` refers to, such as `kernel32` of `try_get_module_kernel32`.
+fn find_api_modules(
+    references: &BTreeMap<String, BTreeSet<String>>,
+    name: &str,
+) -> BTreeSet<String> {
+    references
+        .iter()
+        .filter(|(symbol, _)| {
+            symbol_identifier(symbol)
+                .strip_prefix(TRY_GET_PREFIX)
+                .is_some_and(|x| x == name)
+        })
+        .flat_map(|(_, targets)| targets)
+        .filter_map(|target| {
+            symbol_identifier(target)
+                .strip_prefix(TRY_GET_MODULE_PREFIX)
+                .map(|x| x.to_owned())
+        })
+        .collect()
+}
+
+/// Get the identifier of a C or C++ symbol, such as `try_get_module_kernel32` of
+/// `?try_get_module_kernel32@YY_Thunks@@YAPAUHINSTANCE__@@XZ` or `@try_get_GetTickCount64@0`.
+fn symbol_identifier(symbol: &str) -> &str {
+    let symbol = symbol.trim_start_matches(['?', '@', '_']);
+    symbol.split('@').next().unwrap_or(symbol)
+}
+
+/// Strip the x86 `_name@N` decoration, other archs are not decorated.
+pub(crate) fn undecorate(name: &str, is_x86: bool) -> String {
+    if !is_x86 {
+        return name.to_owned();
+    }
+    let name = name
+        .strip_prefix('_')
+        .or_else(|| name.strip_prefix('@'))
+        .unwrap_or(name);
+    match name.rsplit_once('@') {
+        Some((name, size)) if size.chars().all(|x| x.is_ascii_digit()) => name.to_owned(),
+        _ => name.to_owned(),
+    }
+}
+
+/// The YY-Thunks shims kept by the linker in a build.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LinkedThunks {
+    pub artifact: String,
+    /// `map` or `image`, where the shims are read from.
+    pub source: String,
+    pub apis: BTreeSet<String>,
+}
+
+/// Read the shims from the `__imp_` symbols the link map places in the YY-Thunks object.
+pub fn read_linked_thunks_from_map(map: &LinkMap, is_x86: bool) -> BTreeSet<String> {
+    map.symbols
+        .iter()
+        .filter(|symbol| symbol.object.contains(YY_THUNKS_OBJ_PREFIX))
+        .filter_map(|symbol| symbol.name.strip_prefix(IMP_PREFIX))
+        .map(|name| undecorate(name, is_x86))
+        .collect()
+}
+
+/// Read the shims from the API names a build keeps for `GetProcAddress`.
+pub fn read_linked_thunks_from_image(data: &[u8], thunked: &ThunkedApis) -> BTreeSet<String> {
+    data.split(|x| *x == 0)
+        .filter_map(|x| std::str::from_utf8(x).ok())
+        .filter(|x| thunked.contains_name(x))
+        .map(|x| x.to_owned())
+        .collect()
+}
+
+/// Get the shims linked into a build from its link map, or from the image without a map.
+///
+/// `yy_thunks` is the directory the build used, it is found as [`get_yy_thunks_dir`] without one.
+pub fn get_linked_thunks(
+    file: &Path,
+    map_file: Option<&Path>,
+    yy_thunks: Option<&Path>,
+    os: OS,
+) -> Result<LinkedThunks> {
+    let data =
+        fs::read(file).map_err(|e| anyhow!("Failed to read {}: {}", file.to_string_lossy(), e))?;
+    let arch = read_arch(&data)?;
+    let artifact = file.to_string_lossy().to_string();
+
+    match map_file
+        .map(|x| x.to_owned())
+        .or_else(|| find_map_file(file))
+    {
+        Some(map_file) => Ok(LinkedThunks {
+            artifact,
+            source: "map".to_owned(),
+            apis: read_linked_thunks_from_map(&LinkMap::load(&map_file)?, arch == Arch::Win32),
+        }),
+        None => {
+            let yy_thunks = match yy_thunks {
+                Some(dir) => dir.to_owned(),
+                None => get_yy_thunks_dir()?,
+            };
+            Ok(LinkedThunks {
+                artifact,
+                source: "image".to_owned(),
+                apis: read_linked_thunks_from_image(
+                    &data,
+                    &get_thunked_apis(&yy_thunks, os, arch)?,
+                ),
+            })
+        }
+    }
+}
+
+/// Print the shims linked into a build.
+pub fn print_linked_thunks(linked: &LinkedThunks) {
+    println!(
+        "YY-Thunks shims linked into {} (from the {}):",
+        linked.artifact, linked.source
+    );
+    for api in &linked.apis {
+        println!(" * {}", api);
+    }
+    println!("Total: {}", linked.apis.len());
+}
+
+/// Write linked shim reports as JSON.
+pub fn save_linked_thunks_json(reports: &[LinkedThunks], path: &Path) -> Result<()> {
+    fs::write(path, serde_json::to_string_pretty(reports)?)?;
+    Ok(())
+}
+
+/// Inspect the APIs provided by YY-Thunks
+#[derive(Debug, Args)]
+pub struct ThunksArgs {
+    #[command(subcommand)]
+    command: ThunksCommands,
+}
+
+#[derive(Debug, Subcommand)]
+enum ThunksCommands {
+    /// List the APIs YY-Thunks provides for the target OS
+    List {
+        /// Operating system: xp, vista, win7, win10, 20h1 (dafault: win7)
+        #[arg(short, long, value_name = "OS")]
+        os: Option<OS>,
+        /// Operating system arch: x86, x64 (dafault: x86)
+        #[arg(short, long)]
+        arch: Option<Arch>,
+    },
+    /// List the shims the linker kept in a build, the fallback paths to test on the old OS
+    Linked {
+        /// The exe or dll to inspect
+        file: PathBuf,
+        /// Operating system, used without a link map: xp, vista, win7, win10, 20h1 (dafault: win7)
+        #[arg(short, long, value_name = "OS")]
+        os: Option<OS>,
+        /// Link map (default: the newest map of the file in the same or the deps directory)
+        #[arg(long, value_name = "FILE")]
+        map: Option<PathBuf>,
+        /// Also write the report as JSON
+        #[arg(long, value_name = "FILE")]
+        json: Option<PathBuf>,
+    },
+}
+
+impl ThunksArgs {
+    pub fn run(self) -> Result<()> {
+        match self.command {
+            ThunksCommands::List { os, arch } => {
+                let os = os.unwrap_or(OS::Windows7);
+                let arch = arch.unwrap_or(Arch::Win32);
+                let apis = get_thunked_apis(&get_yy_thunks_dir()?, os, arch)?;
+
+                println!("YY-Thunks APIs for Windows {}({}):", os, arch);
+                for (module, name) in apis.iter() {
+                    if module.is_empty() {
+                        println!(" * {}", name);
+                    } else {
+                        println!(" * {}!{}", module, name);
+                    }
+                }
+                println!("Total: {}", apis.len());
+            }
+            ThunksCommands::Linked {
+                file,
+                os,
+                map,
+                json,
+            } => {
+                let os = os.unwrap_or(OS::Windows7);
+                let linked = get_linked_thunks(&file, map.as_deref(), None, os)?;
+                print_linked_thunks(&linked);
+                if let Some(json) = &json {
+                    save_linked_thunks_json(&[linked], json)?;
+                    println!("Report is saved to {}", json.to_string_lossy());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_linked_thunks() {
+        let map = LinkMap::parse(
+            " 0003:00000010       __imp__GetTickCount64@0    00403010     YY_Thunks_for_WinXP.obj
+ 0003:00000020       __imp__ExitProcess@4       00403020     kernel32:kernel32.dll",
+        );
+        assert_eq!(
+            read_linked_thunks_from_map(&map, true),
+            BTreeSet::from(["GetTickCount64".to_owned()])
+        );
+
+        let thunked = ThunkedApis::from([
+            ("kernel32", "GetTickCount64"),
+            ("kernel32", "InitializeSRWLock"),
+        ]);
+        assert_eq!(
+            read_linked_thunks_from_image(b"\0kernel32\0GetTickCount64\0xGetTickCount64", &thunked),
+            BTreeSet::from(["GetTickCount64".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_thunked_apis() {
+        let thunked = ThunkedApis::from([
+            ("kernel32", "GetTickCount64"),
+            ("api-ms-win-core-synch-l1-2-0.dll", "WaitOnAddress"),
+            ("", "InitializeSRWLock"),
+        ]);
+        assert!(thunked.contains("KERNEL32.dll", "GetTickCount64"));
+        assert!(!thunked.contains("myhelper.dll", "GetTickCount64"));
+        assert!(thunked.contains("API-MS-Win-Core-Synch-l1-2-0.dll", "WaitOnAddress"));
+        assert!(!thunked.contains("kernel32.dll", "WaitOnAddress"));
+        assert!(thunked.contains("myhelper.dll", "InitializeSRWLock"));
+        assert!(thunked.contains_name("WaitOnAddress"));
+    }
+
+    #[test]
+    fn test_find_api_modules() {
+        let references = BTreeMap::from([
+            (
+                "?try_get_GetTickCount64@YY_Thunks@@YGP6G_KXZXZ".to_owned(),
+                BTreeSet::from([
+                    "?try_get_module_kernel32@YY_Thunks@@YGPAUHINSTANCE__@@XZ".to_owned()
+                ]),
+            ),
+            (
+                "@try_get_WaitOnAddress@0".to_owned(),
+                BTreeSet::from([
+                    "@try_get_module_api_ms_win_core_synch_l1_2_0@0".to_owned(),
+                    "_memset".to_owned(),
+                ]),
+            ),
+            (
+                "_GetTickCount64@0".to_owned(),
+                BTreeSet::from([
+                    "@try_get_GetTickCount64@0".to_owned(),
+                    "@try_get_module_ntdll@0".to_owned(),
+                ]),
+            ),
+        ]);
+        assert_eq!(
+            find_api_modules(&references, "GetTickCount64"),
+            BTreeSet::from(["kernel32".to_owned()])
+        );
+        assert_eq!(
+            find_api_modules(&references, "WaitOnAddress"),
+            BTreeSet::from(["api_ms_win_core_synch_l1_2_0".to_owned()])
+        );
+        assert!(find_api_modules(&references, "InitializeSRWLock").is_empty());
+    }
+}
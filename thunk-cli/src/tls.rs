@@ -0,0 +1,103 @@
+use anyhow::{anyhow, Result};
+use clap::Args;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::pe::{read_is_dll, read_tls, TlsInfo};
+use crate::sys::*;
+
+/// Find implicit TLS in a DLL that the loader of the OS does not initialize.
+///
+/// The XP loader only sets up static TLS for the DLLs loaded with the process, a DLL
+/// loaded by `LoadLibrary` crashes on its first `thread_local!` access.
+pub fn check_tls(file: &Path, os: OS) -> Result<Option<String>> {
+    let data =
+        fs::read(file).map_err(|e| anyhow!("Failed to read {}: {}", file.to_string_lossy(), e))?;
+    if os > OS::WindowsXP || !read_is_dll(&data)? {
+        return Ok(None);
+    }
+    Ok(read_tls(&data)?.map(|tls| {
+        format!(
+            "{} has {}, Windows {} does not initialize it when the DLL is loaded by LoadLibrary",
+            file.to_string_lossy(),
+            describe_tls(&tls),
+            os
+        )
+    }))
+}
+
+/// Describe the TLS of a PE image, such as `implicit TLS of 8 bytes (.tls section of 16 bytes)`.
+pub fn describe_tls(tls: &TlsInfo) -> String {
+    let mut text = format!("implicit TLS of {} bytes", tls.raw_data_size);
+    if tls.zero_fill_size > 0 {
+        text += &format!(" and {} zero filled bytes", tls.zero_fill_size);
+    }
+    if let Some((name, size)) = &tls.section {
+        text += &format!(" ({} section of {} bytes)", name, size);
+    }
+    if tls.callbacks > 0 {
+        text += &format!(" with {} TLS callbacks", tls.callbacks);
+    }
+    text
+}
+
+/// Check whether a DLL uses implicit TLS that the target OS fails to initialize
+#[derive(Debug, Args)]
+pub struct TlsArgs {
+    /// The dll to check
+    file: PathBuf,
+    /// Operating system: xp, vista, win7, win10, 20h1 (dafault: xp)
+    #[arg(short, long, value_name = "OS")]
+    os: Option<OS>,
+}
+
+impl TlsArgs {
+    pub fn run(self) -> Result<()> {
+        let os = self.os.unwrap_or(OS::WindowsXP);
+        let data = fs::read(&self.file)
+            .map_err(|e| anyhow!("Failed to read {}: {}", self.file.to_string_lossy(), e))?;
+
+        println!(
+            "Check TLS of {} for Windows {}:",
+            self.file.to_string_lossy(),
+            os
+        );
+        match read_tls(&data)? {
+            Some(tls) => println!(" * Found {}", describe_tls(&tls)),
+            None => println!(" * No implicit TLS"),
+        }
+
+        match check_tls(&self.file, os)? {
+            Some(message) => Err(anyhow!(message)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_tls() {
+        let mut tls = TlsInfo {
+            raw_data_size: 8,
+            zero_fill_size: 0,
+            callbacks: 0,
+            section: Some((".tls".to_owned(), 12)),
+        };
+        assert_eq!(
+            describe_tls(&tls),
+            "implicit TLS of 8 bytes (.tls section of 12 bytes)"
+        );
+        tls.zero_fill_size = 4;
+        tls.callbacks = 1;
+        tls.section = None;
+        assert_eq!(
+            describe_tls(&tls),
+            "implicit TLS of 8 bytes and 4 zero filled bytes with 1 TLS callbacks"
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use anyhow::{anyhow, Result};
+use clap::Args;
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::crt::is_vc_redist_dll;
+use crate::map::{find_map_file, LinkMap};
+use crate::pe::{read_arch, read_imports, read_section_names, Import, ImportName};
+use crate::sys::*;
+use crate::thunks::{find_thunked_apis, ThunkedApis, YY_THUNKS_OBJ_PREFIX};
+
+const YY_THUNKS_SECTION_PREFIX: &str = ".YYTh";
+
+/// Find why the imports show that VC-LTL did not take effect.
+pub fn verify_crt(imports: &[Import], os: OS) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let redist: BTreeSet<String> = imports
+        .iter()
+        .filter(|import| is_vc_redist_dll(&import.dll, os))
+        .map(|import| import.dll.to_ascii_lowercase())
+        .collect();
+    for dll in &redist {
+        problems.push(format!(
+            "imports {}, VC-LTL was bypassed by the library search order, crt-static or a stale build",
+            dll
+        ));
+    }
+
+    let crt_dll = get_vc_ltl_crt_dll(os);
+    if !imports
+        .iter()
+        .any(|import| import.dll.eq_ignore_ascii_case(crt_dll))
+    {
+        problems.push(format!(
+            "does not import {}, the CRT of VC-LTL for Windows {}",
+            crt_dll, os
+        ));
+    }
+
+    problems
+}
+
+/// Find why the build shows that YY-Thunks did not take effect.
+///
+/// The YY-Thunks object is linked if the link map places symbols in it or the image has its
+/// sections, and no API it provides may be left in the imports.
+pub fn verify_yy_thunks(
+    imports: &[Import],
+    section_names: &[String],
+    map: Option<&LinkMap>,
+    thunked: &ThunkedApis,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let has_section = section_names
+        .iter()
+        .any(|name| name.starts_with(YY_THUNKS_SECTION_PREFIX));
+    let has_symbols = map.is_some_and(|map| {
+        map.symbols
+            .iter()
+            .any(|symbol| symbol.object.contains(YY_THUNKS_OBJ_PREFIX))
+    });
+    if !has_section && !has_symbols {
+        problems.push("has no YY-Thunks object linked".to_owned());
+    }
+
+    for import in imports {
+        if let ImportName::Name(name) = &import.name {
+            if thunked.contains(&import.dll, name) {
+                problems.push(format!("imports {} which YY-Thunks should provide", import));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Verify that VC-LTL and YY-Thunks took effect in a build, returning the problems found.
+///
+/// `yy_thunks` is the directory the build used, it is found from the configuration without one.
+pub fn verify_file(file: &Path, yy_thunks: Option<&Path>, os: OS) -> Result<Vec<String>> {
+    let data =
+        fs::read(file).map_err(|e| anyhow!("Failed to read {}: {}", file.to_string_lossy(), e))?;
+    let arch = read_arch(&data)?;
+    let imports = read_imports(&data)?;
+
+    let mut problems = verify_crt(&imports, os);
+
+    if get_yy_thunks_obj_path(os, arch).is_some() {
+        let map = match find_map_file(file) {
+            Some(path) => Some(LinkMap::load(&path)?),
+            None => None,
+        };
+        let thunked = find_thunked_apis(yy_thunks, os, arch)?;
+        problems.extend(verify_yy_thunks(
+            &imports,
+            &read_section_names(&data)?,
+            map.as_ref(),
+            &thunked,
+        ));
+    }
+
+    Ok(problems)
+}
+
+/// Verify that VC-LTL and YY-Thunks took effect in a build
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    /// The exe or dll to verify
+    file: PathBuf,
+    /// Operating system: xp, vista, win7, win10, 20h1 (dafault: win7)
+    #[arg(short, long, value_name = "OS")]
+    os: Option<OS>,
+}
+
+impl VerifyArgs {
+    pub fn run(self) -> Result<()> {
+        let os = self.os.unwrap_or(OS::Windows7);
+        println!("Verify {} for Windows {}:", self.file.to_string_lossy(), os);
+        let problems = verify_file(&self.file, None, os)?;
+        for problem in &problems {
+            println!(" * {}", problem);
+        }
+        if problems.is_empty() {
+            println!("VC-LTL and YY-Thunks took effect.");
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "VC-LTL or YY-Thunks did not take effect in {}",
+                self.file.to_string_lossy()
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(dll: &str, name: &str) -> Import {
+        Import {
+            dll: dll.to_owned(),
+            name: ImportName::Name(name.to_owned()),
+            delayed: false,
+        }
+    }
+
+    #[test]
+    fn test_verify_crt() {
+        let imports = [
+            import("msvcrt.dll", "malloc"),
+            import("KERNEL32.dll", "ExitProcess"),
+        ];
+        assert!(verify_crt(&imports, OS::WindowsXP).is_empty());
+        assert_eq!(verify_crt(&imports, OS::Windows10).len(), 1);
+
+        let imports = [
+            import("VCRUNTIME140.dll", "memcpy"),
+            import("api-ms-win-crt-heap-l1-1-0.dll", "malloc"),
+        ];
+        assert_eq!(verify_crt(&imports, OS::Windows7).len(), 3);
+    }
+
+    #[test]
+    fn test_verify_yy_thunks() {
+        let thunked = ThunkedApis::from([("kernel32", "GetTickCount64")]);
+        let imports = [import("KERNEL32.dll", "ExitProcess")];
+        let sections = vec![".text".to_owned(), ".YYThr".to_owned()];
+        assert!(verify_yy_thunks(&imports, &sections, None, &thunked).is_empty());
+
+        let map = LinkMap::parse(
+            " 0003:00000010       __imp__GetTickCount64@0    00403010     YY_Thunks_for_WinXP.obj",
+        );
+        assert!(verify_yy_thunks(&imports, &[], Some(&map), &thunked).is_empty());
+
+        let imports = [import("KERNEL32.dll", "GetTickCount64")];
+        assert_eq!(verify_yy_thunks(&imports, &[], None, &thunked).len(), 2);
+
+        let imports = [import("myhelper.dll", "GetTickCount64")];
+        assert_eq!(verify_yy_thunks(&imports, &[], None, &thunked).len(), 1);
+    }
+}
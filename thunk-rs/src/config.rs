@@ -0,0 +1,503 @@
+//! What `thunk()` builds for: `[package.metadata.thunk]` of the package, with the features of
+//! thunk-rs as a fallback.
+
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use toml::Table;
+
+use crate::OS;
+
+const VC_LTL_VERSION: &str = "5.2.2";
+const YY_THUNKS_VERSION: &str = "1.1.7";
+
+/// The settings of `thunk()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// The OS to build for, `None` to only link VC-LTL with `vc_ltl_only` or do nothing.
+    pub os: Option<OS>,
+    /// Link VC-LTL for Vista without YY-Thunks when no OS is set.
+    pub vc_ltl_only: bool,
+    /// Link release builds with the WINDOWS subsystem.
+    pub subsystem_windows: bool,
+    /// Build a library, the subsystem is left alone.
+    pub lib: bool,
+    /// Leave the build alone, with `THUNK_DISABLE`.
+    pub disable: bool,
+    pub vc_ltl_version: String,
+    pub yy_thunks_version: String,
+    /// The OS features enabled, from the lowest OS.
+    pub os_features: Vec<&'static str>,
+    /// Whether `os` is set in `[package.metadata.thunk]` or `THUNK_OS`, the features do not
+    /// matter then.
+    pub os_is_set: bool,
+    pub os_conflict: OsConflict,
+}
+
+/// What to do when feature unification enables several OS features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OsConflict {
+    /// Build for the lowest OS, which the builds of the others also run on.
+    #[default]
+    Lowest,
+    Error,
+}
+
+impl Config {
+    /// Get the settings of the enabled features.
+    pub fn from_features() -> Self {
+        let os_features = get_os_features();
+        Self {
+            os: os_features.first().map(|x| x.1),
+            vc_ltl_only: cfg!(feature = "vc_ltl_only"),
+            subsystem_windows: cfg!(feature = "subsystem_windows"),
+            lib: cfg!(feature = "lib"),
+            disable: false,
+            vc_ltl_version: VC_LTL_VERSION.to_owned(),
+            yy_thunks_version: YY_THUNKS_VERSION.to_owned(),
+            os_features: os_features.iter().map(|x| x.0).collect(),
+            os_is_set: false,
+            os_conflict: OsConflict::Lowest,
+        }
+    }
+
+    /// Apply the keys of `[package.metadata.thunk]`, then the ones of its table of the target
+    /// arch, such as `[package.metadata.thunk.x86]`.
+    pub fn apply_metadata(&mut self, metadata: &Table, target_arch: &str) -> Result<(), String> {
+        self.apply_table(metadata, "[package.metadata.thunk]")?;
+        if let Some(value) = metadata.get(target_arch) {
+            let name = format!("[package.metadata.thunk.{}]", target_arch);
+            let table = value
+                .as_table()
+                .ok_or_else(|| format!("{} is not a table", name))?;
+            self.apply_table(table, &name)?;
+        }
+        Ok(())
+    }
+
+    fn apply_table(&mut self, table: &Table, name: &str) -> Result<(), String> {
+        let text = |key: &str| -> Result<Option<String>, String> {
+            match table.get(key) {
+                Some(value) => value
+                    .as_str()
+                    .map(|x| Some(x.to_owned()))
+                    .ok_or_else(|| format!("{} of {} is not a string", key, name)),
+                None => Ok(None),
+            }
+        };
+
+        if let Some(os) = text("os")? {
+            self.os = Some(parse_os(&os).map_err(|e| format!("os of {} {}", name, e))?);
+            self.os_is_set = true;
+        }
+        if let Some(conflict) = text("os-conflict")? {
+            self.os_conflict = match conflict.as_str() {
+                "lowest" => OsConflict::Lowest,
+                "error" => OsConflict::Error,
+                _ => return Err(format!("os-conflict of {} is not lowest or error", name)),
+            };
+        }
+        if let Some(subsystem) = text("subsystem")? {
+            self.subsystem_windows = is_subsystem_windows(&subsystem)
+                .ok_or_else(|| format!("subsystem of {} is not windows or console", name))?;
+        }
+        if let Some(value) = table.get("lib") {
+            self.lib = value
+                .as_bool()
+                .ok_or_else(|| format!("lib of {} is not a bool", name))?;
+        }
+        if let Some(version) = text("vc-ltl-version")? {
+            self.vc_ltl_version = version;
+        }
+        if let Some(version) = text("yy-thunks-version")? {
+            self.yy_thunks_version = version;
+        }
+        Ok(())
+    }
+
+    /// Apply `THUNK_OS`, `THUNK_SUBSYSTEM`, `THUNK_LIB` and `THUNK_DISABLE`, the target specific
+    /// forms such as `THUNK_OS_i686_pc_windows_msvc` first.
+    ///
+    /// `var` gets an environment variable, it is asked for every name that matters.
+    pub fn apply_env(
+        &mut self,
+        target: &str,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<(), String> {
+        let get = |name: &'static str| {
+            let specific = var(&format!("{}_{}", name, target.replace(['-', '.'], "_")));
+            let generic = var(name);
+            specific.or(generic).map(|x| (name, x))
+        };
+        let get_bool = |name: &'static str| match get(name) {
+            Some((name, value)) => parse_bool(&value)
+                .map(Some)
+                .ok_or_else(|| format!("{} is not a bool: {}", name, value)),
+            None => Ok(None),
+        };
+
+        if let Some((name, os)) = get("THUNK_OS") {
+            self.os = Some(parse_os(&os).map_err(|e| format!("{} {}", name, e))?);
+            self.os_is_set = true;
+        }
+        if let Some((name, subsystem)) = get("THUNK_SUBSYSTEM") {
+            self.subsystem_windows = is_subsystem_windows(&subsystem)
+                .ok_or_else(|| format!("{} is not windows or console: {}", name, subsystem))?;
+        }
+        if let Some(lib) = get_bool("THUNK_LIB")? {
+            self.lib = lib;
+        }
+        if let Some(disable) = get_bool("THUNK_DISABLE")? {
+            self.disable = disable;
+        }
+        Ok(())
+    }
+
+    /// Describe the features that do not agree with each other or with the OS.
+    pub fn get_conflicts(&self) -> Vec<String> {
+        let mut conflicts = Vec::new();
+        if self.os_features.len() > 1 && !self.os_is_set {
+            conflicts.push(format!(
+                "the OS features {} of thunk-rs are all enabled, Windows {} is used as the lowest",
+                self.os_features.join(", "),
+                self.os.map(|x| x.to_string()).unwrap_or_default()
+            ));
+        }
+        if let (true, Some(os)) = (self.vc_ltl_only, self.os) {
+            conflicts.push(format!(
+                "vc_ltl_only of thunk-rs is enabled, but YY-Thunks for Windows {} is linked too",
+                os
+            ));
+        }
+        conflicts
+    }
+
+    /// Get the features of [`Config::get_conflicts`], to tell who enables them.
+    pub fn get_conflict_features(&self) -> Vec<&'static str> {
+        let mut features = Vec::new();
+        if self.os_features.len() > 1 && !self.os_is_set {
+            features.extend(&self.os_features);
+        }
+        if let (true, Some(_)) = (self.vc_ltl_only, self.os) {
+            features.push("vc_ltl_only");
+        }
+        features
+    }
+
+    /// Get the platform directory of VC-LTL.
+    pub fn get_vc_ltl_platform(&self, target_arch: &str) -> Option<&'static str> {
+        match self.os {
+            Some(OS::WindowsXP) if target_arch == "x86" => Some("5.1.2600.0"),
+            Some(OS::WindowsXP) => Some("5.2.3790.0"),
+            Some(OS::WindowsVista | OS::Windows7) => Some("6.0.6000.0"),
+            Some(OS::Windows8) => Some("6.2.9200.0"),
+            Some(OS::Windows10) => Some("10.0.10240.0"),
+            Some(OS::Windows10_20H1) => Some("10.0.19041.0"),
+            None if self.vc_ltl_only => Some("6.0.6000.0"),
+            None => None,
+        }
+    }
+
+    /// Get the platform of the YY-Thunks object.
+    pub fn get_yy_thunks_platform(&self) -> Option<&'static str> {
+        match self.os? {
+            OS::WindowsXP => Some("WinXP"),
+            OS::WindowsVista => Some("Vista"),
+            OS::Windows7 => Some("Win7"),
+            OS::Windows8 => Some("Win8"),
+            OS::Windows10 => Some("Win10.0.10240"),
+            OS::Windows10_20H1 => Some("Win10.0.19041"),
+        }
+    }
+}
+
+/// Get the OS features enabled, from the lowest OS.
+fn get_os_features() -> Vec<(&'static str, OS)> {
+    let features = [
+        (cfg!(feature = "xp"), "xp", OS::WindowsXP),
+        (cfg!(feature = "vista"), "vista", OS::WindowsVista),
+        (cfg!(feature = "win7"), "win7", OS::Windows7),
+        (cfg!(feature = "win8"), "win8", OS::Windows8),
+        (cfg!(feature = "win10_10240"), "win10_10240", OS::Windows10),
+        (
+            cfg!(feature = "win10_19041"),
+            "win10_19041",
+            OS::Windows10_20H1,
+        ),
+    ];
+    features
+        .into_iter()
+        .filter(|x| x.0)
+        .map(|x| (x.1, x.2))
+        .collect()
+}
+
+/// Get the features of thunk-rs a feature of it enables, with itself.
+fn expand_feature(features: &Map<String, Value>, feature: &str) -> BTreeSet<String> {
+    let mut expanded = BTreeSet::new();
+    let mut queue = vec![feature.to_owned()];
+    while let Some(current) = queue.pop() {
+        if !expanded.insert(current.clone()) {
+            continue;
+        }
+        let implied = features.get(&current).and_then(Value::as_array);
+        for x in implied.into_iter().flatten().filter_map(Value::as_str) {
+            queue.push(x.to_owned());
+        }
+    }
+    expanded
+}
+
+/// Get the features of thunk-rs a package asks for, in its dependency or through its own features
+/// that are enabled.
+fn get_requested_features(package: &Value, enabled: &[&str]) -> BTreeSet<String> {
+    let mut requested = BTreeSet::new();
+    let mut names = vec![];
+    let dependencies = package["dependencies"].as_array().into_iter().flatten();
+    for dependency in dependencies.filter(|x| x["name"] == "thunk-rs") {
+        if dependency["uses_default_features"].as_bool() != Some(false) {
+            requested.insert("default".to_owned());
+        }
+        let features = dependency["features"].as_array().into_iter().flatten();
+        requested.extend(features.filter_map(Value::as_str).map(str::to_owned));
+        names.push(dependency["rename"].as_str().unwrap_or("thunk-rs"));
+    }
+
+    // Features such as `legacy = ["thunk-rs/xp"]` of the package
+    for feature in enabled {
+        let items = package["features"][feature]
+            .as_array()
+            .into_iter()
+            .flatten();
+        for item in items.filter_map(Value::as_str) {
+            let (name, feature) = match item.split_once('/') {
+                Some(x) => x,
+                None => continue,
+            };
+            if names.contains(&name.trim_end_matches('?')) {
+                requested.insert(feature.to_owned());
+            }
+        }
+    }
+    requested
+}
+
+/// Describe which packages enable each feature of thunk-rs `version`, from the output of
+/// `cargo metadata --format-version 1`.
+pub fn describe_enablers(
+    metadata: &str,
+    version: &str,
+    features: &[&str],
+) -> Result<Vec<String>, String> {
+    let metadata: Value = serde_json::from_str(metadata).map_err(|e| e.to_string())?;
+    let packages = metadata["packages"]
+        .as_array()
+        .ok_or("cargo metadata has no packages")?;
+    let thunk = packages
+        .iter()
+        .find(|x| x["name"] == "thunk-rs" && x["version"] == version)
+        .ok_or_else(|| format!("cargo metadata has no thunk-rs v{}", version))?;
+    let thunk_features = thunk["features"].as_object().cloned().unwrap_or_default();
+    let nodes = metadata["resolve"]["nodes"]
+        .as_array()
+        .ok_or("cargo metadata has no resolve")?;
+
+    // The packages that depend on thunk-rs and the features they enable
+    let mut enablers: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for node in nodes {
+        let depends = node["deps"].as_array().into_iter().flatten();
+        if !depends.into_iter().any(|x| x["pkg"] == thunk["id"]) {
+            continue;
+        }
+        let package = match packages.iter().find(|x| x["id"] == node["id"]) {
+            Some(package) => package,
+            None => continue,
+        };
+        let name = format!(
+            "{} v{}",
+            package["name"].as_str().unwrap_or_default(),
+            package["version"].as_str().unwrap_or_default()
+        );
+        let enabled: Vec<&str> = node["features"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .collect();
+        for requested in get_requested_features(package, &enabled) {
+            let expanded = expand_feature(&thunk_features, &requested);
+            for feature in features.iter().filter(|x| expanded.contains(**x)) {
+                let enabler = match requested == *feature {
+                    true => name.clone(),
+                    false => format!("{} through \"{}\"", name, requested),
+                };
+                enablers.entry(feature).or_default().push(enabler);
+            }
+        }
+    }
+
+    let lines = features
+        .iter()
+        .map(|x| match enablers.get(x) {
+            Some(enablers) => format!("{}: enabled by {}", x, enablers.join(", ")),
+            None => format!("{}: enabled by an unknown package", x),
+        })
+        .collect();
+    Ok(lines)
+}
+
+/// Parse an OS, the error tells the names it takes.
+fn parse_os(os: &str) -> Result<OS, String> {
+    OS::parse(os).ok_or_else(|| format!("is not one of {}: {}", OS::NAMES, os))
+}
+
+/// The spellings of the subsystem thunk-cli understands.
+fn is_subsystem_windows(subsystem: &str) -> Option<bool> {
+    match subsystem.to_lowercase().as_str() {
+        "window" | "windows" | "win" | "w" | "gui" | "g" | "ui" | "u" => Some(true),
+        "console" | "command" | "line" | "c" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "on" | "yes" | "true" => Some(true),
+        "0" | "off" | "no" | "false" | "" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_metadata() {
+        let metadata: Table = r#"
+            os = "win7"
+            subsystem = "windows"
+            yy-thunks-version = "1.1.6"
+            icon = "app.ico"
+
+            [x86]
+            os = "xp"
+            "#
+        .parse()
+        .unwrap();
+
+        let mut config = Config::from_features();
+        config.apply_metadata(&metadata, "x86_64").unwrap();
+        assert_eq!(config.os, Some(OS::Windows7));
+        assert!(config.subsystem_windows);
+        assert_eq!(config.yy_thunks_version, "1.1.6");
+        assert_eq!(config.vc_ltl_version, VC_LTL_VERSION);
+        assert_eq!(config.get_vc_ltl_platform("x86_64"), Some("6.0.6000.0"));
+        assert_eq!(config.get_yy_thunks_platform(), Some("Win7"));
+
+        let mut config = Config::from_features();
+        config.apply_metadata(&metadata, "x86").unwrap();
+        assert_eq!(config.os, Some(OS::WindowsXP));
+        assert_eq!(config.get_vc_ltl_platform("x86"), Some("5.1.2600.0"));
+
+        let bad: Table = "lib = \"yes\"".parse().unwrap();
+        assert!(Config::from_features().apply_metadata(&bad, "x86").is_err());
+        let bad: Table = "[x86]\nos = \"win11\"".parse().unwrap();
+        assert_eq!(
+            Config::from_features().apply_metadata(&bad, "x86"),
+            Err(format!(
+                "os of [package.metadata.thunk.x86] is not one of {}: win11",
+                OS::NAMES
+            ))
+        );
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let vars = [
+            ("THUNK_OS", "win10"),
+            ("THUNK_OS_i686_pc_windows_msvc", "Windows XP"),
+            ("THUNK_SUBSYSTEM", "gui"),
+            ("THUNK_LIB_x86_64_pc_windows_msvc", "1"),
+        ];
+        let var = |name: &str| vars.iter().find(|x| x.0 == name).map(|x| x.1.to_owned());
+
+        let mut config = Config::from_features();
+        config.apply_env("i686-pc-windows-msvc", var).unwrap();
+        assert_eq!(config.os, Some(OS::WindowsXP));
+        assert!(config.os_is_set);
+        assert!(config.subsystem_windows);
+        assert!(!config.disable);
+
+        let mut config = Config::from_features();
+        config.apply_env("x86_64-pc-windows-msvc", var).unwrap();
+        assert_eq!(config.os, Some(OS::Windows10));
+        assert!(config.lib);
+
+        let bad = |name: &str| (name == "THUNK_DISABLE").then(|| "maybe".to_owned());
+        assert!(Config::from_features()
+            .apply_env("i686-pc-windows-msvc", bad)
+            .is_err());
+        let bad = |name: &str| (name == "THUNK_OS").then(|| "win01".to_owned());
+        assert_eq!(
+            Config::from_features().apply_env("i686-pc-windows-msvc", bad),
+            Err(format!("THUNK_OS is not one of {}: win01", OS::NAMES))
+        );
+    }
+
+    #[test]
+    fn test_get_conflicts() {
+        let mut config = Config::from_features();
+        config.os = Some(OS::WindowsVista);
+        config.os_features = vec!["vista", "win10_19041"];
+        config.vc_ltl_only = true;
+        let conflicts = config.get_conflicts();
+        assert_eq!(conflicts.len(), 2);
+        assert!(conflicts[0].contains("vista, win10_19041"));
+        assert_eq!(
+            config.get_conflict_features(),
+            ["vista", "win10_19041", "vc_ltl_only"]
+        );
+
+        config.os_is_set = true;
+        assert_eq!(config.get_conflicts().len(), 1);
+        assert_eq!(config.get_conflict_features(), ["vc_ltl_only"]);
+    }
+
+    #[test]
+    fn test_describe_enablers() {
+        let metadata = r#"{
+            "packages": [
+                {"name": "thunk-rs", "version": "0.3.5", "id": "thunk-rs 0.3.5",
+                 "features": {"default": ["xp"], "xp": [], "win7": [], "win8": [],
+                              "windows_xp": ["xp"]},
+                 "dependencies": []},
+                {"name": "sample", "version": "0.1.0", "id": "sample 0.1.0", "features": {},
+                 "dependencies": [{"name": "thunk-rs", "rename": null, "features": [],
+                                   "uses_default_features": true}]},
+                {"name": "app", "version": "0.1.0", "id": "app 0.1.0", "features": {},
+                 "dependencies": [{"name": "thunk-rs", "rename": "thunk", "features": ["win7"],
+                                   "uses_default_features": false}]},
+                {"name": "legacy", "version": "1.0.0", "id": "legacy 1.0.0",
+                 "features": {"old": ["thunk?/windows_xp"]},
+                 "dependencies": [{"name": "thunk-rs", "rename": "thunk", "features": [],
+                                   "uses_default_features": false}]}
+            ],
+            "resolve": {"nodes": [
+                {"id": "thunk-rs 0.3.5", "deps": [], "features": ["default", "win7", "xp"]},
+                {"id": "sample 0.1.0", "deps": [{"pkg": "thunk-rs 0.3.5"}], "features": []},
+                {"id": "app 0.1.0", "deps": [{"pkg": "thunk-rs 0.3.5"}], "features": []},
+                {"id": "legacy 1.0.0", "deps": [{"pkg": "thunk-rs 0.3.5"}], "features": ["old"]}
+            ]}
+        }"#;
+        assert_eq!(
+            describe_enablers(metadata, "0.3.5", &["xp", "win7", "win8"]).unwrap(),
+            [
+                "xp: enabled by sample v0.1.0 through \"default\", legacy v1.0.0 through \"windows_xp\"",
+                "win7: enabled by app v0.1.0",
+                "win8: enabled by an unknown package",
+            ]
+        );
+        assert!(describe_enablers(metadata, "0.4.0", &["xp"]).is_err());
+        assert!(describe_enablers("error: offline", "0.3.5", &["xp"]).is_err());
+    }
+}
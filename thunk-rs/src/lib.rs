@@ -1,9 +1,23 @@
 #![doc = include_str!("../README.md")]
 
-use std::{env, path::PathBuf, process::Command};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
 
-const VC_LTL_VERSION: &'static str = "5.2.2";
-const YY_THUNKS_VERSION: &'static str = "1.1.7";
+mod config;
+pub mod manifest;
+mod os;
+pub mod res;
+pub mod version;
+
+pub use os::OS;
+
+use config::{Config, OsConflict};
+use manifest::Manifest;
+use res::ResourceFile;
+use version::{PackageInfo, VersionInfo};
 
 /// This function should be call in build.rs.
 pub fn thunk() {
@@ -18,27 +32,54 @@ pub fn thunk() {
     let target_arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
 
+    // The environment over [package.metadata.thunk] over the features
+    let metadata = read_metadata().unwrap_or_default();
+    let mut config = Config::from_features();
+    config
+        .apply_metadata(&metadata, &target_arch)
+        .unwrap_or_else(|e| panic!("{}", e));
+    let target = env::var("TARGET").unwrap();
+    config
+        .apply_env(&target, |name| {
+            println!("cargo::rerun-if-env-changed={}", name);
+            env::var(name).ok()
+        })
+        .unwrap_or_else(|e| panic!("{}", e));
+    if config.disable {
+        println!("cargo::warning=Thunk Disabled: THUNK_DISABLE is set!");
+        return;
+    }
+
+    // Feature unification may enable several OS features, tell which packages enable them
+    let conflicts = config.get_conflicts();
+    if !conflicts.is_empty() {
+        for line in &conflicts {
+            println!("cargo::warning=Feature Conflict: {}", line);
+        }
+        match get_feature_enablers(&config.get_conflict_features()) {
+            Ok(lines) => {
+                for line in lines {
+                    println!("cargo::warning=Feature Conflict: {}", line);
+                }
+            }
+            Err(e) => println!(
+                "cargo::warning=Feature Conflict: can not tell which packages enable the features: {}",
+                e
+            ),
+        }
+        if config.os_conflict == OsConflict::Error {
+            panic!("Conflicting features of thunk-rs, see the warnings above!");
+        }
+    }
+
     // Enable VC-LTL5
     let vc_ltl_arch = if target_arch == "x86" { "Win32" } else { "x64" };
-    let vc_ltl_platform = if cfg!(feature = "xp") {
-        if vc_ltl_arch == "Win32" {
-            "5.1.2600.0"
-        } else {
-            "5.2.3790.0"
+    let vc_ltl_platform = match config.get_vc_ltl_platform(&target_arch) {
+        Some(platform) => platform,
+        None => {
+            println!("cargo::warning=VC-LTL5 Skipped: Nothing to do!");
+            return;
         }
-    } else if cfg!(feature = "vista") || cfg!(feature = "win7") {
-        "6.0.6000.0"
-    } else if cfg!(feature = "win8") {
-        "6.2.9200.0"
-    } else if cfg!(feature = "win10_10240") {
-        "10.0.10240.0"
-    } else if cfg!(feature = "win10_19041") {
-        "10.0.19041.0"
-    } else if cfg!(feature = "vc_ltl_only") {
-        "6.0.6000.0"
-    } else {
-        println!("cargo::warning=VC-LTL5 Skipped: Nothing to do!");
-        return;
     };
 
     let vc_ltl = get_or_download(
@@ -46,40 +87,63 @@ pub fn thunk() {
         "VC_LTL_URL",
         &format!(
             "https://github.com/Chuyu-Team/VC-LTL5/releases/download/v{}/VC-LTL-Binary.7z",
-            VC_LTL_VERSION
+            config.vc_ltl_version
         ),
         &out_dir,
-        &format!("VC-LTL-{}", VC_LTL_VERSION),
+        &format!("VC-LTL-{}", config.vc_ltl_version),
     );
 
-    let vc_ltl_path = vc_ltl.join(&format!(
+    let vc_ltl_path = vc_ltl.join(format!(
         "TargetPlatform/{}/lib/{}",
         vc_ltl_platform, vc_ltl_arch
     ));
 
     println!("cargo::rustc-link-search={}", vc_ltl_path.to_string_lossy());
-    println!(
-        "cargo::warning=VC-LTL5 Enabled: {}({})",
-        vc_ltl_platform, vc_ltl_arch
-    );
+
+    // The build script runs before linking, so only warn about what keeps VC-LTL from taking effect
+    let target_features = env::var("CARGO_CFG_TARGET_FEATURE").unwrap_or_default();
+    if !vc_ltl_path.join("msvcrt.lib").is_file() {
+        println!(
+            "cargo::warning=VC-LTL5 Not Effective: {} has no msvcrt.lib!",
+            vc_ltl_path.to_string_lossy()
+        );
+    } else if target_features.split(',').any(|x| x == "crt-static") {
+        println!("cargo::warning=VC-LTL5 Not Effective: crt-static links the static CRT instead!");
+    } else {
+        println!(
+            "cargo::warning=VC-LTL5 Enabled: {}({})",
+            vc_ltl_platform, vc_ltl_arch
+        );
+    }
+
+    // Cross compile from a non-Windows host, VC-LTL must stay in front of the sysroot
+    let host = env::var("HOST").unwrap();
+    if !host.contains("windows") {
+        println!("cargo::rerun-if-env-changed=THUNK_SYSROOT");
+        let sysroot = env::var("THUNK_SYSROOT")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| panic!("THUNK_SYSROOT is needed to cross compile from {}!", host));
+
+        for lib in ["crt/lib", "sdk/lib/um", "sdk/lib/ucrt"] {
+            let lib_path = sysroot.join(lib).join(&target_arch);
+            if !lib_path.is_dir() {
+                panic!(
+                    "Windows sysroot is missing {}, expected an xwin style crt and sdk tree!",
+                    lib_path.to_string_lossy()
+                );
+            }
+            println!("cargo::rustc-link-search={}", lib_path.to_string_lossy());
+        }
+    }
 
     // Enable YY-Thunks
     let yy_thunks_arch = if target_arch == "x86" { "x86" } else { "x64" };
-    let yy_thunks_platform = if cfg!(feature = "xp") {
-        "WinXP"
-    } else if cfg!(feature = "vista") {
-        "Vista"
-    } else if cfg!(feature = "win7") {
-        "Win7"
-    } else if cfg!(feature = "win8") {
-        "Win8"
-    } else if cfg!(feature = "win10_10240") {
-        "Win10.0.10240"
-    } else if cfg!(feature = "win10_19041") {
-        "Win10.0.19041"
-    } else {
-        println!("cargo::warning=YY-Thunks Skipped: Nothing to do!!");
-        return;
+    let yy_thunks_platform = match config.get_yy_thunks_platform() {
+        Some(platform) => platform,
+        None => {
+            println!("cargo::warning=YY-Thunks Skipped: Nothing to do!!");
+            return;
+        }
     };
 
     let yy_thunks = get_or_download(
@@ -87,31 +151,64 @@ pub fn thunk() {
         "YY_THUNKS_URL",
         &format!(
             "https://github.com/Chuyu-Team/YY-Thunks/releases/download/v{}/YY-Thunks-Objs.zip",
-            YY_THUNKS_VERSION
+            config.yy_thunks_version
         ),
         &out_dir,
-        &format!("YY-Thunks-{}", YY_THUNKS_VERSION),
+        &format!("YY-Thunks-{}", config.yy_thunks_version),
     );
 
-    let yy_thunks = yy_thunks.join(format!(
+    let yy_thunks_obj = yy_thunks.join(format!(
         "objs/{}/YY_Thunks_for_{}.obj",
         yy_thunks_arch, yy_thunks_platform
     ));
 
-    println!("cargo::rustc-link-arg={}", yy_thunks.to_string_lossy());
-    println!(
-        "cargo::warning=YY-Thunks Enabled: {}({})",
-        yy_thunks_platform, yy_thunks_arch
-    );
+    println!("cargo::rustc-link-arg={}", yy_thunks_obj.to_string_lossy());
+    if yy_thunks_obj.is_file() {
+        println!(
+            "cargo::warning=YY-Thunks Enabled: {}({})",
+            yy_thunks_platform, yy_thunks_arch
+        );
+    } else {
+        println!(
+            "cargo::warning=YY-Thunks Not Effective: {} is not found!",
+            yy_thunks_obj.to_string_lossy()
+        );
+    }
+
+    // Embed the manifest for the OS, the version information and the icon
+    embed_resources(&config, &metadata, &out_dir);
+
+    // Link with the timestamps and PDB GUID as hashes of the content. This is not a reproducible
+    // build: the paths rustc embeds are not remapped, as build scripts can not pass rustc flags,
+    // so only tell which paths to remap.
+    if cfg!(feature = "brepro") {
+        println!("cargo::rustc-link-arg=/Brepro");
+        println!("cargo::rustc-link-arg=/PDBALTPATH:%_PDB%");
+        let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let cargo_home = env::var("CARGO_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| {
+                env::var(if cfg!(windows) { "USERPROFILE" } else { "HOME" })
+                    .map(|x| PathBuf::from(x).join(".cargo"))
+            })
+            .unwrap_or_default();
+        println!(
+            "cargo::warning=Brepro Linking Enabled: paths are not remapped, add to RUSTFLAGS --remap-path-prefix={}=/build --remap-path-prefix={}=/cargo --remap-path-prefix={}=/VC-LTL --remap-path-prefix={}=/YY-Thunks",
+            manifest_dir,
+            cargo_home.to_string_lossy(),
+            vc_ltl.to_string_lossy(),
+            yy_thunks.to_string_lossy()
+        );
+    }
 
     // Return if is lib mode
-    if cfg!(feature = "lib") {
+    if config.lib {
         println!("cargo::warning=Lib Mode Enabled!");
         return;
     }
 
     // Set subsystem to windows
-    let os_version = if cfg!(feature = "xp") {
+    let os_version = if config.os == Some(OS::WindowsXP) {
         if target_arch == "x86" {
             ",5.01"
         } else {
@@ -121,7 +218,7 @@ pub fn thunk() {
         ""
     };
 
-    if cfg!(feature = "subsystem_windows") && env::var("PROFILE").unwrap() != "debug" {
+    if config.subsystem_windows && env::var("PROFILE").unwrap() != "debug" {
         println!("cargo::rustc-link-arg=/SUBSYSTEM:WINDOWS{}", os_version);
         println!("cargo::rustc-link-arg=/ENTRY:mainCRTStartup");
         println!("cargo::warning=Subsystem is set to WINDOWS");
@@ -130,6 +227,107 @@ pub fn thunk() {
     }
 }
 
+/// Read `[package.metadata.thunk]` of the package being built.
+fn read_metadata() -> Option<toml::Table> {
+    let manifest = PathBuf::from(env::var("CARGO_MANIFEST_DIR").ok()?).join("Cargo.toml");
+    println!("cargo::rerun-if-changed={}", manifest.to_string_lossy());
+    let text = fs::read_to_string(&manifest).ok()?;
+    let mut table: toml::Table = text
+        .parse()
+        .unwrap_or_else(|e| panic!("Failed to parse {}: {}", manifest.to_string_lossy(), e));
+    match table.remove("package")?.get("metadata")?.get("thunk")? {
+        toml::Value::Table(metadata) => Some(metadata.clone()),
+        _ => panic!("[package.metadata.thunk] is not a table"),
+    }
+}
+
+/// Tell which packages enable the features of thunk-rs, from `cargo metadata` of the package.
+fn get_feature_enablers(features: &[&str]) -> Result<Vec<String>, String> {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
+    let manifest = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.toml");
+    let output = Command::new(cargo)
+        .args(["metadata", "--format-version", "1", "--offline"])
+        .arg("--manifest-path")
+        .arg(manifest)
+        .output()
+        .map_err(|e| format!("failed to run cargo metadata: {}", e))?;
+    if !output.status.success() {
+        // A warning is a single line
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr: Vec<&str> = stderr.split_whitespace().collect();
+        return Err(format!("cargo metadata failed: {}", stderr.join(" ")));
+    }
+    config::describe_enablers(
+        &String::from_utf8_lossy(&output.stdout),
+        env!("CARGO_PKG_VERSION"),
+        features,
+    )
+}
+
+/// Compile the manifest, the version information and the icon into a `.res` file and link it.
+fn embed_resources(config: &Config, metadata: &toml::Table, out_dir: &Path) {
+    let is_lib = config.lib;
+    let mut res = ResourceFile::new();
+
+    if let (true, Some(os)) = (cfg!(feature = "manifest"), config.os) {
+        let mut manifest = Manifest::new(os);
+
+        // Manifest fragments to merge, separated like PATH
+        println!("cargo::rerun-if-env-changed=THUNK_MANIFEST");
+        if let Some(paths) = env::var_os("THUNK_MANIFEST") {
+            for path in env::split_paths(&paths) {
+                println!("cargo::rerun-if-changed={}", path.to_string_lossy());
+                let xml = fs::read_to_string(&path).unwrap_or_else(|e| {
+                    panic!("Failed to read manifest {}: {}", path.to_string_lossy(), e)
+                });
+                manifest.add_fragment(&xml);
+            }
+        }
+
+        manifest.add_to(&mut res, is_lib);
+        println!("cargo::warning=Manifest Embedded: Windows {}", os);
+    }
+
+    if cfg!(feature = "version_info") {
+        if let Some(package) = PackageInfo::from_env() {
+            let mut version_info = VersionInfo::from_package(&package, is_lib);
+            version_info
+                .apply_metadata(metadata)
+                .unwrap_or_else(|e| panic!("{}", e));
+            version_info.add_to(&mut res);
+        }
+    }
+
+    // The icon is relative to the package
+    if let Some(icon) = metadata.get("icon") {
+        let icon = icon
+            .as_str()
+            .expect("icon of [package.metadata.thunk] is not a string");
+        let path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join(icon);
+        println!("cargo::rerun-if-changed={}", path.to_string_lossy());
+        let ico = fs::read(&path)
+            .unwrap_or_else(|e| panic!("Failed to read icon {}: {}", path.to_string_lossy(), e));
+        res.add_icon(1, &ico)
+            .unwrap_or_else(|e| panic!("Failed to add icon {}: {}", path.to_string_lossy(), e));
+    }
+
+    if res.is_empty() {
+        return;
+    }
+    let res_path = out_dir.join("thunk.res");
+    res.save(&res_path)
+        .unwrap_or_else(|e| panic!("Failed to write {}: {}", res_path.to_string_lossy(), e));
+    println!("cargo::rustc-link-arg={}", res_path.to_string_lossy());
+    // The linker must not add a manifest of its own.
+    if res
+        .resources
+        .iter()
+        .any(|x| x.kind == res::RT_MANIFEST.into())
+    {
+        println!("cargo::rustc-link-arg=/MANIFEST:NO");
+    }
+}
+
 fn get_or_download(
     env_path: &str,
     env_url: &str,
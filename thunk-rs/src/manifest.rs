@@ -0,0 +1,250 @@
+//! Application manifests for the target OS.
+
+use std::{fmt, str::FromStr};
+
+use crate::res::{ResourceFile, RT_MANIFEST};
+use crate::OS;
+
+/// The manifest resource ID of an executable.
+pub const CREATEPROCESS_MANIFEST_RESOURCE_ID: u16 = 1;
+/// The manifest resource ID of a DLL.
+pub const ISOLATIONAWARE_MANIFEST_RESOURCE_ID: u16 = 2;
+
+/// `supportedOS` IDs of the compatibility section, from the oldest.
+const SUPPORTED_OS: &[(OS, &str, &str)] = &[
+    (
+        OS::WindowsVista,
+        "{e2011457-1546-43c5-a5fe-008deee3d3f0}",
+        "Windows Vista",
+    ),
+    (
+        OS::Windows7,
+        "{35138b9a-5d96-4fbd-8e2d-a2440225f93a}",
+        "Windows 7",
+    ),
+    (
+        OS::Windows8,
+        "{4a2f28e3-53b9-4441-ba9c-d69d4a4a6e38}",
+        "Windows 8",
+    ),
+    (
+        OS::Windows8,
+        "{1f676c76-80e1-4239-95bb-83d0f6d0da78}",
+        "Windows 8.1",
+    ),
+    (
+        OS::Windows10,
+        "{8e0f7a12-bfb3-4fe8-b9a5-48fd50a15a9a}",
+        "Windows 10 and 11",
+    ),
+];
+
+/// The `requestedExecutionLevel` of the manifest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExecutionLevel {
+    #[default]
+    AsInvoker,
+    HighestAvailable,
+    RequireAdministrator,
+}
+
+impl fmt::Display for ExecutionLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            ExecutionLevel::AsInvoker => "asInvoker",
+            ExecutionLevel::HighestAvailable => "highestAvailable",
+            ExecutionLevel::RequireAdministrator => "requireAdministrator",
+        };
+        f.write_str(text)
+    }
+}
+
+impl FromStr for ExecutionLevel {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.to_ascii_lowercase().as_str() {
+            "asinvoker" => Ok(ExecutionLevel::AsInvoker),
+            "highestavailable" => Ok(ExecutionLevel::HighestAvailable),
+            "requireadministrator" => Ok(ExecutionLevel::RequireAdministrator),
+            _ => Err(format!(
+                "Unknown execution level {}, expected asInvoker, highestAvailable or requireAdministrator",
+                text
+            )),
+        }
+    }
+}
+
+/// An application manifest for the target OS.
+///
+/// Elements the loader of the OS rejects are left out: Windows XP gets neither the compatibility
+/// section nor the window settings, and its `trustInfo` uses the `asm.v2` namespace because XP
+/// before SP3 fails to load images with the `asm.v3` one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub os: OS,
+    pub execution_level: ExecutionLevel,
+    /// Depend on Common Controls v6 for visual styles.
+    pub common_controls: bool,
+    pub dpi_aware: bool,
+    /// User XML merged into the `assembly` element, a fragment replaces the generated element of
+    /// the same name (`windowsSettings` for the window settings).
+    pub fragments: Vec<String>,
+}
+
+impl Manifest {
+    pub fn new(os: OS) -> Self {
+        Self {
+            os,
+            execution_level: ExecutionLevel::default(),
+            common_controls: true,
+            dpi_aware: true,
+            fragments: Vec::new(),
+        }
+    }
+
+    /// Merge a user fragment, either elements of `assembly` or a whole manifest.
+    pub fn add_fragment(&mut self, xml: &str) {
+        let mut xml = xml.trim();
+        if let Some(end) = xml.strip_prefix("<?xml").and_then(|x| x.find("?>")) {
+            xml = xml[5 + end + 2..].trim();
+        }
+        if let (Some(start), Some(end)) = (xml.find("<assembly"), xml.rfind("</assembly>")) {
+            if let Some(open_end) = xml[start..].find('>') {
+                xml = xml[start + open_end + 1..end].trim();
+            }
+        }
+        self.fragments.push(xml.to_owned());
+    }
+
+    /// Whether a fragment has a top level element, such as `trustInfo`.
+    fn has_fragment(&self, element: &str) -> bool {
+        let open = format!("<{}", element);
+        self.fragments.iter().any(|fragment| {
+            fragment.match_indices(&open).any(|(index, _)| {
+                let next = fragment[index + open.len()..].chars().next();
+                matches!(next, Some(' ' | '>' | '/' | '\t' | '\r' | '\n'))
+            })
+        })
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::new();
+        xml += "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n";
+        xml += "<assembly xmlns=\"urn:schemas-microsoft-com:asm.v1\" manifestVersion=\"1.0\">\n";
+
+        if self.common_controls && !self.has_fragment("dependency") {
+            xml += r#"  <dependency>
+    <dependentAssembly>
+      <assemblyIdentity type="win32" name="Microsoft.Windows.Common-Controls" version="6.0.0.0" processorArchitecture="*" publicKeyToken="6595b64144ccf1df" language="*"/>
+    </dependentAssembly>
+  </dependency>
+"#;
+        }
+
+        if !self.has_fragment("trustInfo") {
+            let namespace = match self.os {
+                OS::WindowsXP => "urn:schemas-microsoft-com:asm.v2",
+                _ => "urn:schemas-microsoft-com:asm.v3",
+            };
+            xml += &format!(
+                r#"  <trustInfo xmlns="{}">
+    <security>
+      <requestedPrivileges>
+        <requestedExecutionLevel level="{}" uiAccess="false"/>
+      </requestedPrivileges>
+    </security>
+  </trustInfo>
+"#,
+                namespace, self.execution_level
+            );
+        }
+
+        if self.os > OS::WindowsXP && !self.has_fragment("compatibility") {
+            xml += "  <compatibility xmlns=\"urn:schemas-microsoft-com:compatibility.v1\">\n";
+            xml += "    <application>\n";
+            for (_, id, name) in SUPPORTED_OS.iter().filter(|(os, _, _)| *os >= self.os) {
+                xml += &format!("      <!-- {} -->\n", name);
+                xml += &format!("      <supportedOS Id=\"{}\"/>\n", id);
+            }
+            xml += "    </application>\n";
+            xml += "  </compatibility>\n";
+        }
+
+        if self.os > OS::WindowsXP && self.dpi_aware && !self.has_fragment("windowsSettings") {
+            // Per-monitor awareness needs Windows 8.1, V2 needs Windows 10 1703.
+            let settings = match self.os {
+                OS::Windows10 | OS::Windows10_20H1 => {
+                    r#"      <dpiAware xmlns="http://schemas.microsoft.com/SMI/2005/WindowsSettings">true/pm</dpiAware>
+      <dpiAwareness xmlns="http://schemas.microsoft.com/SMI/2016/WindowsSettings">PerMonitorV2, PerMonitor</dpiAwareness>
+"#
+                }
+                _ => {
+                    r#"      <dpiAware xmlns="http://schemas.microsoft.com/SMI/2005/WindowsSettings">true</dpiAware>
+"#
+                }
+            };
+            xml += "  <application xmlns=\"urn:schemas-microsoft-com:asm.v3\">\n";
+            xml += "    <windowsSettings>\n";
+            xml += settings;
+            xml += "    </windowsSettings>\n";
+            xml += "  </application>\n";
+        }
+
+        for fragment in &self.fragments {
+            for line in fragment.lines() {
+                xml += &format!("  {}\n", line);
+            }
+        }
+
+        xml += "</assembly>\n";
+        xml
+    }
+
+    /// Add the manifest to a resource file, with the resource ID of an executable or a DLL.
+    pub fn add_to(&self, res: &mut ResourceFile, is_lib: bool) {
+        let id = match is_lib {
+            true => ISOLATIONAWARE_MANIFEST_RESOURCE_ID,
+            false => CREATEPROCESS_MANIFEST_RESOURCE_ID,
+        };
+        res.add(RT_MANIFEST, id, self.to_xml().into_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_for_xp() {
+        let xml = Manifest::new(OS::WindowsXP).to_xml();
+        assert!(xml.contains("Microsoft.Windows.Common-Controls"));
+        assert!(xml.contains("<trustInfo xmlns=\"urn:schemas-microsoft-com:asm.v2\">"));
+        assert!(!xml.contains("compatibility"));
+        assert!(!xml.contains("dpiAware"));
+    }
+
+    #[test]
+    fn test_manifest_for_win7() {
+        let xml = Manifest::new(OS::Windows7).to_xml();
+        assert_eq!(xml.matches("<supportedOS").count(), 4);
+        assert!(!xml.contains("{e2011457-1546-43c5-a5fe-008deee3d3f0}"));
+        assert!(xml.contains(">true</dpiAware>"));
+    }
+
+    #[test]
+    fn test_manifest_fragment() {
+        let mut manifest = Manifest::new(OS::Windows10);
+        manifest.add_fragment(
+            r#"<?xml version="1.0"?>
+<assembly xmlns="urn:schemas-microsoft-com:asm.v1" manifestVersion="1.0">
+<trustInfo xmlns="urn:schemas-microsoft-com:asm.v3"><security><requestedPrivileges><requestedExecutionLevel level="requireAdministrator"/></requestedPrivileges></security></trustInfo>
+</assembly>"#,
+        );
+        let xml = manifest.to_xml();
+        assert_eq!(xml.matches("<trustInfo").count(), 1);
+        assert!(xml.contains("requireAdministrator"));
+        assert_eq!(xml.matches("<?xml").count(), 1);
+        assert!(xml.contains("PerMonitorV2"));
+    }
+}
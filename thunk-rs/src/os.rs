@@ -0,0 +1,61 @@
+use std::fmt;
+
+/// A Windows version Thunk builds for, from the oldest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OS {
+    WindowsXP,
+    WindowsVista,
+    Windows7,
+    Windows8,
+    Windows10,
+    Windows10_20H1,
+}
+
+impl OS {
+    /// The usual names of the OSes, for messages about a name that is none of them.
+    pub const NAMES: &'static str = "xp, vista, win7, win8, win10, 20h1";
+
+    /// Parse the name or version of an OS, `None` if it is none of them.
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.to_lowercase().as_str() {
+            "windows xp" | "winxp" | "xp" | "5.1" | "5.2" | "2600" | "3790" | "2003" => {
+                Some(OS::WindowsXP)
+            }
+            "windows vista" | "winvista" | "vista" | "6.0" | "6000" | "2008" => {
+                Some(OS::WindowsVista)
+            }
+            "windows 7" | "win7" | "7" | "6.1" | "7600" | "2008r2" => Some(OS::Windows7),
+            "windows 8" | "win8" | "8" | "6.2" | "9200" | "2012" => Some(OS::Windows8),
+            "windows 10" | "win10" | "10" | "10240" | "2016" | "2019" => Some(OS::Windows10),
+            "windows 10 20h1" | "win10 20h1" | "10_20h1" | "20h1" | "19041" | "2020" => {
+                Some(OS::Windows10_20H1)
+            }
+            _ => None,
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(text: &str) -> Self {
+        Self::parse(text).unwrap_or(OS::WindowsXP)
+    }
+}
+
+impl fmt::Display for OS {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            OS::WindowsXP => "XP",
+            OS::WindowsVista => "Vista",
+            OS::Windows7 => "7",
+            OS::Windows8 => "8",
+            OS::Windows10 => "10",
+            OS::Windows10_20H1 => "10_20h1",
+        };
+        f.write_str(text)
+    }
+}
+
+impl From<String> for OS {
+    fn from(value: String) -> Self {
+        Self::from_str(&value)
+    }
+}
@@ -0,0 +1,230 @@
+//! A writer of compiled resource (`.res`) files, which both `link.exe` and `lld-link` take as
+//! input, so no resource compiler is needed.
+
+use std::{fs, io, path::Path};
+
+pub const RT_ICON: u16 = 3;
+pub const RT_GROUP_ICON: u16 = 14;
+pub const RT_VERSION: u16 = 16;
+pub const RT_MANIFEST: u16 = 24;
+
+/// English (United States), the language resource compilers default to.
+pub const LANG_EN_US: u16 = 0x0409;
+
+// MOVEABLE | PURE | DISCARDABLE
+const MEMORY_FLAGS: u16 = 0x1030;
+
+/// The type or name of a resource.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResId {
+    Id(u16),
+    Name(String),
+}
+
+impl From<u16> for ResId {
+    fn from(value: u16) -> Self {
+        ResId::Id(value)
+    }
+}
+
+impl From<&str> for ResId {
+    fn from(value: &str) -> Self {
+        ResId::Name(value.to_uppercase())
+    }
+}
+
+/// A resource of a `.res` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resource {
+    pub kind: ResId,
+    pub name: ResId,
+    pub language: u16,
+    pub data: Vec<u8>,
+}
+
+/// A `.res` file being built.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceFile {
+    pub resources: Vec<Resource>,
+}
+
+impl ResourceFile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, kind: impl Into<ResId>, name: impl Into<ResId>, data: Vec<u8>) {
+        self.resources.push(Resource {
+            kind: kind.into(),
+            name: name.into(),
+            language: LANG_EN_US,
+            data,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.resources.is_empty()
+    }
+
+    /// Get the bytes of the `.res` file, which starts with an empty resource as its signature.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_resource(&mut bytes, &ResId::Id(0), &ResId::Id(0), 0, 0, &[]);
+        for resource in &self.resources {
+            write_resource(
+                &mut bytes,
+                &resource.kind,
+                &resource.name,
+                MEMORY_FLAGS,
+                resource.language,
+                &resource.data,
+            );
+        }
+        bytes
+    }
+
+    /// Add the images of an `.ico` file as `RT_ICON` resources, grouped under `name` as an
+    /// `RT_GROUP_ICON`, which is the icon Explorer shows when it has the lowest name.
+    pub fn add_icon(&mut self, name: impl Into<ResId>, ico: &[u8]) -> Result<(), String> {
+        let read_u16 = |offset: usize| {
+            ico.get(offset..offset + 2)
+                .map(|x| u16::from_le_bytes([x[0], x[1]]))
+        };
+        let read_u32 = |offset: usize| {
+            ico.get(offset..offset + 4)
+                .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        };
+        if read_u16(0) != Some(0) || read_u16(2) != Some(1) {
+            return Err("Not an icon file".to_owned());
+        }
+        let count = read_u16(4).ok_or("Truncated icon header")?;
+
+        // Read every image first, so that a bad icon adds nothing.
+        let mut images = Vec::new();
+        for index in 0..count as usize {
+            // ICONDIRENTRY is 16 bytes, the group entry replaces its offset by the ID.
+            let entry = 6 + index * 16;
+            let (size, offset) = match (read_u32(entry + 8), read_u32(entry + 12)) {
+                (Some(size), Some(offset)) => (size as usize, offset as usize),
+                _ => return Err("Truncated icon directory".to_owned()),
+            };
+            let image = offset
+                .checked_add(size)
+                .and_then(|end| ico.get(offset..end))
+                .ok_or("Truncated icon image")?;
+            images.push((&ico[entry..entry + 12], image));
+        }
+
+        // The first IDs not taken by another icon
+        let first_id = self
+            .resources
+            .iter()
+            .filter(|x| x.kind == ResId::Id(RT_ICON))
+            .filter_map(|x| match x.name {
+                ResId::Id(id) => Some(id),
+                ResId::Name(_) => None,
+            })
+            .max()
+            .unwrap_or_default()
+            + 1;
+
+        let mut group = ico[..6].to_vec();
+        for (id, (entry, image)) in (first_id..).zip(images) {
+            self.add(RT_ICON, id, image.to_vec());
+            group.extend(entry);
+            group.extend(id.to_le_bytes());
+        }
+        self.add(RT_GROUP_ICON, name, group);
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+}
+
+fn write_id(bytes: &mut Vec<u8>, id: &ResId) {
+    match id {
+        ResId::Id(id) => {
+            bytes.extend(0xffffu16.to_le_bytes());
+            bytes.extend(id.to_le_bytes());
+        }
+        ResId::Name(name) => {
+            for unit in name.encode_utf16().chain([0]) {
+                bytes.extend(unit.to_le_bytes());
+            }
+        }
+    }
+}
+
+fn align_dword(bytes: &mut Vec<u8>) {
+    while bytes.len() % 4 != 0 {
+        bytes.push(0);
+    }
+}
+
+fn write_resource(
+    bytes: &mut Vec<u8>,
+    kind: &ResId,
+    name: &ResId,
+    memory_flags: u16,
+    language: u16,
+    data: &[u8],
+) {
+    let mut header = Vec::new();
+    write_id(&mut header, kind);
+    write_id(&mut header, name);
+    align_dword(&mut header);
+    header.extend(0u32.to_le_bytes()); // DataVersion
+    header.extend(memory_flags.to_le_bytes());
+    header.extend(language.to_le_bytes());
+    header.extend(0u32.to_le_bytes()); // Version
+    header.extend(0u32.to_le_bytes()); // Characteristics
+
+    // DataSize and HeaderSize come first and count in the header size.
+    bytes.extend((data.len() as u32).to_le_bytes());
+    bytes.extend((header.len() as u32 + 8).to_le_bytes());
+    bytes.extend(header);
+    bytes.extend(data);
+    align_dword(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_bytes() {
+        let mut res = ResourceFile::new();
+        res.add(RT_MANIFEST, 1, b"<a/>".to_vec());
+        let bytes = res.to_bytes();
+
+        // The empty signature resource
+        assert_eq!(&bytes[..8], &[0, 0, 0, 0, 32, 0, 0, 0]);
+        assert_eq!(&bytes[8..16], &[0xff, 0xff, 0, 0, 0xff, 0xff, 0, 0]);
+        // Then the manifest with the same header size
+        assert_eq!(&bytes[32..40], &[4, 0, 0, 0, 32, 0, 0, 0]);
+        assert_eq!(&bytes[40..48], &[0xff, 0xff, 24, 0, 0xff, 0xff, 1, 0]);
+        assert_eq!(&bytes[64..], b"<a/>");
+    }
+
+    #[test]
+    fn test_add_icon() {
+        // One 1x1 image of 4 bytes
+        let mut ico = vec![0, 0, 1, 0, 1, 0];
+        ico.extend([1, 1, 0, 0, 1, 0, 32, 0, 4, 0, 0, 0, 22, 0, 0, 0]);
+        ico.extend([1, 2, 3, 4]);
+
+        let mut res = ResourceFile::new();
+        res.add_icon(1, &ico).unwrap();
+        assert_eq!(res.resources[0].kind, ResId::Id(RT_ICON));
+        assert_eq!(res.resources[0].data, [1, 2, 3, 4]);
+        assert_eq!(res.resources[1].kind, ResId::Id(RT_GROUP_ICON));
+        assert_eq!(res.resources[1].data[6..18], ico[6..18]);
+        assert_eq!(res.resources[1].data[18..], [1, 0]);
+        assert!(res.add_icon(2, &ico[..10]).is_err());
+        assert!(res.add_icon(2, &ico[..5]).is_err());
+        assert!(res.add_icon(2, &ico[..24]).is_err());
+        assert_eq!(res.resources.len(), 2);
+    }
+}
@@ -0,0 +1,305 @@
+//! `VERSIONINFO` resources from the package fields, which Explorer shows as the file properties.
+
+use toml::Table;
+
+use crate::res::{ResourceFile, RT_VERSION};
+
+/// The resource ID of the version information.
+pub const VS_VERSION_INFO: u16 = 1;
+
+const VS_FFI_SIGNATURE: u32 = 0xfeef04bd;
+const VS_FFI_STRUCVERSION: u32 = 0x00010000;
+const VS_FFI_FILEFLAGSMASK: u32 = 0x3f;
+const VOS_NT_WINDOWS32: u32 = 0x00040004;
+const VFT_APP: u32 = 1;
+const VFT_DLL: u32 = 2;
+
+/// English (United States) in Unicode, as `040904b0` of the string table.
+const TRANSLATION: (u16, u16) = (0x0409, 1200);
+
+/// The package fields the version information is generated from, as in `Cargo.toml`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackageInfo {
+    pub name: String,
+    pub version: String,
+    pub description: Option<String>,
+    pub authors: Vec<String>,
+}
+
+impl PackageInfo {
+    /// Get the package fields cargo passes to build scripts.
+    pub fn from_env() -> Option<Self> {
+        let name = std::env::var("CARGO_PKG_NAME").ok()?;
+        let version = std::env::var("CARGO_PKG_VERSION").ok()?;
+        let description = std::env::var("CARGO_PKG_DESCRIPTION")
+            .ok()
+            .filter(|x| !x.is_empty());
+        let authors = std::env::var("CARGO_PKG_AUTHORS")
+            .unwrap_or_default()
+            .split(':')
+            .filter(|x| !x.is_empty())
+            .map(str::to_owned)
+            .collect();
+        Some(Self {
+            name,
+            version,
+            description,
+            authors,
+        })
+    }
+}
+
+/// A `VERSIONINFO` resource.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionInfo {
+    pub file_version: [u16; 4],
+    pub product_version: [u16; 4],
+    pub is_lib: bool,
+    /// The entries of the string table, such as `CompanyName`, in order.
+    pub strings: Vec<(String, String)>,
+}
+
+impl VersionInfo {
+    /// Generate the version information of a package.
+    ///
+    /// `CompanyName` is the authors without their emails, `FileDescription` falls back to the
+    /// name as Explorer shows it in place of the file name.
+    pub fn from_package(package: &PackageInfo, is_lib: bool) -> Self {
+        let version = parse_version(&package.version).unwrap_or_default();
+        let mut info = Self {
+            file_version: version,
+            product_version: version,
+            is_lib,
+            strings: Vec::new(),
+        };
+
+        let authors: Vec<&str> = package
+            .authors
+            .iter()
+            .map(|x| x.split('<').next().unwrap_or_default().trim())
+            .filter(|x| !x.is_empty())
+            .collect();
+        if !authors.is_empty() {
+            info.set("CompanyName", &authors.join(", "));
+        }
+        let description = package.description.as_deref().unwrap_or(&package.name);
+        info.set("FileDescription", description);
+        info.set("FileVersion", &package.version);
+        info.set("InternalName", &package.name);
+        // rustc names DLLs after the crate, with underscores.
+        let original_filename = match is_lib {
+            true => format!("{}.dll", package.name.replace('-', "_")),
+            false => format!("{}.exe", package.name),
+        };
+        info.set("OriginalFilename", &original_filename);
+        info.set("ProductName", &package.name);
+        info.set("ProductVersion", &package.version);
+        info
+    }
+
+    /// Set an entry of the string table, `FileVersion` and `ProductVersion` also set the
+    /// numeric version when they start with one.
+    pub fn set(&mut self, key: &str, value: &str) {
+        match key {
+            "FileVersion" => {
+                if let Some(version) = parse_version(value) {
+                    self.file_version = version;
+                }
+            }
+            "ProductVersion" => {
+                if let Some(version) = parse_version(value) {
+                    self.product_version = version;
+                }
+            }
+            _ => {}
+        }
+        match self.strings.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_owned(),
+            None => self.strings.push((key.to_owned(), value.to_owned())),
+        }
+    }
+
+    /// Apply the `version-info` table of `[package.metadata.thunk]`, whose keys are the names of
+    /// the string table entries.
+    pub fn apply_metadata(&mut self, metadata: &Table) -> Result<(), String> {
+        let table = match metadata.get("version-info") {
+            Some(value) => value
+                .as_table()
+                .ok_or("version-info of [package.metadata.thunk] is not a table")?,
+            None => return Ok(()),
+        };
+        for (key, value) in table {
+            let value = value
+                .as_str()
+                .ok_or_else(|| format!("version-info.{} is not a string", key))?;
+            self.set(key, value);
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut fixed = Vec::new();
+        for value in [
+            VS_FFI_SIGNATURE,
+            VS_FFI_STRUCVERSION,
+            join_version(self.file_version[0], self.file_version[1]),
+            join_version(self.file_version[2], self.file_version[3]),
+            join_version(self.product_version[0], self.product_version[1]),
+            join_version(self.product_version[2], self.product_version[3]),
+            VS_FFI_FILEFLAGSMASK,
+            0, // FileFlags
+            VOS_NT_WINDOWS32,
+            if self.is_lib { VFT_DLL } else { VFT_APP },
+            0, // FileSubtype
+            0, // FileDateMS
+            0, // FileDateLS
+        ] {
+            fixed.extend(value.to_le_bytes());
+        }
+
+        let mut strings = Vec::new();
+        for (key, value) in &self.strings {
+            let value = to_utf16z(value);
+            // The length of a string value counts in WORDs.
+            write_block(&mut strings, key, 1, (value.len() / 2) as u16, &value, &[]);
+        }
+        let table_name = format!("{:04x}{:04x}", TRANSLATION.0, TRANSLATION.1);
+        let mut string_table = Vec::new();
+        write_block(&mut string_table, &table_name, 1, 0, &[], &strings);
+
+        let mut translation = Vec::new();
+        translation.extend(TRANSLATION.0.to_le_bytes());
+        translation.extend(TRANSLATION.1.to_le_bytes());
+        let mut var = Vec::new();
+        write_block(&mut var, "Translation", 0, 4, &translation, &[]);
+
+        let mut children = Vec::new();
+        write_block(&mut children, "StringFileInfo", 1, 0, &[], &string_table);
+        write_block(&mut children, "VarFileInfo", 1, 0, &[], &var);
+
+        let mut bytes = Vec::new();
+        let length = fixed.len() as u16;
+        write_block(&mut bytes, "VS_VERSION_INFO", 0, length, &fixed, &children);
+        bytes
+    }
+
+    pub fn add_to(&self, res: &mut ResourceFile) {
+        res.add(RT_VERSION, VS_VERSION_INFO, self.to_bytes());
+    }
+}
+
+/// Parse the leading numbers of a version such as `1.2.3-beta.1` into `[1, 2, 3, 0]`.
+pub fn parse_version(text: &str) -> Option<[u16; 4]> {
+    let mut version = [0; 4];
+    let mut count = 0;
+    for (index, part) in text.split('.').take(4).enumerate() {
+        let digits: String = part.chars().take_while(|x| x.is_ascii_digit()).collect();
+        if digits.is_empty() {
+            break;
+        }
+        version[index] = digits.parse().ok()?;
+        count += 1;
+        if digits.len() != part.len() {
+            break;
+        }
+    }
+    (count > 0).then_some(version)
+}
+
+fn join_version(high: u16, low: u16) -> u32 {
+    ((high as u32) << 16) | low as u32
+}
+
+fn to_utf16z(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .chain([0])
+        .flat_map(|x| x.to_le_bytes())
+        .collect()
+}
+
+fn align_dword(bytes: &mut Vec<u8>) {
+    while bytes.len() % 4 != 0 {
+        bytes.push(0);
+    }
+}
+
+/// Write a block of the version information, each block starts on a DWORD boundary and its
+/// length leaves out the padding after it.
+fn write_block(
+    bytes: &mut Vec<u8>,
+    key: &str,
+    kind: u16,
+    value_length: u16,
+    value: &[u8],
+    children: &[u8],
+) {
+    align_dword(bytes);
+    let start = bytes.len();
+    bytes.extend([0, 0]); // Length
+    bytes.extend(value_length.to_le_bytes());
+    bytes.extend(kind.to_le_bytes());
+    bytes.extend(to_utf16z(key));
+    if !value.is_empty() {
+        align_dword(bytes);
+        bytes.extend(value);
+    }
+    if !children.is_empty() {
+        align_dword(bytes);
+        bytes.extend(children);
+    }
+    let length = (bytes.len() - start) as u16;
+    bytes[start..start + 2].copy_from_slice(&length.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("0.3.5"), Some([0, 3, 5, 0]));
+        assert_eq!(parse_version("1.2.3-beta.1"), Some([1, 2, 3, 0]));
+        assert_eq!(parse_version("1.2.3.4.5"), Some([1, 2, 3, 4]));
+        assert_eq!(parse_version("2.x"), Some([2, 0, 0, 0]));
+        assert_eq!(parse_version("v1"), None);
+    }
+
+    #[test]
+    fn test_version_info() {
+        let package = PackageInfo {
+            name: "build-for-xp".to_owned(),
+            version: "0.3.5".to_owned(),
+            description: None,
+            authors: vec!["Felix <felix@example.com>".to_owned()],
+        };
+        let mut info = VersionInfo::from_package(&package, true);
+        let metadata: Table = toml::from_str(
+            "[version-info]\nLegalCopyright = \"(c) Felix\"\nFileVersion = \"1.0.0.7\"\n",
+        )
+        .unwrap();
+        info.apply_metadata(&metadata).unwrap();
+
+        assert_eq!(info.file_version, [1, 0, 0, 7]);
+        assert_eq!(info.product_version, [0, 3, 5, 0]);
+        let get = |key: &str| {
+            info.strings
+                .iter()
+                .find(|(k, _)| k == key)
+                .unwrap()
+                .1
+                .clone()
+        };
+        assert_eq!(get("CompanyName"), "Felix");
+        assert_eq!(get("OriginalFilename"), "build_for_xp.dll");
+        assert_eq!(get("LegalCopyright"), "(c) Felix");
+
+        let bytes = info.to_bytes();
+        assert_eq!(
+            u16::from_le_bytes([bytes[0], bytes[1]]) as usize,
+            bytes.len()
+        );
+        // The fixed info follows the key, aligned
+        assert_eq!(&bytes[40..44], &VS_FFI_SIGNATURE.to_le_bytes());
+        assert_eq!(&bytes[48..52], &[0, 0, 1, 0]);
+    }
+}